@@ -1,39 +1,28 @@
 /// general data manipulation api
 use crate::{
+    auth::{authorize, authorize_with_body, Access},
     db::{
-        models::{Params, Stats},
+        models::{validate_sendable_bounds, InvoiceAPI, Params, Scope, Stats},
         Db,
     },
-    with_clone,
+    handlers::Error,
+    keysend,
+    pin::reset_pin_async,
+    webhook, with_clone, LNbitsConfig,
 };
 use log::*;
+use percent_encoding::percent_decode_str;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::collections::HashMap;
-use warp::{host::Authority, reject::Reject, Filter, Rejection};
+use validator::Validate;
+use warp::{
+    host::Authority,
+    hyper::{body::Bytes, StatusCode},
+    reject, Filter, Rejection,
+};
 
 use super::Config;
-use std::convert::Infallible;
-
-#[derive(Debug)]
-struct AuthError;
-
-impl Reject for AuthError {}
-
-pub async fn authenticate(
-    pin: String,
-    config: Config,
-    // _body: HashMap<String, String>,
-) -> Result<(), Rejection> {
-    info!("The pin header value is equal to {}", pin);
-    // TODO: this needs to be finished so simple
-    // mitm does not reveal the pin
-    // current assumption is that those APIs
-    // are accessed via localhost from cli
-    if pin != format!("TODO-{}", config.pin_secret) {
-        return Err(warp::reject::custom(AuthError));
-    }
-    Ok(())
-}
 
 pub async fn check_domain(
     db: Db,
@@ -53,97 +42,462 @@ pub async fn check_domain(
     Ok((db, config, username, domain))
 }
 
+/// Maximum size of the JSON body accepted by the user endpoints
+const MAX_BODY_SIZE: u64 = 1024 * 16;
+
 // construct api handlers
 pub fn handlers(
     db: crate::db::Db,
     config: crate::Config,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    let base = warp::path!("user")
+    let access = Access::Token(db.clone(), config.token_secret.clone());
+    routes(db, config, access)
+}
+
+/// Same routes as `handlers` but without token checks,
+/// served only over the local admin socket (see `local`)
+pub fn local_handlers(
+    db: crate::db::Db,
+    config: crate::Config,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    routes(db, config, Access::Local)
+}
+
+fn routes(
+    db: crate::db::Db,
+    config: crate::Config,
+    access: Access,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    // method filters go first so that the body is consumed
+    // (by the auth guard) only by the matching route
+    let add_user = warp::path!("user")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(authorize_with_body(access.clone(), Scope::Users))
         .and(with_clone(db.clone()))
-        .and(with_clone(config));
+        .and(with_clone(config.clone()))
+        .and_then(add_user);
+
+    // operations on an existing entry, addressed as `name@domain`
+    let user = warp::path!("user" / String);
 
-    let add_user = base.clone().and(warp::post()).and_then(add_user);
-    let edit_user = base.clone().and(warp::put()).and_then(edit_user);
-    let delete_user = base.clone().and(warp::delete()).and_then(delete_user);
-    let get_user = base.and(warp::get()).and_then(get_user);
+    let edit_user = user
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(authorize_with_body(access.clone(), Scope::Users))
+        .and(with_clone(db.clone()))
+        .and(with_clone(config.clone()))
+        .and_then(edit_user);
+    let delete_user = user
+        .and(warp::delete())
+        .and(authorize(access.clone(), Scope::Users))
+        .and(with_clone(db.clone()))
+        .and(with_clone(config.clone()))
+        .and_then(delete_user);
+    let get_user = user
+        .and(warp::get())
+        .and(authorize(access.clone(), Scope::Users))
+        .and(with_clone(db.clone()))
+        .and(with_clone(config))
+        .and_then(get_user);
+    let reset_user_pin = warp::path!("user" / String / "pin")
+        .and(warp::post())
+        .and(authorize(access.clone(), Scope::Users))
+        .and(with_clone(db.clone()))
+        .and_then(reset_user_pin);
+    let disable_user = warp::path!("user" / String / "disable")
+        .and(warp::post())
+        .and(authorize(access.clone(), Scope::Users))
+        .and(with_clone(db.clone()))
+        .and(with_clone(true))
+        .and_then(set_user_disabled);
+    let enable_user = warp::path!("user" / String / "enable")
+        .and(warp::post())
+        .and(authorize(access.clone(), Scope::Users))
+        .and(with_clone(db.clone()))
+        .and(with_clone(false))
+        .and_then(set_user_disabled);
+    let list_users = warp::path!("users")
+        .and(warp::get())
+        .and(authorize(access.clone(), Scope::Users))
+        .and(with_clone(db.clone()))
+        .and_then(list_users);
+    let list_invoices = warp::path!("invoices")
+        .and(warp::get())
+        .and(authorize(access.clone(), Scope::Invoices))
+        .and(warp::query::<InvoiceQuery>())
+        .and(with_clone(db.clone()))
+        .and_then(list_invoices);
+    let get_invoice = warp::path!("invoice" / String)
+        .and(warp::get())
+        .and(authorize(access.clone(), Scope::Invoices))
+        .and(with_clone(db.clone()))
+        .and_then(get_invoice);
     let get_stats = warp::path!("stats")
-        .and(with_clone(db))
         .and(warp::get())
+        .and(authorize(access, Scope::Stats))
+        .and(with_clone(db))
         .and_then(get_stats);
 
     add_user
         .or(edit_user)
         .or(delete_user)
         .or(get_user)
+        .or(reset_user_pin)
+        .or(disable_user)
+        .or(enable_user)
+        .or(list_users)
+        .or(list_invoices)
+        .or(get_invoice)
         .or(get_stats)
 }
 
-pub async fn add_user(_db: Db, _config: Config) -> Result<impl warp::Reply, Infallible> {
-    Ok(warp::reply::Response::new(
-        "message: add".to_string().into(),
-    ))
+/// Deserializes JSON body of the request
+fn parse_body<T: DeserializeOwned>(body: &Bytes) -> Result<T, Rejection> {
+    let des = &mut serde_json::Deserializer::from_slice(body);
+    serde_path_to_error::deserialize(des)
+        .map_err(|e| reject::custom(Error::JSONPath(e.to_string())))
 }
 
-pub async fn edit_user(_db: Db, _config: Config) -> Result<impl warp::Reply, Infallible> {
-    Ok(warp::reply::Response::new(
-        "message: edit".to_string().into(),
-    ))
+/// Splits `name@domain` address (as passed in the url path)
+/// into its name and domain parts.
+fn split_address(address: &str) -> Result<(String, String), Rejection> {
+    let address = percent_decode_str(address)
+        .decode_utf8()
+        .map_err(|e| reject::custom(Error::Val(e.to_string())))?;
+
+    match address.rsplit_once('@') {
+        Some((name, domain)) if !name.is_empty() && !domain.is_empty() => {
+            Ok((name.to_owned(), domain.to_owned()))
+        }
+        _ => Err(reject::custom(Error::Val(format!(
+            "invalid address: {}",
+            address
+        )))),
+    }
 }
 
-pub async fn delete_user(_db: Db, _config: Config) -> Result<impl warp::Reply, Infallible> {
-    Ok(warp::reply::Response::new(
-        "message: del".to_string().into(),
-    ))
+/// Validates the body of user related requests
+fn validate_params(params: &Params, config: &Config) -> Result<(), Rejection> {
+    params
+        .validate()
+        .map_err(|e| reject::custom(Error::Validation(e)))?;
+    // keysend entries need LNbits elements provisioned for them,
+    // that's done only by the web form (see `handlers::grab`)
+    if let InvoiceAPI::Keysend(_) = params.invoice_api {
+        return Err(reject::custom(Error::Val(
+            "keysend entries can only be set up on the website".to_string(),
+        )));
+    }
+    if !config.domains.contains(&params.domain) {
+        return Err(reject::custom(Error::Val(format!(
+            "domain not supported: {}",
+            params.domain
+        ))));
+    }
+    validate_sendable_bounds(params, config.sendable_bounds())
+        .map_err(|e| reject::custom(Error::Validation(e)))
 }
 
-pub async fn get_user(_db: Db, _config: Config) -> Result<impl warp::Reply, Infallible> {
-    Ok(warp::reply::Response::new(
-        "message: get".to_string().into(),
+/// Webhooks can only notify public https urls (see `webhook::check_url`)
+async fn check_webhook(params: &Params, config: &Config) -> Result<(), Rejection> {
+    match params.webhook {
+        Some(ref hook) => webhook::check_url(&hook.url, config.webhook_allow_local)
+            .await
+            .map_err(|e| reject::custom(Error::Val(e.to_string()))),
+        None => Ok(()),
+    }
+}
+
+/// Creates a new entry, fails if the entry already exists
+pub async fn add_user(body: Bytes, db: Db, config: Config) -> Result<impl warp::Reply, Rejection> {
+    let mut params: Params = parse_body(&body)?;
+    validate_params(&params, &config)?;
+    check_webhook(&params, &config).await?;
+
+    let pin = reset_pin_async(&mut params)
+        .await
+        .map_err(|e| reject::custom(Error::Internal(e.to_string())))?;
+    params.stats = Default::default();
+    if let Some(ref mut hook) = params.webhook {
+        webhook::keep_or_generate_secret(hook, None);
+    }
+    // concurrent creates of the same address, only one of them wins
+    let created = db
+        .create(&params)
+        .map_err(|e| reject::custom(Error::Internal(e.to_string())))?;
+    if !created {
+        return Err(reject::custom(Error::Conflict(format!(
+            "{}@{} already exists",
+            params.name, params.domain
+        ))));
+    }
+
+    info!("Added {}@{} via the API", params.name, params.domain);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({
+            "pin": pin,
+            "user": params,
+        })),
+        StatusCode::CREATED,
     ))
 }
 
-pub async fn get_stats(db: Db) -> Result<impl warp::Reply, Infallible> {
-    let (data, summary) = generate_stats(&db).unwrap();
+/// Replaces an existing entry, pin, stats and the disabled flag are preserved
+/// (so is the webhook secret if the url doesn't change)
+pub async fn edit_user(
+    address: String,
+    body: Bytes,
+    db: Db,
+    config: Config,
+) -> Result<impl warp::Reply, Rejection> {
+    let params: Params = parse_body(&body)?;
+    let (name, domain) = split_address(&address)?;
+    if params.name != name || params.domain != domain {
+        return Err(reject::custom(Error::Val(
+            "name and domain can not be changed".to_string(),
+        )));
+    }
+    validate_params(&params, &config)?;
+    check_webhook(&params, &config).await?;
+
+    // kept from the entry as stored when it's replaced
+    let mut previous = None;
+    let params = db
+        .modify(&name, &domain, |entry| {
+            let mut edited = Params {
+                pin: entry.pin.clone(),
+                pin_hash: entry.pin_hash.clone(),
+                stats: entry.stats.clone(),
+                disabled: entry.disabled,
+                ..params.clone()
+            };
+            if let Some(ref mut hook) = edited.webhook {
+                webhook::keep_or_generate_secret(hook, entry.webhook.as_ref());
+            }
+            edited.stats.edits.inc();
+            previous = Some(entry.clone());
+            Some(edited)
+        })
+        .map_err(|e| reject::custom(Error::Internal(e.to_string())))?
+        .ok_or_else(|| reject::custom(Error::NotFound(format!("{}@{}", name, domain))))?;
+    if let Some(previous) = previous {
+        release_keysend(&config.lnbits, &previous, &params).await;
+    }
+
+    info!("Updated {}@{} via the API", name, domain);
+    Ok(warp::reply::json(&params))
+}
+
+/// Removes the LNbits elements provisioned for `previous`
+/// if it was a keysend entry and `params` replacing it is not
+pub async fn release_keysend(lnbits: &LNbitsConfig, previous: &Params, params: &Params) {
+    match (&previous.invoice_api, &params.invoice_api) {
+        (InvoiceAPI::Keysend(_), InvoiceAPI::Keysend(_)) => {}
+        (InvoiceAPI::Keysend(k_params), _) => {
+            if let Err(e) = keysend::deprovision_backend(lnbits, k_params).await {
+                error!(
+                    "Unable to deprovision keysend backend of {}@{}: {}",
+                    previous.name, previous.domain, e
+                );
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Removes the entry, cleaning up the LNbits elements provisioned
+/// for keysend entries first. Returns `None` if the entry doesn't exist.
+pub async fn remove_entry(
+    db: &Db,
+    lnbits: &LNbitsConfig,
+    name: &str,
+    domain: &str,
+) -> Result<Option<Params>, anyhow::Error> {
+    let params = match db.get(name, domain)? {
+        Some(params) => params,
+        None => return Ok(None),
+    };
+    if let InvoiceAPI::Keysend(ref k_params) = params.invoice_api {
+        keysend::deprovision_backend(lnbits, k_params).await?;
+    }
+    db.delete(name, domain)
+}
+
+/// Removes an entry from the database
+pub async fn delete_user(
+    address: String,
+    db: Db,
+    config: Config,
+) -> Result<impl warp::Reply, Rejection> {
+    let (name, domain) = split_address(&address)?;
+    remove_entry(&db, &config.lnbits, &name, &domain)
+        .await
+        .map_err(|e| reject::custom(Error::Internal(e.to_string())))?
+        .ok_or_else(|| reject::custom(Error::NotFound(format!("{}@{}", name, domain))))?;
+
+    info!("Deleted {}@{} via the API", name, domain);
     Ok(warp::reply::json(&json!({
-        "data": data,
-        "summary": summary
+        "message": "deleted",
     })))
 }
 
-pub fn generate_stats(db: &Db) -> Result<(HashMap<String, Stats>, Value), anyhow::Error> {
-    let mut data = HashMap::new();
-    let mut summary: Value = json!(
-        {"calls": 0, "edits": 0, "invoices": 0}
+/// Returns a single entry
+pub async fn get_user(
+    address: String,
+    db: Db,
+    _config: Config,
+) -> Result<impl warp::Reply, Rejection> {
+    let (name, domain) = split_address(&address)?;
+    let params = db
+        .get(&name, &domain)
+        .map_err(|e| reject::custom(Error::Internal(e.to_string())))?
+        .ok_or_else(|| reject::custom(Error::NotFound(format!("{}@{}", name, domain))))?;
+
+    Ok(warp::reply::json(&params))
+}
+
+/// Disables or re-enables an entry, disabled entries
+/// don't serve payment requests
+pub async fn set_user_disabled(
+    address: String,
+    db: Db,
+    disabled: bool,
+) -> Result<impl warp::Reply, Rejection> {
+    let (name, domain) = split_address(&address)?;
+    let mut params = db
+        .get(&name, &domain)
+        .map_err(|e| reject::custom(Error::Internal(e.to_string())))?
+        .ok_or_else(|| reject::custom(Error::NotFound(format!("{}@{}", name, domain))))?;
+
+    params.disabled = disabled;
+    db.update(&params)
+        .map_err(|e| reject::custom(Error::Internal(e.to_string())))?;
+
+    info!(
+        "{}@{} {} via the API",
+        name,
+        domain,
+        if disabled { "disabled" } else { "enabled" }
     );
+    Ok(warp::reply::json(&params))
+}
 
-    for r in db.iter() {
-        let ivec = r?;
-        let p: Params = rmp_serde::from_slice(&ivec.1)?;
+/// Returns all the entries
+pub async fn list_users(db: Db) -> Result<impl warp::Reply, Rejection> {
+    let records = db
+        .list()
+        .map_err(|e| reject::custom(Error::Internal(e.to_string())))?;
+    Ok(warp::reply::json(&records))
+}
 
-        let calls = summary["calls"].as_u64().unwrap() as u16 + p.stats.calls.num;
-        summary["calls"] = serde_json::Value::Number(calls.into());
+/// Generates a new PIN for a single entry, other entries are not affected
+pub async fn reset_user_pin(address: String, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let (name, domain) = split_address(&address)?;
+    let mut params = db
+        .get(&name, &domain)
+        .map_err(|e| reject::custom(Error::Internal(e.to_string())))?
+        .ok_or_else(|| reject::custom(Error::NotFound(format!("{}@{}", name, domain))))?;
 
-        let edits = summary["edits"].as_u64().unwrap() as u16 + p.stats.edits.num;
-        summary["edits"] = serde_json::Value::Number(edits.into());
+    let pin = reset_pin_async(&mut params)
+        .await
+        .map_err(|e| reject::custom(Error::Internal(e.to_string())))?;
+    db.update(&params)
+        .map_err(|e| reject::custom(Error::Internal(e.to_string())))?;
 
-        let invoices = summary["invoices"].as_u64().unwrap() as u16 + p.stats.invoices.num;
-        summary["invoices"] = serde_json::Value::Number(invoices.into());
+    info!("PIN reset for {}@{} via the API", name, domain);
+    Ok(warp::reply::json(&json!({
+        "pin": pin,
+    })))
+}
 
+/// Filters of the invoice ledger listing
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct InvoiceQuery {
+    /// only invoices issued for this `name@domain`
+    pub address: Option<String>,
+    /// maximum number of (most recent) invoices returned
+    pub limit: Option<usize>,
+}
+
+/// Returns invoices from the ledger, the most recent ones first
+pub async fn list_invoices(query: InvoiceQuery, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let mut invoices = db
+        .list_invoices(query.address.as_deref())
+        .map_err(|e| reject::custom(Error::Internal(e.to_string())))?;
+    if let Some(limit) = query.limit {
+        invoices.truncate(limit);
+    }
+    Ok(warp::reply::json(&invoices))
+}
+
+/// Returns a single invoice from the ledger
+pub async fn get_invoice(payment_hash: String, db: Db) -> Result<impl warp::Reply, Rejection> {
+    let invoice = db
+        .get_invoice(&payment_hash.to_lowercase())
+        .map_err(|e| reject::custom(Error::Internal(e.to_string())))?
+        .ok_or_else(|| reject::custom(Error::NotFound(payment_hash)))?;
+    Ok(warp::reply::json(&invoice))
+}
+
+pub async fn get_stats(db: Db) -> Result<impl warp::Reply, Rejection> {
+    let (data, summary) =
+        generate_stats(&db).map_err(|e| reject::custom(Error::Internal(e.to_string())))?;
+    Ok(warp::reply::json(&json!({
+        "data": data,
+        "summary": summary
+    })))
+}
+
+pub fn generate_stats(db: &Db) -> Result<(HashMap<String, Stats>, Value), anyhow::Error> {
+    let mut data = HashMap::new();
+    let (mut calls, mut edits, mut invoices) = (0u64, 0u64, 0u64);
+
+    for p in db.list()? {
+        calls += u64::from(p.stats.calls.num);
+        edits += u64::from(p.stats.edits.num);
+        invoices += u64::from(p.stats.invoices.num);
         data.insert(format!("{}@{}", p.name, p.domain), p.stats);
     }
+
+    // payments tracked in the invoice ledger
+    let totals = db.ledger_totals()?;
+    let summary = json!({
+        "calls": calls,
+        "edits": edits,
+        "invoices": invoices,
+        "settled": totals.settled,
+        "received": totals.msat_received / 1000,
+    });
     Ok((data, summary))
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::{
+        collections::HashMap,
+        time::{Duration, SystemTime},
+    };
 
     use envconfig::Envconfig;
-    use warp::host::Authority;
+    use serde_json::Value;
+    use warp::{host::Authority, hyper::StatusCode, Filter};
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
 
-    use crate::{api::authenticate, db::helpers, Config};
+    use crate::{
+        auth,
+        db::{
+            helpers,
+            models::{InvoiceAPI, InvoiceRecord, InvoiceStatus, KeysendParams, Params, Scope},
+        },
+        pin::verify_pin,
+        Config,
+    };
 
-    use super::check_domain;
+    use super::{check_domain, handlers};
 
     fn init_config() -> Config {
         let hm = HashMap::from([
@@ -151,25 +505,133 @@ mod tests {
             ("PIN_SECRET".to_owned(), "my-secret".to_owned()),
             ("SITE_NAME".to_owned(), "my-site".to_owned()),
             ("SITE_SUB_NAME".to_owned(), "my-com".to_owned()),
+            ("LNBITS_URL".to_owned(), "http://127.0.0.1:5000/".to_owned()),
+            ("LNBITS_API_KEY".to_owned(), "lnbits-key".to_owned()),
+            ("LNBITS_ADMIN_ID".to_owned(), "lnbits-admin".to_owned()),
         ]);
         Config::init_from_hashmap(&hm).unwrap()
     }
 
+    fn user_params(name: &str) -> Params {
+        let mut params = Params {
+            name: name.to_owned(),
+            domain: "mydomain.com".to_owned(),
+            ..Default::default()
+        };
+        if let crate::db::models::InvoiceAPI::Lnd(ref mut p) = params.invoice_api {
+            p.host = "https://lnd.mydomain.com".to_owned();
+            p.macaroon = "macaroon".to_owned();
+        }
+        params
+    }
+
     #[tokio::test]
-    async fn authenticate_header_secret() {
-        let config = init_config();
-        assert!(
-            authenticate("whatever".to_owned(), config.clone())
-                .await
-                .is_err(),
-            "should reject invalid value"
-        );
-        assert!(
-            authenticate("TODO-my-secret".to_owned(), config.clone())
-                .await
-                .is_ok(),
-            "should pass correct value"
-        );
+    async fn handlers_require_scoped_token() {
+        let db = helpers::tmp_db();
+        let stats_token = auth::issue_token(&db, "stats", vec![Scope::Stats], None, None).unwrap();
+        let api = handlers(db, init_config()).recover(crate::handlers::handle_rejection);
+
+        let resp = warp::test::request().path("/stats").reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let bearer = format!("Bearer {}", stats_token);
+        let resp = warp::test::request()
+            .path("/stats")
+            .header("authorization", &bearer)
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = warp::test::request()
+            .path("/user/alice@mydomain.com")
+            .header("authorization", &bearer)
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn invoice_ledger_is_queryable() {
+        let db = helpers::tmp_db();
+        let bearer = format!("Bearer {}", auth::helpers::admin_token(&db));
+        let api = handlers(db.clone(), init_config()).recover(crate::handlers::handle_rejection);
+
+        let now = SystemTime::now();
+        for (idx, address) in [
+            "alice@mydomain.com",
+            "bob@mydomain.com",
+            "alice@mydomain.com",
+        ]
+        .iter()
+        .enumerate()
+        {
+            let created_at = now + Duration::from_secs(idx as u64);
+            db.insert_invoice(&InvoiceRecord {
+                payment_request: format!("lnbc{}", idx),
+                address: address.to_string(),
+                msat: 1000 * (idx as u64 + 1),
+                comment: Some("thanks".to_string()),
+                created_at,
+                expires_at: created_at + Duration::from_secs(3600),
+                ..helpers::invoice(&format!("{:064}", idx))
+            })
+            .unwrap();
+        }
+
+        let resp = warp::test::request()
+            .header("authorization", &bearer)
+            .path("/invoices?address=alice@mydomain.com&limit=1")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let invoices: Vec<InvoiceRecord> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(invoices.len(), 1);
+        assert_eq!(invoices[0].msat, 3000, "most recent invoice goes first");
+
+        let resp = warp::test::request()
+            .header("authorization", &bearer)
+            .path(&format!("/invoice/{:064}", 1))
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let invoice: InvoiceRecord = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(invoice.address, "bob@mydomain.com");
+
+        let resp = warp::test::request()
+            .header("authorization", &bearer)
+            .path("/invoice/missing")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn stats_are_summed() {
+        let db = helpers::tmp_db();
+        let bearer = format!("Bearer {}", auth::helpers::admin_token(&db));
+        let api = handlers(db.clone(), init_config()).recover(crate::handlers::handle_rejection);
+        for name in ["alice", "bob"] {
+            let mut params = user_params(name);
+            params.stats.calls.num = 40_000;
+            db.create(&params).unwrap();
+        }
+        db.insert_invoice(&InvoiceRecord {
+            status: InvoiceStatus::Settled,
+            msat_received: Some(21_000),
+            ..helpers::invoice("aa")
+        })
+        .unwrap();
+
+        let resp = warp::test::request()
+            .header("authorization", &bearer)
+            .path("/stats")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["summary"]["calls"], 80_000);
+        assert_eq!(body["summary"]["settled"], 1);
+        assert_eq!(body["summary"]["received"], 21);
     }
 
     #[tokio::test]
@@ -200,4 +662,225 @@ mod tests {
         .await
         .is_ok());
     }
+
+    #[tokio::test]
+    async fn user_crud_works() {
+        let db = helpers::tmp_db();
+        let bearer = format!("Bearer {}", auth::helpers::admin_token(&db));
+        let api = handlers(db.clone(), init_config());
+        let params = user_params("alice");
+
+        let resp = warp::test::request()
+            .header("authorization", &bearer)
+            .method("POST")
+            .path("/user")
+            .json(&params)
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        let pin = body["pin"].as_str().unwrap().to_owned();
+        let created: Params = serde_json::from_value(body["user"].clone()).unwrap();
+        assert!(created.pin_hash.is_some(), "pin should be generated");
+        assert!(verify_pin(&created, &pin, &["my-secret"]));
+
+        let resp = warp::test::request()
+            .header("authorization", &bearer)
+            .method("GET")
+            .path("/user/alice@mydomain.com")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let fetched: Params = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(fetched, created);
+
+        let mut edited = params.clone();
+        edited.max_sendable = Some(5_000);
+        let resp = warp::test::request()
+            .header("authorization", &bearer)
+            .method("PUT")
+            .path("/user/alice@mydomain.com")
+            .json(&edited)
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let stored = db.get("alice", "mydomain.com").unwrap().unwrap();
+        assert_eq!(stored.max_sendable, Some(5_000));
+        assert_eq!(stored.pin_hash, created.pin_hash, "pin should be preserved");
+
+        let resp = warp::test::request()
+            .header("authorization", &bearer)
+            .method("POST")
+            .path("/user/alice@mydomain.com/pin")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        let stored = db.get("alice", "mydomain.com").unwrap().unwrap();
+        assert!(!verify_pin(&stored, &pin, &["my-secret"]));
+        assert!(verify_pin(
+            &stored,
+            body["pin"].as_str().unwrap(),
+            &["my-secret"]
+        ));
+
+        let resp = warp::test::request()
+            .header("authorization", &bearer)
+            .method("POST")
+            .path("/user/alice@mydomain.com/disable")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(db.get("alice", "mydomain.com").unwrap().unwrap().disabled);
+        let resp = warp::test::request()
+            .header("authorization", &bearer)
+            .method("POST")
+            .path("/user/alice@mydomain.com/enable")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!db.get("alice", "mydomain.com").unwrap().unwrap().disabled);
+
+        let resp = warp::test::request()
+            .header("authorization", &bearer)
+            .method("DELETE")
+            .path("/user/alice@mydomain.com")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(db.get("alice", "mydomain.com").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn user_add_rejects_duplicates_and_invalid_data() {
+        let db = helpers::tmp_db();
+        let bearer = format!("Bearer {}", auth::helpers::admin_token(&db));
+        let api = handlers(db, init_config()).recover(crate::handlers::handle_rejection);
+        let params = user_params("bob");
+
+        let add = |p: &Params| {
+            warp::test::request()
+                .header("authorization", &bearer)
+                .method("POST")
+                .path("/user")
+                .json(p)
+        };
+        assert_eq!(add(&params).reply(&api).await.status(), StatusCode::CREATED);
+        assert_eq!(
+            add(&params).reply(&api).await.status(),
+            StatusCode::CONFLICT
+        );
+        // concurrent creates of the same address, only one of them wins
+        let racer = user_params("frank");
+        let (first, second) = tokio::join!(add(&racer).reply(&api), add(&racer).reply(&api));
+        let mut statuses = vec![first.status(), second.status()];
+        statuses.sort();
+        assert_eq!(statuses, vec![StatusCode::CREATED, StatusCode::CONFLICT]);
+
+        let mut invalid = user_params("carol");
+        invalid.min_sendable = Some(10_000);
+        invalid.max_sendable = Some(1_000);
+        let resp = add(&invalid).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["message"], "field errors");
+
+        let mut too_much = user_params("erin");
+        too_much.max_sendable = Some(10_000_000_000);
+        let resp = add(&too_much).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["errors"][0]["field"], "maxSendable");
+
+        let mut other_domain = user_params("dave");
+        other_domain.domain = "example.com".to_owned();
+        let resp = add(&other_domain).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn keysend_entries_are_not_set_up_via_the_api() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("DELETE"))
+            .and(path("/usermanager/api/v1/users/user-1"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let mut config = init_config();
+        config.lnbits.url = format!("{}/", mock_server.uri()).parse().unwrap();
+        let db = helpers::tmp_db();
+        let bearer = format!("Bearer {}", auth::helpers::admin_token(&db));
+        let api = handlers(db.clone(), config).recover(crate::handlers::handle_rejection);
+        let keysend = Params {
+            invoice_api: InvoiceAPI::Keysend(KeysendParams {
+                pub_key: "pubkey".to_owned(),
+                ..Default::default()
+            }),
+            ..user_params("alice")
+        };
+
+        let resp = warp::test::request()
+            .header("authorization", &bearer)
+            .method("POST")
+            .path("/user")
+            .json(&keysend)
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(db.get("alice", "mydomain.com").unwrap().is_none());
+
+        // provisioned by the web form
+        let provisioned = Params {
+            invoice_api: InvoiceAPI::Keysend(KeysendParams {
+                pub_key: "pubkey".to_owned(),
+                user_id: Some("user-1".to_owned()),
+                ..Default::default()
+            }),
+            ..user_params("alice")
+        };
+        db.create(&provisioned).unwrap();
+        let edit = |p: &Params| {
+            warp::test::request()
+                .header("authorization", &bearer)
+                .method("PUT")
+                .path("/user/alice@mydomain.com")
+                .json(p)
+        };
+        assert_eq!(
+            edit(&keysend).reply(&api).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        // switched to another backend, the LNbits user is removed
+        let resp = edit(&user_params("alice")).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let stored = db.get("alice", "mydomain.com").unwrap().unwrap();
+        assert!(matches!(stored.invoice_api, InvoiceAPI::Lnd(_)));
+    }
+
+    #[tokio::test]
+    async fn user_missing_returns_not_found() {
+        let db = helpers::tmp_db();
+        let bearer = format!("Bearer {}", auth::helpers::admin_token(&db));
+        let api = handlers(db, init_config()).recover(crate::handlers::handle_rejection);
+
+        for method in ["GET", "DELETE"] {
+            let resp = warp::test::request()
+                .header("authorization", &bearer)
+                .method(method)
+                .path("/user/nobody@mydomain.com")
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        }
+
+        let resp = warp::test::request()
+            .header("authorization", &bearer)
+            .method("PUT")
+            .path("/user/nobody@mydomain.com")
+            .json(&user_params("nobody"))
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
 }
@@ -0,0 +1,614 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use anyhow::{anyhow, bail, Result};
+use hmac::{Hmac, Mac};
+use log::*;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use warp::{
+    filters::path::FullPath,
+    http::{HeaderMap, Method},
+    hyper::body::Bytes,
+    reject, Filter, Rejection,
+};
+
+use crate::{
+    db::{
+        models::{ApiToken, Scope},
+        Db,
+    },
+    handlers::Error,
+    with_clone,
+};
+
+/// Length of the random part of the issued tokens
+const SECRET_LEN: usize = 48;
+
+/// Hashes the secret part of the token, that's the only
+/// form in which the secret is persisted.
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::new().chain_update(secret).finalize())
+}
+
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+
+/// Key signing the requests (see `sign_request`), derived from the
+/// secret part of the token so that callers can compute it too.
+/// It differs from the stored hash, which can't be used for signing.
+fn signing_key(secret: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key size");
+    mac.update(b"sataddress request signing");
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Encrypts the signing key with the server secret (`TOKEN_SECRET`),
+/// which never gets to the database. Result is hex encoded `iv || ciphertext`.
+fn seal_signing_key(server_secret: &str, key: &[u8]) -> String {
+    let cipher_key = Sha256::digest(server_secret.as_bytes());
+    let iv: [u8; 16] = rand::random();
+    let ciphertext =
+        Aes256CbcEnc::new(&cipher_key, &iv.into()).encrypt_padded_vec_mut::<Pkcs7>(key);
+    hex::encode([&iv[..], &ciphertext].concat())
+}
+
+/// Decrypts the signing key sealed by `seal_signing_key`
+fn open_signing_key(server_secret: &str, sealed: &str) -> Result<Vec<u8>> {
+    let bytes = hex::decode(sealed)?;
+    if bytes.len() < 32 {
+        bail!("truncated signing key");
+    }
+    let (iv, ciphertext) = bytes.split_at(16);
+    let cipher_key = Sha256::digest(server_secret.as_bytes());
+    Aes256CbcDec::new(&cipher_key, iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map_err(|_| anyhow!("unable to decrypt the signing key"))
+}
+
+/// Creates a new named token with given scopes and stores its hash
+/// in the database. Returns the full token (`<name>.<secret>`)
+/// which is not recoverable later on. Tokens can sign requests only
+/// if issued with the server secret (`TOKEN_SECRET`), which encrypts
+/// the stored signing key.
+pub fn issue_token(
+    db: &Db,
+    name: &str,
+    scopes: Vec<Scope>,
+    ttl: Option<Duration>,
+    server_secret: Option<&str>,
+) -> Result<String> {
+    if name.is_empty() || name.contains('.') {
+        bail!("token name can not be empty or contain dots");
+    }
+    if scopes.is_empty() {
+        bail!("token needs at least one scope");
+    }
+    if db.get_token(name)?.is_some() {
+        bail!("token named {} already exists", name);
+    }
+
+    let secret: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SECRET_LEN)
+        .map(char::from)
+        .collect();
+    let created_at = SystemTime::now();
+    let token = ApiToken {
+        name: name.to_owned(),
+        hash: hash_secret(&secret),
+        signing_key: server_secret.map(|s| seal_signing_key(s, &signing_key(&secret))),
+        scopes,
+        created_at,
+        expires_at: ttl.map(|ttl| created_at + ttl),
+    };
+    db.insert_token(&token)?;
+
+    Ok(format!("{}.{}", name, secret))
+}
+
+/// Checks if the presented token is valid and grants requested scope.
+pub(crate) fn verify_token(db: &Db, token: &str, scope: &Scope) -> Result<ApiToken, Error> {
+    let (name, secret) = token
+        .split_once('.')
+        .ok_or_else(|| Error::Unauthorized("malformed token".to_string()))?;
+
+    let stored = db
+        .get_token(name)
+        .map_err(|e| Error::Internal(e.to_string()))?
+        .ok_or_else(|| Error::Unauthorized("invalid token".to_string()))?;
+
+    let hash = hash_secret(secret);
+    if !bool::from(hash.as_bytes().ct_eq(stored.hash.as_bytes())) {
+        return Err(Error::Unauthorized("invalid token".to_string()));
+    }
+    check_grants(&stored, scope)?;
+    Ok(stored)
+}
+
+/// Checks if the (already authenticated) token is still
+/// valid and grants requested scope.
+fn check_grants(token: &ApiToken, scope: &Scope) -> Result<(), Error> {
+    if token.is_expired() {
+        return Err(Error::Unauthorized("token expired".to_string()));
+    }
+    if !token.scopes.contains(scope) {
+        return Err(Error::Forbidden(format!("token lacks {} scope", scope)));
+    }
+    Ok(())
+}
+
+/// Header carrying the name of the token used to sign the request
+pub static KEY_HEADER: &str = "x-sataddress-key";
+/// Header carrying unix timestamp (seconds) of the signed request
+pub static TIMESTAMP_HEADER: &str = "x-sataddress-timestamp";
+/// Header carrying unique, random value of the signed request
+pub static NONCE_HEADER: &str = "x-sataddress-nonce";
+/// Header carrying the hex encoded HMAC-SHA256 signature
+pub static SIGNATURE_HEADER: &str = "x-sataddress-signature";
+
+/// How far (in seconds) the signed request timestamp can
+/// drift from the server clock. Nonces are remembered
+/// for twice that long.
+const MAX_CLOCK_SKEW: u64 = 300;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Prepares HMAC over the request data. The string that gets signed is
+/// `METHOD\nPATH\nTIMESTAMP\nNONCE\nhex(sha256(body))` and the key is
+/// `HMAC-SHA256(secret, "sataddress request signing")`, the server keeps
+/// it only encrypted with its own secret.
+fn request_mac(
+    key: &[u8],
+    method: &str,
+    path: &str,
+    timestamp: u64,
+    nonce: &str,
+    body: &[u8],
+) -> HmacSha256 {
+    let body_hash = hex::encode(Sha256::new().chain_update(body).finalize());
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key size");
+    mac.update(
+        format!(
+            "{}\n{}\n{}\n{}\n{}",
+            method, path, timestamp, nonce, body_hash
+        )
+        .as_bytes(),
+    );
+    mac
+}
+
+/// Computes the request signature, see `request_mac`.
+pub fn signature(
+    key: &[u8],
+    method: &str,
+    path: &str,
+    timestamp: u64,
+    nonce: &str,
+    body: &[u8],
+) -> Vec<u8> {
+    request_mac(key, method, path, timestamp, nonce, body)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+/// Signs a request with the full token (`<name>.<secret>`) and returns
+/// headers that need to be attached to it.
+pub fn sign_request(
+    token: &str,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> Result<Vec<(&'static str, String)>> {
+    let (name, secret) = match token.split_once('.') {
+        Some(parts) => parts,
+        None => bail!("malformed token"),
+    };
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let nonce: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    let sig = signature(&signing_key(secret), method, path, timestamp, &nonce, body);
+
+    Ok(vec![
+        (KEY_HEADER, name.to_owned()),
+        (TIMESTAMP_HEADER, timestamp.to_string()),
+        (NONCE_HEADER, nonce),
+        (SIGNATURE_HEADER, hex::encode(sig)),
+    ])
+}
+
+/// Checks HMAC signed request, rejects stale timestamps and reused nonces.
+pub(crate) fn verify_signature(
+    db: &Db,
+    server_secret: Option<&str>,
+    method: &Method,
+    path: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+    scope: &Scope,
+) -> Result<ApiToken, Error> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| Error::Unauthorized(format!("missing {} header", name)))
+    };
+    let name = header(KEY_HEADER)?;
+    let nonce = header(NONCE_HEADER)?;
+    let timestamp: u64 = header(TIMESTAMP_HEADER)?
+        .parse()
+        .map_err(|_| Error::Unauthorized("invalid timestamp".to_string()))?;
+    let sig = hex::decode(header(SIGNATURE_HEADER)?)
+        .map_err(|_| Error::Unauthorized("invalid signature".to_string()))?;
+
+    if nonce.len() < 16 || nonce.len() > 64 {
+        return Err(Error::Unauthorized("invalid nonce".to_string()));
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| Error::Internal(e.to_string()))?
+        .as_secs();
+    if now.abs_diff(timestamp) > MAX_CLOCK_SKEW {
+        return Err(Error::Unauthorized("stale request".to_string()));
+    }
+
+    let stored = db
+        .get_token(name)
+        .map_err(|e| Error::Internal(e.to_string()))?
+        .ok_or_else(|| Error::Unauthorized("invalid signature".to_string()))?;
+
+    // tokens issued without the server secret can't sign requests
+    let key = match (server_secret, &stored.signing_key) {
+        (Some(server_secret), Some(sealed)) => open_signing_key(server_secret, sealed)
+            .map_err(|_| Error::Unauthorized("invalid signature".to_string()))?,
+        _ => {
+            return Err(Error::Unauthorized(
+                "request signing not enabled".to_string(),
+            ))
+        }
+    };
+    let mac = request_mac(&key, method.as_str(), path, timestamp, nonce, body);
+    mac.verify_slice(&sig)
+        .map_err(|_| Error::Unauthorized("invalid signature".to_string()))?;
+    check_grants(&stored, scope)?;
+
+    // only remember nonces of correctly signed requests, so that
+    // the tree can't be flooded by anonymous callers
+    db.prune_nonces(now.saturating_sub(2 * MAX_CLOCK_SKEW))
+        .map_err(|e| Error::Internal(e.to_string()))?;
+    let fresh = db
+        .insert_nonce(&format!("{}:{}", name, nonce), timestamp)
+        .map_err(|e| Error::Internal(e.to_string()))?;
+    if !fresh {
+        return Err(Error::Unauthorized("nonce already used".to_string()));
+    }
+    Ok(stored)
+}
+
+/// Callers allowed to use the admin routes
+#[derive(Clone)]
+pub enum Access {
+    /// callers need a token (with the right scope) stored in the db,
+    /// signed requests also need the server secret (`TOKEN_SECRET`)
+    Token(Db, Option<String>),
+    /// callers connected over the local admin socket, which is
+    /// protected by its filesystem permissions (see `local`)
+    Local,
+}
+
+impl From<Db> for Access {
+    fn from(db: Db) -> Self {
+        Access::Token(db, None)
+    }
+}
+
+/// Warp filter guarding admin routes without a body. Requires either
+/// a bearer token (passed via `Authorization` header) or a HMAC
+/// signed request made with a token with the given scope.
+pub fn authorize(
+    access: impl Into<Access>,
+    scope: Scope,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(warp::any().map(Bytes::new))
+        .and(with_clone(access.into()))
+        .and(with_clone(scope))
+        .and_then(check_request)
+        .map(|_| ())
+        .untuple_one()
+}
+
+/// Same as `authorize` but also consumes the request body (as it's
+/// a part of the signature) and passes it further.
+pub fn authorize_with_body(
+    access: impl Into<Access>,
+    scope: Scope,
+) -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::bytes())
+        .and(with_clone(access.into()))
+        .and(with_clone(scope))
+        .and_then(check_request)
+}
+
+async fn check_request(
+    method: Method,
+    path: FullPath,
+    headers: HeaderMap,
+    body: Bytes,
+    access: Access,
+    scope: Scope,
+) -> Result<Bytes, Rejection> {
+    let (db, server_secret) = match access {
+        Access::Token(db, server_secret) => (db, server_secret),
+        Access::Local => return Ok(body),
+    };
+    let bearer = headers
+        .get("authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+
+    let token = match bearer {
+        Some(token) => verify_token(&db, token.trim(), &scope),
+        None if headers.contains_key(SIGNATURE_HEADER) => verify_signature(
+            &db,
+            server_secret.as_deref(),
+            &method,
+            path.as_str(),
+            &headers,
+            &body,
+            &scope,
+        ),
+        None => Err(Error::Unauthorized(
+            "bearer token or request signature required".to_string(),
+        )),
+    }
+    .map_err(reject::custom)?;
+
+    debug!("Admin API call authorized with token {}", token.name);
+    Ok(body)
+}
+
+#[cfg(test)]
+pub mod helpers {
+    use strum::IntoEnumIterator;
+
+    use crate::db::{models::Scope, Db};
+
+    /// Issues a token valid for all scopes
+    pub fn admin_token(db: &Db) -> String {
+        super::issue_token(db, "test-admin", Scope::iter().collect(), None, None).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use warp::{http::StatusCode, hyper::body::Bytes, Filter};
+
+    use super::{
+        authorize, authorize_with_body, issue_token, sign_request, signature, signing_key,
+        verify_token, Access, KEY_HEADER, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER,
+    };
+    use crate::{
+        db::{helpers, models::Scope, Db},
+        handlers::{handle_rejection, Error},
+    };
+
+    const SERVER_SECRET: &str = "server-secret";
+
+    /// Access checking signed requests too
+    fn signing(db: Db) -> Access {
+        Access::Token(db, Some(SERVER_SECRET.to_owned()))
+    }
+
+    /// Headers of a request signed with the given key
+    fn signed_headers(key: &[u8], body: &[u8]) -> Vec<(&'static str, String)> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let nonce = "0123456789abcdef0123";
+        let sig = signature(key, "POST", "/api/v1/user", timestamp, nonce, body);
+        vec![
+            (KEY_HEADER, "signer".to_owned()),
+            (TIMESTAMP_HEADER, timestamp.to_string()),
+            (NONCE_HEADER, nonce.to_owned()),
+            (SIGNATURE_HEADER, hex::encode(sig)),
+        ]
+    }
+
+    #[test]
+    fn issued_token_is_stored_hashed() {
+        let db = helpers::tmp_db();
+        let token = issue_token(&db, "ci", vec![Scope::Stats], None, None).unwrap();
+        let (name, secret) = token.split_once('.').unwrap();
+        assert_eq!(name, "ci");
+
+        let stored = db.get_token("ci").unwrap().unwrap();
+        assert_ne!(stored.hash, secret);
+        assert!(!stored.hash.contains(secret));
+        assert!(issue_token(&db, "ci", vec![Scope::Stats], None, None).is_err());
+        assert!(issue_token(&db, "with.dot", vec![Scope::Stats], None, None).is_err());
+    }
+
+    #[test]
+    fn token_verification_checks_secret_scope_and_expiry() {
+        let db = helpers::tmp_db();
+        let token = issue_token(&db, "stats", vec![Scope::Stats], None, None).unwrap();
+
+        assert!(verify_token(&db, &token, &Scope::Stats).is_ok());
+        assert!(matches!(
+            verify_token(&db, &token, &Scope::Users),
+            Err(Error::Forbidden(_))
+        ));
+        assert!(matches!(
+            verify_token(&db, "stats.wrong-secret", &Scope::Stats),
+            Err(Error::Unauthorized(_))
+        ));
+        assert!(matches!(
+            verify_token(&db, "unknown.secret", &Scope::Stats),
+            Err(Error::Unauthorized(_))
+        ));
+
+        let expired =
+            issue_token(&db, "old", vec![Scope::Stats], Some(Duration::ZERO), None).unwrap();
+        assert!(matches!(
+            verify_token(&db, &expired, &Scope::Stats),
+            Err(Error::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn authorize_filter_maps_errors_to_status_codes() {
+        let db = helpers::tmp_db();
+        let token = issue_token(&db, "stats", vec![Scope::Stats], None, None).unwrap();
+        let filter = authorize(db, Scope::Users)
+            .map(warp::reply)
+            .recover(handle_rejection);
+
+        let resp = warp::test::request().reply(&filter).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = warp::test::request()
+            .header("authorization", format!("Bearer {}", token))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn signed_requests_are_accepted_once() {
+        let db = helpers::tmp_db();
+        let token =
+            issue_token(&db, "signer", vec![Scope::Users], None, Some(SERVER_SECRET)).unwrap();
+        let filter = authorize_with_body(signing(db), Scope::Users)
+            .map(|body: Bytes| String::from_utf8(body.to_vec()).unwrap())
+            .recover(handle_rejection);
+
+        let body = r#"{"name":"alice"}"#;
+        let headers = sign_request(&token, "POST", "/api/v1/user", body.as_bytes()).unwrap();
+        let signed = || {
+            headers.iter().fold(
+                warp::test::request()
+                    .method("POST")
+                    .path("/api/v1/user")
+                    .body(body),
+                |req, (k, v)| req.header(*k, v),
+            )
+        };
+
+        let resp = signed().reply(&filter).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), body);
+
+        let resp = signed().reply(&filter).await;
+        assert_eq!(
+            resp.status(),
+            StatusCode::UNAUTHORIZED,
+            "replay is rejected"
+        );
+    }
+
+    #[tokio::test]
+    async fn signed_requests_reject_tampering_and_stale_timestamps() {
+        let db = helpers::tmp_db();
+        let token =
+            issue_token(&db, "signer", vec![Scope::Users], None, Some(SERVER_SECRET)).unwrap();
+        let filter = authorize_with_body(signing(db), Scope::Users)
+            .map(|_| warp::reply())
+            .recover(handle_rejection);
+
+        let headers = sign_request(&token, "POST", "/api/v1/user", b"{}").unwrap();
+        let tampered = headers.iter().fold(
+            warp::test::request()
+                .method("POST")
+                .path("/api/v1/user")
+                .body(r#"{"admin":true}"#),
+            |req, (k, v)| req.header(*k, v),
+        );
+        let resp = tampered.reply(&filter).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let (_, secret) = token.split_once('.').unwrap();
+        let stale = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            - 3600;
+        let nonce = "0123456789abcdef0123";
+        let sig = signature(
+            &signing_key(secret),
+            "POST",
+            "/api/v1/user",
+            stale,
+            nonce,
+            b"{}",
+        );
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/api/v1/user")
+            .body("{}")
+            .header(KEY_HEADER, "signer")
+            .header(TIMESTAMP_HEADER, stale.to_string())
+            .header(NONCE_HEADER, nonce)
+            .header(SIGNATURE_HEADER, hex::encode(sig))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn stored_records_can_not_sign_requests() {
+        let db = helpers::tmp_db();
+        let token =
+            issue_token(&db, "signer", vec![Scope::Users], None, Some(SERVER_SECRET)).unwrap();
+        let stored = db.get_token("signer").unwrap().unwrap();
+        let sealed = stored.signing_key.clone().unwrap();
+        let (_, secret) = token.split_once('.').unwrap();
+        assert!(!sealed.contains(&hex::encode(signing_key(secret))));
+
+        let filter = authorize_with_body(signing(db.clone()), Scope::Users)
+            .map(|_| warp::reply())
+            .recover(handle_rejection);
+        let request = |headers: Vec<(&'static str, String)>| {
+            headers.into_iter().fold(
+                warp::test::request()
+                    .method("POST")
+                    .path("/api/v1/user")
+                    .body("{}"),
+                |req, (k, v)| req.header(k, v),
+            )
+        };
+
+        // everything the database holds is useless for signing
+        for key in [stored.hash.as_bytes(), sealed.as_bytes()] {
+            let resp = request(signed_headers(key, b"{}")).reply(&filter).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        // the server needs its secret to check signatures
+        let resp = request(signed_headers(&signing_key(secret), b"{}"))
+            .reply(
+                &authorize_with_body(db.clone(), Scope::Users)
+                    .map(|_| warp::reply())
+                    .recover(handle_rejection),
+            )
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = request(signed_headers(&signing_key(secret), b"{}"))
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,157 @@
+//! Checks of avatar images uploaded for addresses. Images end up in
+//! the LNURL metadata (and so in every pay request), keep them small.
+
+use crate::db::models::ImageFormat;
+
+/// Largest accepted image, in bytes (before base64 encoding)
+pub const MAX_SIZE: usize = 64 * 1024;
+/// Largest accepted width and height, in pixels
+pub const MAX_DIMENSION: u32 = 512;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Recognizes the image format by its signature
+pub fn detect_format(bytes: &[u8]) -> Option<ImageFormat> {
+    if bytes.starts_with(PNG_SIGNATURE) {
+        Some(ImageFormat::Png)
+    } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some(ImageFormat::Jpeg)
+    } else {
+        None
+    }
+}
+
+/// Reads width and height of the image from its header
+pub fn dimensions(format: ImageFormat, bytes: &[u8]) -> Option<(u32, u32)> {
+    match format {
+        ImageFormat::Png => png_dimensions(bytes),
+        ImageFormat::Jpeg => jpeg_dimensions(bytes),
+    }
+}
+
+/// PNG starts with the IHDR chunk carrying the dimensions
+fn png_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    if !bytes.starts_with(PNG_SIGNATURE) || bytes.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(bytes.get(16..20)?.try_into().ok()?);
+    let height = u32::from_be_bytes(bytes.get(20..24)?.try_into().ok()?);
+    Some((width, height))
+}
+
+/// JPEG carries the dimensions in the start of frame segment,
+/// which follows a (variable) number of other segments
+fn jpeg_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let mut pos = 2;
+    loop {
+        if *bytes.get(pos)? != 0xff {
+            return None;
+        }
+        let marker = *bytes.get(pos + 1)?;
+        match marker {
+            // padding
+            0xff => {
+                pos += 1;
+                continue;
+            }
+            // markers without a payload
+            0x01 | 0xd0..=0xd7 => {
+                pos += 2;
+                continue;
+            }
+            // start of scan or end of image before the frame
+            0xd9 | 0xda => return None,
+            _ => {}
+        }
+        let len = u16::from_be_bytes([*bytes.get(pos + 2)?, *bytes.get(pos + 3)?]) as usize;
+        // SOF0-SOF15, except DHT, JPG and DAC which share the range
+        if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+            let height = u16::from_be_bytes([*bytes.get(pos + 5)?, *bytes.get(pos + 6)?]);
+            let width = u16::from_be_bytes([*bytes.get(pos + 7)?, *bytes.get(pos + 8)?]);
+            return Some((width.into(), height.into()));
+        }
+        pos += 2 + len;
+    }
+}
+
+/// Checks the base64 encoded image, returns the reason it's not accepted
+pub fn check(format: ImageFormat, data: &str) -> Result<(), String> {
+    let bytes = base64::decode(data).map_err(|_| "image is not base64 encoded".to_string())?;
+    if bytes.len() > MAX_SIZE {
+        return Err(format!("image larger than {} bytes", MAX_SIZE));
+    }
+    if detect_format(&bytes) != Some(format) {
+        return Err(format!("image is not a {}", format));
+    }
+    match dimensions(format, &bytes) {
+        Some((w, h)) if w > 0 && h > 0 && w <= MAX_DIMENSION && h <= MAX_DIMENSION => Ok(()),
+        Some((w, h)) => Err(format!(
+            "image is {}x{}, up to {}x{} is accepted",
+            w, h, MAX_DIMENSION, MAX_DIMENSION
+        )),
+        None => Err("unable to read image dimensions".to_string()),
+    }
+}
+
+#[cfg(test)]
+pub mod helpers {
+    /// Minimal PNG header of the given dimensions
+    pub fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = super::PNG_SIGNATURE.to_vec();
+        bytes.extend_from_slice(&13u32.to_be_bytes());
+        bytes.extend_from_slice(b"IHDR");
+        bytes.extend_from_slice(&width.to_be_bytes());
+        bytes.extend_from_slice(&height.to_be_bytes());
+        bytes.extend_from_slice(&[8, 6, 0, 0, 0]);
+        bytes
+    }
+
+    /// Minimal JPEG header (APP0 followed by SOF0) of the given dimensions
+    pub fn jpeg(width: u16, height: u16) -> Vec<u8> {
+        let mut bytes = vec![0xff, 0xd8, 0xff, 0xe0, 0x00, 0x10];
+        bytes.extend_from_slice(b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+        bytes.extend_from_slice(&[0xff, 0xc0, 0x00, 0x11, 0x08]);
+        bytes.extend_from_slice(&height.to_be_bytes());
+        bytes.extend_from_slice(&width.to_be_bytes());
+        bytes.extend_from_slice(&[0x03, 0x01, 0x22, 0x00, 0x02, 0x11, 0x01, 0x03, 0x11, 0x01]);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::models::ImageFormat;
+
+    use super::{check, detect_format, dimensions, helpers, MAX_SIZE};
+
+    #[test]
+    fn image_dimensions_are_read() {
+        let png = helpers::png(120, 80);
+        assert_eq!(detect_format(&png), Some(ImageFormat::Png));
+        assert_eq!(dimensions(ImageFormat::Png, &png), Some((120, 80)));
+
+        let jpeg = helpers::jpeg(300, 200);
+        assert_eq!(detect_format(&jpeg), Some(ImageFormat::Jpeg));
+        assert_eq!(dimensions(ImageFormat::Jpeg, &jpeg), Some((300, 200)));
+
+        assert_eq!(detect_format(b"GIF89a"), None);
+    }
+
+    #[test]
+    fn images_are_checked() {
+        let png = base64::encode(helpers::png(256, 256));
+        assert!(check(ImageFormat::Png, &png).is_ok());
+        // declared format has to match
+        assert!(check(ImageFormat::Jpeg, &png).is_err());
+        assert!(check(ImageFormat::Png, "not base64!").is_err());
+
+        let huge = base64::encode(helpers::png(1024, 64));
+        assert!(check(ImageFormat::Png, &huge).is_err());
+        let jpeg = base64::encode(helpers::jpeg(512, 512));
+        assert!(check(ImageFormat::Jpeg, &jpeg).is_ok());
+
+        let mut heavy = helpers::png(64, 64);
+        heavy.resize(MAX_SIZE + 1, 0);
+        assert!(check(ImageFormat::Png, &base64::encode(heavy)).is_err());
+    }
+}
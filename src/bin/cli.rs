@@ -1,17 +1,42 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use anyhow::{anyhow, bail, Result};
 use cli_table::{format::Justify, Cell, Style, Table};
-use fs_extra::dir::{self, CopyOptions};
-use sataddress::{api::generate_stats, db::Db};
+use sataddress::{
+    api::{generate_stats, remove_entry, InvoiceQuery},
+    auth,
+    db::{self, schema, Db},
+    lnurl, local, pin, DbConfig, LNbitsConfig,
+};
+use serde_json::Value;
 
-use sataddress::db::models::{Params, Stats};
+use sataddress::db::models::{InvoiceRecord, Params, Scope, Stats};
+use sataddress::pin::PinScheme;
 
 use ansi_term::{self, Colour};
 use clap::{Parser, Subcommand};
+use envconfig::Envconfig;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about = "Sataddress management CLI tool")]
 struct Cli {
+    /// admin socket of the running server, the database is
+    /// accessed directly if the server is not running
+    #[arg(
+        long,
+        env = "ADMIN_SOCKET",
+        default_value = "sataddress.sock",
+        global = true
+    )]
+    socket: String,
+    /// path of the database, other settings (cache capacity,
+    /// compression etc.) are taken from the `DB_*` env variables
+    #[arg(long, env = "DB_PATH", default_value = db::DEFAULT_NAME, global = true)]
+    db: String,
     #[command(subcommand)]
     command: Commands,
 }
@@ -25,6 +50,74 @@ enum Commands {
     },
     /// gets usage stats data
     Stats {},
+    /// manages admin API tokens (server needs to be stopped)
+    Token {
+        #[command(subcommand)]
+        token_command: TokenCommands,
+    },
+    /// queries the ledger of issued invoices
+    Invoice {
+        #[command(subcommand)]
+        invoice_command: InvoiceCommands,
+    },
+    /// prints the bech32 encoded LNURL of an address and saves its QR code
+    Lnurl {
+        /// address (name@domain or name+link@domain of a pay link)
+        #[arg(short, long, value_name = "NAME@DOMAIN")]
+        address: String,
+        /// saves the QR code as a PNG image
+        #[arg(long, value_name = "FILE.png")]
+        png: Option<PathBuf>,
+        /// saves the QR code as an SVG image
+        #[arg(long, value_name = "FILE.svg")]
+        svg: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum InvoiceCommands {
+    /// list issued invoices, the most recent ones first
+    List {
+        /// only invoices of this address (name@domain)
+        #[arg(short, long, value_name = "NAME@DOMAIN")]
+        address: Option<String>,
+        /// maximum number of invoices shown
+        #[arg(short, long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// show details of a single invoice
+    Show {
+        /// hex encoded payment hash of the invoice
+        #[arg(long = "hash", value_name = "PAYMENT_HASH")]
+        payment_hash: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TokenCommands {
+    /// issue a new admin API token
+    Create {
+        /// unique name of the token
+        #[arg(short, long)]
+        name: String,
+        /// scope granted to the token (stats, users, invoices), can be repeated
+        #[arg(short, long = "scope", required = true)]
+        scopes: Vec<Scope>,
+        /// number of days after which the token expires
+        #[arg(short, long, value_name = "DAYS")]
+        expires_in: Option<u64>,
+        /// server secret, tokens issued without it can't sign requests
+        #[arg(long, env = "TOKEN_SECRET", hide_env_values = true)]
+        secret: Option<String>,
+    },
+    /// list issued tokens
+    List {},
+    /// revoke a token
+    Revoke {
+        /// name of the token to revoke
+        #[arg(short, long)]
+        name: String,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -42,31 +135,199 @@ enum DbCommands {
         #[arg(short, long, value_name = "FILE.json")]
         path: PathBuf,
     },
+
+    /// upgrade all entries to the current schema version
+    Migrate {
+        /// only report what would be migrated
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// hash legacy (plaintext) PINs of all entries
+    MigratePins {},
+
+    /// report how many entries still use PINs derived from (old) secrets
+    PinReport {
+        /// current PIN secret
+        #[arg(long, env = "PIN_SECRET", hide_env_values = true)]
+        secret: String,
+        /// comma separated list of previous PIN secrets
+        #[arg(long, env = "PIN_SECRET_PREVIOUS", hide_env_values = true)]
+        previous: Option<String>,
+    },
+
+    /// generate a new PIN for a single entry
+    ResetPin {
+        /// address of the entry (name@domain)
+        #[arg(short, long, value_name = "NAME@DOMAIN")]
+        address: String,
+    },
+
+    /// remove an entry (along with its LNbits user for keysend entries)
+    Delete {
+        /// address of the entry (name@domain)
+        #[arg(short, long, value_name = "NAME@DOMAIN")]
+        address: String,
+    },
+
+    /// stop serving payment requests for an entry, without removing it
+    Disable {
+        /// address of the entry (name@domain)
+        #[arg(short, long, value_name = "NAME@DOMAIN")]
+        address: String,
+    },
+
+    /// re-enable a disabled entry
+    Enable {
+        /// address of the entry (name@domain)
+        #[arg(short, long, value_name = "NAME@DOMAIN")]
+        address: String,
+    },
 }
 
 #[tokio::main]
 async fn main() {
     banner("Sataddress management CLI");
     let cli = Cli::parse();
+    let mut config = DbConfig::init_from_env().unwrap();
+    config.path = cli.db.clone();
 
     match cli.command {
         Commands::Db { db_command } => match db_command {
             DbCommands::Init { path } => {
-                db_init(path);
+                db_init(&config, path);
             }
             DbCommands::Dump { path } => {
-                db_dump(path);
+                db_dump(&cli.socket, &config, path).await;
+            }
+            DbCommands::Migrate { dry_run } => {
+                db_migrate(&config, dry_run);
+            }
+            DbCommands::MigratePins {} => {
+                db_migrate_pins(&config);
+            }
+            DbCommands::PinReport { secret, previous } => {
+                db_pin_report(&cli.socket, &config, &secret, previous.as_deref()).await;
+            }
+            DbCommands::ResetPin { address } => {
+                db_reset_pin(&cli.socket, &config, &address).await;
+            }
+            DbCommands::Delete { address } => {
+                db_delete(&cli.socket, &config, &address).await;
+            }
+            DbCommands::Disable { address } => {
+                db_set_disabled(&cli.socket, &config, &address, true).await;
+            }
+            DbCommands::Enable { address } => {
+                db_set_disabled(&cli.socket, &config, &address, false).await;
             }
         },
         Commands::Stats {} => {
-            app_stats();
+            app_stats(&cli.socket, &config).await;
+        }
+        Commands::Token { token_command } => match token_command {
+            TokenCommands::Create {
+                name,
+                scopes,
+                expires_in,
+                secret,
+            } => {
+                token_create(&config, &name, scopes, expires_in, secret.as_deref());
+            }
+            TokenCommands::List {} => {
+                token_list(&config);
+            }
+            TokenCommands::Revoke { name } => {
+                token_revoke(&config, &name);
+            }
+        },
+        Commands::Invoice { invoice_command } => match invoice_command {
+            InvoiceCommands::List { address, limit } => {
+                let query = InvoiceQuery {
+                    address,
+                    limit: Some(limit),
+                };
+                invoice_list(&cli.socket, &config, &query).await;
+            }
+            InvoiceCommands::Show { payment_hash } => {
+                invoice_show(&cli.socket, &config, &payment_hash).await;
+            }
+        },
+        Commands::Lnurl { address, png, svg } => {
+            if let Err(e) = address_lnurl(&address, png, svg) {
+                println!("[{}] {}", Colour::Red.paint("Error"), e);
+            }
         }
     }
 }
 
+/// Issues a new admin API token and prints it out (only once)
+fn token_create(
+    config: &DbConfig,
+    name: &str,
+    scopes: Vec<Scope>,
+    expires_in: Option<u64>,
+    secret: Option<&str>,
+) {
+    let db = open_db(config);
+    let ttl = expires_in.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+    match auth::issue_token(&db, name, scopes, ttl, secret) {
+        Ok(token) => {
+            println!("[{}] Token {} created", Colour::Green.paint("✓"), name);
+            println!("{}", Colour::Yellow.paint(token));
+            println!("Store it safely, it won't be shown again.");
+            if secret.is_none() {
+                println!("TOKEN_SECRET is not set, the token can't sign requests.");
+            }
+        }
+        Err(e) => println!("[{}] {}", Colour::Red.paint("Error"), e),
+    }
+}
+
+/// Lists issued admin API tokens (without secrets)
+fn token_list(config: &DbConfig) {
+    let db = open_db(config);
+
+    let mut table = vec![];
+    for token in db.list_tokens().unwrap() {
+        let scopes: Vec<String> = token.scopes.iter().map(|s| s.to_string()).collect();
+        let expires = match token.expires_at {
+            Some(_) if token.is_expired() => Colour::Red.paint("expired").to_string(),
+            Some(t) => fmt_time(t),
+            None => "never".to_string(),
+        };
+        table.push(vec![
+            token.name.cell(),
+            scopes.join(",").cell(),
+            fmt_time(token.created_at).cell(),
+            expires.cell(),
+        ]);
+    }
+
+    let table = table
+        .table()
+        .title(vec![
+            "Name".cell().bold(true),
+            "Scopes".cell().bold(true),
+            "Created at".cell().bold(true),
+            "Expires at".cell().bold(true),
+        ])
+        .bold(true);
+    println!("{}", table.display().unwrap());
+}
+
+/// Revokes (removes) an admin API token
+fn token_revoke(config: &DbConfig, name: &str) {
+    let db = open_db(config);
+    match db.delete_token(name).unwrap() {
+        Some(_) => println!("[{}] Token {} revoked", Colour::Green.paint("✓"), name),
+        None => println!("[{}] Token {} not found", Colour::Red.paint("Error"), name),
+    }
+}
+
 /// Imports data from a json dump into the `sled` database
-fn db_init(path: PathBuf) {
-    let db = Db::init().unwrap();
+fn db_init(config: &DbConfig, path: PathBuf) {
+    let db = open_db(config);
     let text = std::fs::read_to_string(&path).unwrap();
     let data: Vec<Params> = serde_json::from_str(&text).unwrap();
     println!("data is {:?}", data);
@@ -89,24 +350,267 @@ fn db_init(path: PathBuf) {
 }
 
 /// Dumps `sled` database into a json file at provided `path`
-fn db_dump(path: PathBuf) {
-    let db = Db::init().unwrap();
-    let mut data = vec![];
-    for r in db.iter() {
-        let ivec = r.unwrap();
-        let p: Params = rmp_serde::from_slice(&ivec.1).unwrap();
-        data.push(p);
-    }
+async fn db_dump(socket: &str, config: &DbConfig, path: PathBuf) {
+    let data = Source::open(socket, config).await.list().await.unwrap();
     std::fs::write(path, serde_json::to_string_pretty(&data).unwrap()).unwrap();
 }
 
+/// Upgrades entries to the current schema version and prints the report
+fn db_migrate(config: &DbConfig, dry_run: bool) {
+    let db = open_db(config);
+    let report = db.migrate(dry_run).unwrap();
+
+    let mut table = vec![vec![
+        "Up to date".cell(),
+        report.up_to_date.cell().justify(Justify::Right),
+    ]];
+    for (version, count) in report.migrated.iter() {
+        table.push(vec![
+            format!("From v{} to v{}", version, schema::CURRENT_VERSION).cell(),
+            count.cell().justify(Justify::Right),
+        ]);
+    }
+    table.push(vec![
+        "Failed".cell(),
+        report.failed.len().cell().justify(Justify::Right),
+    ]);
+    let table = table
+        .table()
+        .title(vec!["Records".cell().bold(true), "Count".cell().bold(true)])
+        .bold(true);
+
+    if dry_run {
+        println!("Dry run, nothing has been written");
+    }
+    println!("{}", table.display().unwrap());
+    for (key, reason) in report.failed.iter() {
+        println!("[{}] {}: {}", Colour::Red.paint("Failed"), key, reason);
+    }
+}
+
+/// Moves all entries still using the legacy plaintext PIN to hashed PINs
+fn db_migrate_pins(config: &DbConfig) {
+    let db = open_db(config);
+    let mut migrated = 0;
+    for mut p in db.list().unwrap() {
+        if pin::migrate_legacy_pin(&mut p).unwrap() {
+            db.update(&p).unwrap();
+            migrated += 1;
+            println!(
+                "[{}] {}@{}",
+                Colour::Green.paint("Migrated"),
+                p.name,
+                p.domain
+            );
+        }
+    }
+    println!(
+        "[{}] {} entries migrated to hashed PINs",
+        Colour::Green.paint("✓"),
+        migrated
+    );
+}
+
+/// Shows how many entries use hashed PINs and how many still rely
+/// on PINs derived from the current or one of the previous secrets
+async fn db_pin_report(socket: &str, config: &DbConfig, secret: &str, previous: Option<&str>) {
+    let records = Source::open(socket, config).await.list().await.unwrap();
+    let mut secrets = vec![secret];
+    if let Some(previous) = previous {
+        secrets.extend(
+            previous
+                .split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty()),
+        );
+    }
+
+    let mut hashed = 0;
+    let mut unknown = 0;
+    let mut legacy = vec![0; secrets.len()];
+    for p in records {
+        match pin::pin_scheme(&p, &secrets) {
+            PinScheme::Hashed => hashed += 1,
+            PinScheme::Legacy(idx) => legacy[idx] += 1,
+            PinScheme::Unknown => unknown += 1,
+        }
+    }
+
+    let mut table = vec![vec![
+        "Hashed (migrated)".cell(),
+        hashed.cell().justify(Justify::Right),
+    ]];
+    for (idx, count) in legacy.iter().enumerate() {
+        let name = match idx {
+            0 => "Legacy, current secret".to_string(),
+            n => format!("Legacy, previous secret #{}", n),
+        };
+        table.push(vec![name.cell(), count.cell().justify(Justify::Right)]);
+    }
+    table.push(vec![
+        "Legacy, unknown secret".cell(),
+        unknown.cell().justify(Justify::Right),
+    ]);
+
+    let table = table
+        .table()
+        .title(vec![
+            "PIN scheme".cell().bold(true),
+            "Entries".cell().bold(true),
+        ])
+        .bold(true);
+    println!("{}", table.display().unwrap());
+
+    let on_old: usize = legacy.iter().skip(1).sum();
+    if on_old > 0 {
+        println!(
+            "{} entries still rely on previous secrets",
+            Colour::Yellow.paint(on_old.to_string())
+        );
+    }
+}
+
+/// Generates a new PIN for a single entry
+async fn db_reset_pin(socket: &str, config: &DbConfig, address: &str) {
+    match Source::open(socket, config).await.reset_pin(address).await {
+        Ok(Some(new_pin)) => {
+            println!("[{}] New PIN for {}:", Colour::Green.paint("✓"), address);
+            println!("{}", Colour::Yellow.paint(new_pin));
+        }
+        Ok(None) => println!("[{}] {} not found", Colour::Red.paint("Error"), address),
+        Err(e) => println!("[{}] {}", Colour::Red.paint("Error"), e),
+    }
+}
+
+/// Removes a single entry
+async fn db_delete(socket: &str, config: &DbConfig, address: &str) {
+    match Source::open(socket, config).await.delete(address).await {
+        Ok(true) => println!("[{}] {} deleted", Colour::Green.paint("✓"), address),
+        Ok(false) => println!("[{}] {} not found", Colour::Red.paint("Error"), address),
+        Err(e) => println!("[{}] {}", Colour::Red.paint("Error"), e),
+    }
+}
+
+/// Disables or re-enables a single entry
+async fn db_set_disabled(socket: &str, config: &DbConfig, address: &str, disabled: bool) {
+    let state = if disabled { "disabled" } else { "enabled" };
+    match Source::open(socket, config)
+        .await
+        .set_disabled(address, disabled)
+        .await
+    {
+        Ok(true) => println!("[{}] {} {}", Colour::Green.paint("✓"), address, state),
+        Ok(false) => println!("[{}] {} not found", Colour::Red.paint("Error"), address),
+        Err(e) => println!("[{}] {}", Colour::Red.paint("Error"), e),
+    }
+}
+
+/// Formats time as unix timestamp
+fn fmt_time(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs().to_string())
+        .unwrap_or_default()
+}
+
+/// Lists invoices from the ledger
+async fn invoice_list(socket: &str, config: &DbConfig, query: &InvoiceQuery) {
+    let invoices = match Source::open(socket, config)
+        .await
+        .list_invoices(query)
+        .await
+    {
+        Ok(invoices) => invoices,
+        Err(e) => {
+            println!("[{}] {}", Colour::Red.paint("Error"), e);
+            return;
+        }
+    };
+
+    let mut table = vec![];
+    for invoice in invoices.iter() {
+        table.push(vec![
+            invoice.payment_hash.clone().cell(),
+            invoice.address.clone().cell(),
+            (invoice.msat / 1000).cell().justify(Justify::Right),
+            invoice.comment.clone().unwrap_or_default().cell(),
+            invoice
+                .payer_data
+                .as_ref()
+                .and_then(|p| p.label())
+                .unwrap_or_default()
+                .cell(),
+            invoice.status.to_string().cell(),
+            fmt_time(invoice.created_at).cell(),
+        ]);
+    }
+
+    let table = table
+        .table()
+        .title(vec![
+            "Payment hash".cell().bold(true),
+            "Address".cell().bold(true),
+            "Sats".cell().bold(true),
+            "Comment".cell().bold(true),
+            "Payer".cell().bold(true),
+            "Status".cell().bold(true),
+            "Created at".cell().bold(true),
+        ])
+        .bold(true);
+    println!("{}", table.display().unwrap());
+}
+
+/// Prints details of a single invoice from the ledger
+async fn invoice_show(socket: &str, config: &DbConfig, payment_hash: &str) {
+    match Source::open(socket, config)
+        .await
+        .get_invoice(payment_hash)
+        .await
+    {
+        Ok(Some(invoice)) => {
+            println!("{}", serde_json::to_string_pretty(&invoice).unwrap())
+        }
+        Ok(None) => println!(
+            "[{}] invoice {} not found",
+            Colour::Red.paint("Error"),
+            payment_hash
+        ),
+        Err(e) => println!("[{}] {}", Colour::Red.paint("Error"), e),
+    }
+}
+
+/// Prints the LNURL of the address and writes its QR code images
+fn address_lnurl(address: &str, png: Option<PathBuf>, svg: Option<PathBuf>) -> Result<()> {
+    let (name, domain) = split_address(address)?;
+    let url = lnurl::pay_url(domain, name);
+    let code = lnurl::encode(&url)?;
+    println!("{}", url);
+    println!("{}", Colour::Yellow.paint(&code));
+
+    if let Some(path) = png {
+        std::fs::write(&path, lnurl::qr_png(&code)?)?;
+        println!(
+            "[{}] QR code saved to {}",
+            Colour::Green.paint("✓"),
+            path.display()
+        );
+    }
+    if let Some(path) = svg {
+        std::fs::write(&path, lnurl::qr_svg(&code)?)?;
+        println!(
+            "[{}] QR code saved to {}",
+            Colour::Green.paint("✓"),
+            path.display()
+        );
+    }
+    Ok(())
+}
+
 /// Prints basic usage statistics for the application
-fn app_stats() {
+async fn app_stats(socket: &str, config: &DbConfig) {
     // yeah that's highly inefficient but once that
     // becomes a problem we should move to an actual
     // telemetry system anyways
-    let db = DbCopy::init();
-    let (data, summary) = generate_stats(&db.0).unwrap();
+    let (data, summary) = Source::open(socket, config).await.stats().await.unwrap();
     let mut data: Vec<(&String, &Stats)> = data.iter().collect();
     data.sort_by(|a, b| b.1.cmp(a.1));
 
@@ -119,6 +623,22 @@ fn app_stats() {
                 .cell()
                 .justify(Justify::Right),
         ],
+        vec![
+            "Invoices settled".cell(),
+            summary["settled"]
+                .as_i64()
+                .unwrap_or_default()
+                .cell()
+                .justify(Justify::Right),
+        ],
+        vec![
+            "Sats received".cell(),
+            summary["received"]
+                .as_i64()
+                .unwrap_or_default()
+                .cell()
+                .justify(Justify::Right),
+        ],
         vec![
             "API calls".cell(),
             summary["calls"]
@@ -187,27 +707,110 @@ fn banner(quote: &str) {
     println!("{}", text);
 }
 
-static DEFAULT_TMP_DB: &str = "db.tmp";
-/// Creates a temporary copy (on disk) of the database to interact with
-/// as `sled` does not allow to have two readers at the same time and
-/// we want to be able to interact via `cli` while the server is running
-struct DbCopy(Db);
+/// Opens the database directly, which is possible
+/// only when the server is not running
+fn open_db(config: &DbConfig) -> Db {
+    Db::init(config).expect("unable to open the database, make sure the server is stopped")
+}
+
+/// Source of the data: the running server (over its admin socket)
+/// or the database itself when the server is stopped
+enum Source {
+    Server(local::Client),
+    Direct(Db),
+}
 
-impl DbCopy {
-    /// Returns a `Db` struct that is interacting with a copy
-    /// of the sled db.
-    fn init() -> Self {
-        let mut options = CopyOptions::new();
-        options.copy_inside = true;
-        dir::copy(sataddress::db::DEFAULT_NAME, DEFAULT_TMP_DB, &options).unwrap();
+impl Source {
+    async fn open(socket: &str, config: &DbConfig) -> Self {
+        match local::Client::connect(socket).await {
+            Some(client) => Source::Server(client),
+            None => Source::Direct(open_db(config)),
+        }
+    }
 
-        Self(Db::from_path(DEFAULT_TMP_DB).unwrap())
+    async fn list(&self) -> Result<Vec<Params>> {
+        match self {
+            Source::Server(client) => client.list().await,
+            Source::Direct(db) => db.list(),
+        }
+    }
+
+    async fn list_invoices(&self, query: &InvoiceQuery) -> Result<Vec<InvoiceRecord>> {
+        match self {
+            Source::Server(client) => client.list_invoices(query).await,
+            Source::Direct(db) => {
+                let mut invoices = db.list_invoices(query.address.as_deref())?;
+                if let Some(limit) = query.limit {
+                    invoices.truncate(limit);
+                }
+                Ok(invoices)
+            }
+        }
+    }
+
+    async fn get_invoice(&self, payment_hash: &str) -> Result<Option<InvoiceRecord>> {
+        match self {
+            Source::Server(client) => client.get_invoice(payment_hash).await,
+            Source::Direct(db) => db.get_invoice(&payment_hash.to_lowercase()),
+        }
+    }
+
+    async fn stats(&self) -> Result<(HashMap<String, Stats>, Value)> {
+        match self {
+            Source::Server(client) => client.stats().await,
+            Source::Direct(db) => generate_stats(db),
+        }
+    }
+
+    async fn reset_pin(&self, address: &str) -> Result<Option<String>> {
+        let db = match self {
+            Source::Server(client) => return client.reset_pin(address).await,
+            Source::Direct(db) => db,
+        };
+        let (name, domain) = split_address(address)?;
+        match db.get(name, domain)? {
+            Some(mut p) => {
+                let new_pin = pin::reset_pin(&mut p)?;
+                db.update(&p)?;
+                Ok(Some(new_pin))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn delete(&self, address: &str) -> Result<bool> {
+        let db = match self {
+            Source::Server(client) => return client.delete(address).await,
+            Source::Direct(db) => db,
+        };
+        let (name, domain) = split_address(address)?;
+        // needed to clean up LNbits elements of keysend entries
+        let lnbits = LNbitsConfig::init_from_env()
+            .map_err(|e| anyhow!("LNbits settings are required: {}", e))?;
+        Ok(remove_entry(db, &lnbits, name, domain).await?.is_some())
+    }
+
+    async fn set_disabled(&self, address: &str, disabled: bool) -> Result<bool> {
+        let db = match self {
+            Source::Server(client) => return client.set_disabled(address, disabled).await,
+            Source::Direct(db) => db,
+        };
+        let (name, domain) = split_address(address)?;
+        match db.get(name, domain)? {
+            Some(mut p) => {
+                p.disabled = disabled;
+                db.update(&p)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
     }
 }
 
-impl Drop for DbCopy {
-    /// Removes the temporary copy of the db in the filesystem
-    fn drop(&mut self) {
-        dir::remove(DEFAULT_TMP_DB).unwrap();
+/// Splits `name@domain` address into its parts
+fn split_address(address: &str) -> Result<(&str, &str)> {
+    match address.rsplit_once('@') {
+        Some(parts) => Ok(parts),
+        None => bail!("invalid address {}", address),
     }
 }
@@ -1,7 +1,7 @@
-use std::env;
+use std::{env, time::Duration};
 
 use envconfig::Envconfig;
-use sataddress::{api, db, handlers, with_clone, Config};
+use sataddress::{api, db, handlers, local, nostr, settlement, webhook, with_clone, Config};
 use warp::Filter;
 
 use log::*;
@@ -19,7 +19,40 @@ async fn main() {
     let config = Config::init_from_env().unwrap();
     debug!("Running with the following config {:?}", config);
 
-    let db = db::Db::init().unwrap();
+    let db = db::Db::init(&config.db).unwrap();
+    let report = db.migrate(false).unwrap();
+    info!(
+        "Database schema v{}: {} records, {} migrated, {} failed",
+        db::schema::CURRENT_VERSION,
+        report.total,
+        report.migrated_total(),
+        report.failed.len()
+    );
+    for (key, reason) in report.failed.iter() {
+        error!("Unable to migrate {}: {}", key, reason);
+    }
+
+    // local admin channel for the `cli`
+    let admin = local::serve(db.clone(), config.clone(), &config.admin_socket).unwrap();
+    tokio::spawn(admin);
+
+    // tracking of invoice payments
+    if config.settlement_interval > 0 {
+        let every = Duration::from_secs(config.settlement_interval);
+        tokio::spawn(settlement::run(db.clone(), config.clone(), every));
+    }
+
+    // delivery of queued webhooks
+    if config.webhook_interval > 0 {
+        let every = Duration::from_secs(config.webhook_interval);
+        tokio::spawn(webhook::run(db.clone(), config.clone(), every));
+    }
+
+    // publication of zap receipts
+    if config.nostr_interval > 0 {
+        let every = Duration::from_secs(config.nostr_interval);
+        tokio::spawn(nostr::run(db.clone(), config.clone(), every));
+    }
 
     let base_dir = format!("{}/", env!("CARGO_MANIFEST_DIR"));
 
@@ -47,6 +80,37 @@ async fn main() {
         .and(warp::query::<HashMap<String, String>>())
         .and_then(handlers::lnurl);
 
+    // LUD-21 check of the invoice payment
+    let verify = base
+        .clone()
+        .and(warp::path!(
+            ".well-known" / "lnurlp" / String / "verify" / ..
+        ))
+        .and(warp::host::optional())
+        .and_then(api::check_domain)
+        .untuple_one()
+        .and(warp::path!(String))
+        .and_then(handlers::verify);
+
+    // bech32 encoded LNURL of an address
+    let lnurl_code = base
+        .clone()
+        .and(warp::path!("lnurl" / String))
+        .and(warp::host::optional())
+        .and_then(api::check_domain)
+        .untuple_one()
+        .and_then(handlers::lnurl_code);
+
+    // QR code (qr.png or qr.svg) of the LNURL of an address
+    let lnurl_qr = base
+        .clone()
+        .and(warp::path!("lnurl" / String / ..))
+        .and(warp::host::optional())
+        .and_then(api::check_domain)
+        .untuple_one()
+        .and(warp::path!(String))
+        .and_then(handlers::lnurl_qr);
+
     // wizard add/update of an alias
     let grab = base
         .clone()
@@ -54,20 +118,27 @@ async fn main() {
         .and(warp::body::aggregate())
         .and_then(handlers::grab);
 
+    // removal of an alias by its owner
+    let delete = base
+        .clone()
+        .and(warp::path("delete"))
+        .and(warp::post())
+        .and(warp::body::aggregate())
+        .and_then(handlers::delete);
+
     // basic REST API to manage entries in the DB
-    let api = warp::path!("api" / "v1" / ..)
-        .and(warp::header::<String>("X-PIN"))
-        .and(with_clone(config.clone()))
-        // .and(warp::body::json())
-        .and_then(api::authenticate)
-        .untuple_one()
-        .and(api::handlers(db.clone(), config.clone()));
+    // (requires a bearer token, see `cli token`)
+    let api = warp::path!("api" / "v1" / ..).and(api::handlers(db.clone(), config.clone()));
 
     let routes = warp::any().and(
         index
             .or(statics)
             .or(ln_url)
+            .or(verify)
+            .or(lnurl_code)
+            .or(lnurl_qr)
             .or(grab)
+            .or(delete)
             .or(api)
             .recover(handlers::handle_rejection),
     );
@@ -1,90 +1,652 @@
-use log::debug;
-use std::env;
+use log::{debug, warn};
+use std::{
+    cmp::Reverse,
+    env,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{bail, Result};
 
-use self::models::Params;
+use crate::DbConfig;
+
+use self::{
+    models::{
+        ApiToken, InvoiceRecord, InvoiceStatus, LedgerTotals, Params, Stats, WebhookJob,
+        ZapReceiptJob,
+    },
+    schema::MigrationReport,
+    store::{MemoryStore, SledStore, Store},
+};
+
+/// Versioning and migrations of stored records
+pub mod schema;
+/// Storage backends of the address records
+pub mod store;
 
 pub static DEFAULT_NAME: &str = "sataddress.db";
-pub struct Db(sled::Db);
+/// Name of the tree holding admin API tokens
+static TOKENS_TREE: &str = "api_tokens";
+/// Name of the tree holding nonces of signed admin API requests
+static NONCES_TREE: &str = "api_nonces";
+/// Name of the tree holding the ledger of issued invoices
+static INVOICES_TREE: &str = "invoices";
+/// Name of the tree indexing pending invoices of the ledger
+/// (payment hash => expiry), so checks don't scan the whole ledger
+static PENDING_INVOICES_TREE: &str = "pending_invoices";
+/// Name of the tree holding running totals of the ledger
+/// (settled invoices and msat received), so stats don't scan it
+static LEDGER_TOTALS_TREE: &str = "ledger_totals";
+static LEDGER_TOTALS_KEY: &str = "settled";
+/// Name of the tree holding the queue of outgoing webhooks
+static WEBHOOKS_TREE: &str = "webhooks";
+/// Name of the tree indexing queued webhooks by their next attempt
+/// (unix time and id, both big endian), so runs read only due ones
+static WEBHOOKS_DUE_TREE: &str = "webhooks_due";
+/// Name of the tree holding the queue of zap receipts to publish
+static ZAP_RECEIPTS_TREE: &str = "zap_receipts";
 
-impl Clone for Db {
-    fn clone(&self) -> Self {
-        Self(self.0.clone())
-    }
+/// Database handle. Address records live in a pluggable `Store`,
+/// auxiliary data (tokens, nonces, invoices, webhooks, zap receipts)
+/// in separate `sled` trees.
+#[derive(Clone)]
+pub struct Db {
+    store: Arc<dyn Store>,
+    trees: sled::Db,
 }
 
 impl Db {
     pub fn from_path(path: &str) -> Result<Self> {
-        Ok(Self(sled::open(path)?))
+        let db = sled::open(path)?;
+        Ok(Self::with_store(Arc::new(SledStore::new(db.clone())), db))
     }
 
-    pub fn init() -> Result<Self> {
-        let db = Db::from_path(DEFAULT_NAME)?;
+    /// Uses the given store for the address records
+    /// and `trees` for the auxiliary data.
+    pub fn with_store(store: Arc<dyn Store>, trees: sled::Db) -> Self {
+        Self { store, trees }
+    }
 
-        // print db data in case we're in the debug mode
-        if env::var_os("RUST_LOG").unwrap_or_else(|| "".into()) == "debug" {
-            for r in db.0.iter() {
-                let ivec = r.unwrap();
-                let p: Params = rmp_serde::from_slice(&ivec.1).unwrap();
-                debug!("{}@{}: stats => {:?}", p.name, p.domain, p.stats);
-            }
+    /// Database that lives only in memory, useful for tests
+    pub fn in_memory() -> Result<Self> {
+        let trees = sled::Config::new().temporary(true).open()?;
+        Ok(Self::with_store(Arc::new(MemoryStore::default()), trees))
+    }
+
+    /// Keeps the address records in the SQLite database at `path`,
+    /// auxiliary data (tokens, nonces, ledger and queues) stays in
+    /// the `trees` sled database, so it's still single process.
+    #[cfg(feature = "sqlite")]
+    pub fn from_sqlite(path: &str, trees: sled::Db) -> Result<Self> {
+        let store = store::SqliteStore::open(path)?;
+        Ok(Self::with_store(Arc::new(store), trees))
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    pub fn from_sqlite(_path: &str, _trees: sled::Db) -> Result<Self> {
+        bail!("built without the sqlite feature")
+    }
+
+    /// Opens the database as described by the config
+    pub fn open(config: &DbConfig) -> Result<Self> {
+        let sled = sled::Config::new()
+            .path(&config.path)
+            .cache_capacity(config.cache_capacity)
+            .flush_every_ms(match config.flush_every_ms {
+                0 => None,
+                ms => Some(ms),
+            })
+            .use_compression(config.compression)
+            .compression_factor(config.compression_factor)
+            .open()?;
+        match config.sqlite_path {
+            Some(ref path) => Self::from_sqlite(path, sled),
+            None => Ok(Self::with_store(
+                Arc::new(SledStore::new(sled.clone())),
+                sled,
+            )),
         }
+    }
 
+    pub fn init(config: &DbConfig) -> Result<Self> {
+        let db = Db::open(config)?;
+        db.log_records();
+        db.init_ledger_totals()?;
         Ok(db)
     }
 
-    pub fn clear(&self) -> Result<(), sled::Error> {
-        self.0.clear()
+    /// Prints db data in case we're in the debug mode
+    pub fn log_records(&self) {
+        if env::var_os("RUST_LOG").unwrap_or_else(|| "".into()) != "debug" {
+            return;
+        }
+        match self.list() {
+            Ok(records) => {
+                for p in records {
+                    debug!("{}@{}: stats => {:?}", p.name, p.domain, p.stats);
+                }
+            }
+            Err(e) => warn!("Unable to list records: {}", e),
+        }
+    }
+
+    /// Upgrades all records to the current schema version.
+    /// With `dry_run` nothing is written, only the report is generated.
+    pub fn migrate(&self, dry_run: bool) -> Result<MigrationReport> {
+        self.store.migrate(dry_run)
     }
 
-    pub fn iter(&self) -> sled::Iter {
-        self.0.iter()
+    /// Returns all the records
+    pub fn list(&self) -> Result<Vec<Params>> {
+        self.store.list()
+    }
+
+    /// Returns all the records of the given domain
+    pub fn scan_domain(&self, domain: &str) -> Result<Vec<Params>> {
+        self.store.scan_domain(domain)
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        self.store.clear()
     }
 
     pub fn insert(&self, username: &str, domain: &str, params: &Params) -> Result<Option<()>> {
-        let key = format!("{}@{}", username, domain);
-        let value = rmp_serde::to_vec_named(params)?;
-        match self.0.insert(key, value)? {
-            Some(_) => Ok(Some(())),
-            None => Ok(None),
+        if params.name != username || params.domain != domain {
+            bail!("Key does not match the record: {}@{}", username, domain);
+        }
+        match self.store.insert(params)? {
+            true => Ok(Some(())),
+            false => Ok(None),
         }
     }
 
+    /// Inserts a new record, returns `false` (and writes nothing)
+    /// if the record already exists
+    pub fn create(&self, params: &Params) -> Result<bool> {
+        self.store.create(params)
+    }
+
     pub fn update(&self, params: &Params) -> Result<()> {
-        let key = &format!("{}@{}", params.name, params.domain);
-        if !self.0.contains_key(key)? {
-            bail!("Key does not exist: {}", key);
-        }
+        self.store.update(params)
+    }
+
+    /// Replaces the record with what `f` returns for it, atomically
+    /// (see `Store::modify`), returns the record as stored after
+    pub fn modify(
+        &self,
+        username: &str,
+        domain: &str,
+        mut f: impl FnMut(&Params) -> Option<Params>,
+    ) -> Result<Option<Params>> {
+        self.store.modify(username, domain, &mut |current| {
+            f(current).filter(|p| p.name == username && p.domain == domain)
+        })
+    }
 
-        let value = rmp_serde::to_vec_named(params)?;
-        self.0.insert(key, value)?;
+    /// Replaces the record read as `read` with `params`, unless it changed
+    /// in the meantime (stats aside, pay requests bump them and the stored
+    /// ones are kept). Returns `false` if it did or it's gone.
+    pub fn replace(&self, read: &Params, params: &Params) -> Result<bool> {
+        let mut replaced = false;
+        self.modify(&read.name, &read.domain, |current| {
+            let unchanged = Params {
+                stats: read.stats.clone(),
+                ..current.clone()
+            } == *read;
+            replaced = unchanged;
+            unchanged.then(|| Params {
+                stats: current.stats.clone(),
+                ..params.clone()
+            })
+        })?;
+        Ok(replaced)
+    }
+
+    /// Updates the stats of the record in place, leaving the rest of it
+    /// as stored. Does nothing if the record is gone.
+    pub fn bump_stats(&self, username: &str, domain: &str, f: impl Fn(&mut Stats)) -> Result<()> {
+        self.modify(username, domain, |current| {
+            let mut params = current.clone();
+            f(&mut params.stats);
+            Some(params)
+        })?;
         Ok(())
     }
 
+    pub fn delete(&self, username: &str, domain: &str) -> Result<Option<Params>> {
+        self.store.delete(username, domain)
+    }
+
     pub fn get(&self, username: &str, domain: &str) -> Result<Option<Params>> {
-        let key = format!("{}@{}", username, domain);
-        let ivec = self.0.get(key)?;
+        self.store.get(username, domain)
+    }
+}
+
+/// Admin API tokens storage
+impl Db {
+    fn tokens(&self) -> Result<sled::Tree> {
+        Ok(self.trees.open_tree(TOKENS_TREE)?)
+    }
+
+    pub fn insert_token(&self, token: &ApiToken) -> Result<()> {
+        let value = rmp_serde::to_vec_named(token)?;
+        self.tokens()?.insert(&token.name, value)?;
+        Ok(())
+    }
+
+    pub fn get_token(&self, name: &str) -> Result<Option<ApiToken>> {
+        match self.tokens()?.get(name)? {
+            Some(ivec) => Ok(Some(rmp_serde::from_slice(&ivec)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete_token(&self, name: &str) -> Result<Option<ApiToken>> {
+        match self.tokens()?.remove(name)? {
+            Some(ivec) => Ok(Some(rmp_serde::from_slice(&ivec)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn list_tokens(&self) -> Result<Vec<ApiToken>> {
+        self.tokens()?
+            .iter()
+            .map(|r| Ok(rmp_serde::from_slice(&r?.1)?))
+            .collect()
+    }
+
+    /// Remembers the nonce used at `timestamp`, returns `false`
+    /// if the nonce has been already seen.
+    pub fn insert_nonce(&self, nonce: &str, timestamp: u64) -> Result<bool> {
+        let tree = self.trees.open_tree(NONCES_TREE)?;
+        let swap = tree.compare_and_swap(
+            nonce,
+            None as Option<&[u8]>,
+            Some(&timestamp.to_be_bytes()[..]),
+        )?;
+        Ok(swap.is_ok())
+    }
+
+    fn invoices(&self) -> Result<sled::Tree> {
+        Ok(self.trees.open_tree(INVOICES_TREE)?)
+    }
+
+    fn pending_invoices(&self) -> Result<sled::Tree> {
+        Ok(self.trees.open_tree(PENDING_INVOICES_TREE)?)
+    }
+
+    /// Adds the invoice to the pending index or removes it from there.
+    /// The index is updated before the ledger, entries left behind
+    /// are dropped by `list_pending_invoices`.
+    fn index_invoice(&self, invoice: &InvoiceRecord) -> Result<()> {
+        let index = self.pending_invoices()?;
+        match invoice.status {
+            InvoiceStatus::Pending => {
+                let expiry = invoice.expires_at.duration_since(UNIX_EPOCH)?.as_secs();
+                index.insert(&invoice.payment_hash, &expiry.to_be_bytes())?;
+            }
+            _ => {
+                index.remove(&invoice.payment_hash)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn ledger_totals_tree(&self) -> Result<sled::Tree> {
+        Ok(self.trees.open_tree(LEDGER_TOTALS_TREE)?)
+    }
+
+    /// Counts the invoice in the ledger totals, if it got settled
+    /// just now (it `was_settled` not)
+    fn count_settlement(&self, was_settled: bool, invoice: &InvoiceRecord) -> Result<()> {
+        if was_settled || invoice.status != InvoiceStatus::Settled {
+            return Ok(());
+        }
+        let tree = self.ledger_totals_tree()?;
+        loop {
+            let current = tree.get(LEDGER_TOTALS_KEY)?;
+            let mut totals = match current {
+                Some(ref value) => rmp_serde::from_slice(value)?,
+                None => LedgerTotals::default(),
+            };
+            totals.settled += 1;
+            totals.msat_received += invoice.msat_received.unwrap_or_default();
+            let value = rmp_serde::to_vec_named(&totals)?;
+            if tree
+                .compare_and_swap(LEDGER_TOTALS_KEY, current, Some(value))?
+                .is_ok()
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Settled invoices and msat received, over the whole ledger
+    pub fn ledger_totals(&self) -> Result<LedgerTotals> {
+        match self.ledger_totals_tree()?.get(LEDGER_TOTALS_KEY)? {
+            Some(value) => Ok(rmp_serde::from_slice(&value)?),
+            None => Ok(LedgerTotals::default()),
+        }
+    }
+
+    /// Counts the totals of ledgers written before they were kept,
+    /// undecodable invoices are skipped
+    fn init_ledger_totals(&self) -> Result<()> {
+        let tree = self.ledger_totals_tree()?;
+        if tree.contains_key(LEDGER_TOTALS_KEY)? {
+            return Ok(());
+        }
+        let mut totals = LedgerTotals::default();
+        for r in self.invoices()?.iter() {
+            let (payment_hash, value) = r?;
+            match rmp_serde::from_slice::<InvoiceRecord>(&value) {
+                Ok(invoice) if invoice.status == InvoiceStatus::Settled => {
+                    totals.settled += 1;
+                    totals.msat_received += invoice.msat_received.unwrap_or_default();
+                }
+                Ok(_) => {}
+                Err(e) => warn!(
+                    "Skipping invoice {}: {}",
+                    String::from_utf8_lossy(&payment_hash),
+                    e
+                ),
+            }
+        }
+        tree.insert(LEDGER_TOTALS_KEY, rmp_serde::to_vec_named(&totals)?)?;
+        Ok(())
+    }
+
+    /// Records an issued invoice in the ledger
+    pub fn insert_invoice(&self, invoice: &InvoiceRecord) -> Result<()> {
+        let value = rmp_serde::to_vec_named(invoice)?;
+        if invoice.status == InvoiceStatus::Pending {
+            self.index_invoice(invoice)?;
+        }
+        let previous = self.invoices()?.insert(&invoice.payment_hash, value)?;
+        if invoice.status != InvoiceStatus::Pending {
+            self.index_invoice(invoice)?;
+        }
+        let was_settled = previous
+            .and_then(|value| rmp_serde::from_slice::<InvoiceRecord>(&value).ok())
+            .filter(|previous| previous.status == InvoiceStatus::Settled)
+            .is_some();
+        self.count_settlement(was_settled, invoice)
+    }
+
+    /// Replaces the stored invoice with the one `update` derives from it,
+    /// atomically. Returns the new record or `None` if `update` declined
+    /// (or there's no such invoice).
+    fn swap_invoice(
+        &self,
+        payment_hash: &str,
+        update: impl Fn(InvoiceRecord) -> Option<InvoiceRecord>,
+    ) -> Result<Option<InvoiceRecord>> {
+        let tree = self.invoices()?;
+        loop {
+            let current = match tree.get(payment_hash)? {
+                Some(current) => current,
+                None => return Ok(None),
+            };
+            let stored: InvoiceRecord = rmp_serde::from_slice(&current)?;
+            let was_settled = stored.status == InvoiceStatus::Settled;
+            let invoice = match update(stored) {
+                Some(invoice) => invoice,
+                None => return Ok(None),
+            };
+            let value = rmp_serde::to_vec_named(&invoice)?;
+            if tree
+                .compare_and_swap(payment_hash, Some(current), Some(value))?
+                .is_ok()
+            {
+                self.index_invoice(&invoice)?;
+                self.count_settlement(was_settled, &invoice)?;
+                return Ok(Some(invoice));
+            }
+        }
+    }
+
+    /// Saves the new state of an invoice, but only if the ledger still
+    /// has it pending. Returns `false` if somebody else has already
+    /// moved it on (e.g. a concurrent check settled it).
+    pub fn update_pending_invoice(&self, invoice: &InvoiceRecord) -> Result<bool> {
+        let swapped = self.swap_invoice(&invoice.payment_hash, |stored| match stored.status {
+            InvoiceStatus::Pending => Some(invoice.clone()),
+            _ => None,
+        })?;
+        Ok(swapped.is_some())
+    }
+
+    /// Marks the pending invoice as being checked now, unless it was
+    /// checked after `since`. Returns the claimed invoice, `None` means
+    /// that there's no need to ask the backend.
+    pub fn claim_invoice_check(
+        &self,
+        payment_hash: &str,
+        since: SystemTime,
+    ) -> Result<Option<InvoiceRecord>> {
+        self.swap_invoice(payment_hash, |mut stored| {
+            let recent = matches!(stored.checked_at, Some(at) if at > since);
+            if stored.status != InvoiceStatus::Pending || recent {
+                return None;
+            }
+            stored.checked_at = Some(SystemTime::now());
+            Some(stored)
+        })
+    }
 
-        match ivec {
-            Some(ivec) => Ok(rmp_serde::from_slice(&ivec)?),
+    pub fn get_invoice(&self, payment_hash: &str) -> Result<Option<InvoiceRecord>> {
+        match self.invoices()?.get(payment_hash)? {
+            Some(ivec) => Ok(Some(rmp_serde::from_slice(&ivec)?)),
             None => Ok(None),
         }
     }
+
+    /// Returns invoices from the ledger (of the given address
+    /// if provided), the most recent ones first
+    pub fn list_invoices(&self, address: Option<&str>) -> Result<Vec<InvoiceRecord>> {
+        let mut invoices = vec![];
+        for r in self.invoices()?.iter() {
+            let invoice: InvoiceRecord = rmp_serde::from_slice(&r?.1)?;
+            match address {
+                Some(address) if address != invoice.address => continue,
+                _ => invoices.push(invoice),
+            }
+        }
+        invoices.sort_by_key(|invoice| Reverse(invoice.created_at));
+        Ok(invoices)
+    }
+
+    /// Returns the pending invoices, those expiring first come first
+    pub fn list_pending_invoices(&self) -> Result<Vec<InvoiceRecord>> {
+        let index = self.pending_invoices()?;
+        let mut invoices = vec![];
+        for r in index.iter() {
+            let (payment_hash, expiry) = r?;
+            let invoice = self
+                .invoices()?
+                .get(&payment_hash)?
+                .map(|ivec| rmp_serde::from_slice::<InvoiceRecord>(&ivec))
+                .transpose()?
+                .filter(|invoice| invoice.status == InvoiceStatus::Pending);
+            match invoice {
+                Some(invoice) => {
+                    invoices.push((u64::from_be_bytes(expiry.as_ref().try_into()?), invoice))
+                }
+                None => {
+                    index.remove(payment_hash)?;
+                }
+            }
+        }
+        invoices.sort_by_key(|(expiry, _)| *expiry);
+        Ok(invoices.into_iter().map(|(_, invoice)| invoice).collect())
+    }
+
+    /// Rebuilds the pending index from the whole ledger,
+    /// returns the number of pending invoices
+    pub fn reindex_pending_invoices(&self) -> Result<usize> {
+        let mut pending = 0;
+        for r in self.invoices()?.iter() {
+            let invoice: InvoiceRecord = rmp_serde::from_slice(&r?.1)?;
+            if invoice.status == InvoiceStatus::Pending {
+                self.index_invoice(&invoice)?;
+                pending += 1;
+            }
+        }
+        Ok(pending)
+    }
+
+    fn webhooks(&self) -> Result<sled::Tree> {
+        Ok(self.trees.open_tree(WEBHOOKS_TREE)?)
+    }
+
+    fn webhooks_due(&self) -> Result<sled::Tree> {
+        Ok(self.trees.open_tree(WEBHOOKS_DUE_TREE)?)
+    }
+
+    /// Key of the job in the index of due webhooks
+    fn due_key(job: &WebhookJob) -> Result<Vec<u8>> {
+        let at = job.next_attempt.duration_since(UNIX_EPOCH)?.as_secs();
+        Ok([at.to_be_bytes(), job.id.to_be_bytes()].concat())
+    }
+
+    /// Adds the job to the webhook queue, assigning it a new id
+    pub fn push_webhook(&self, job: &mut WebhookJob) -> Result<()> {
+        job.id = self.trees.generate_id()?;
+        self.save_webhook(job)
+    }
+
+    /// Stores the job (e.g. with updated attempts) in the queue
+    pub fn save_webhook(&self, job: &WebhookJob) -> Result<()> {
+        let value = rmp_serde::to_vec_named(job)?;
+        let due = self.webhooks_due()?;
+        due.insert(Self::due_key(job)?, &[])?;
+        if let Some(old) = self.webhooks()?.insert(job.id.to_be_bytes(), value)? {
+            let old: WebhookJob = rmp_serde::from_slice(&old)?;
+            if old.next_attempt != job.next_attempt {
+                due.remove(Self::due_key(&old)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn delete_webhook(&self, id: u64) -> Result<()> {
+        if let Some(old) = self.webhooks()?.remove(id.to_be_bytes())? {
+            let old: WebhookJob = rmp_serde::from_slice(&old)?;
+            self.webhooks_due()?.remove(Self::due_key(&old)?)?;
+        }
+        Ok(())
+    }
+
+    /// Returns queued webhook jobs, the oldest ones first
+    pub fn list_webhooks(&self) -> Result<Vec<WebhookJob>> {
+        self.webhooks()?
+            .iter()
+            .map(|r| Ok(rmp_serde::from_slice(&r?.1)?))
+            .collect()
+    }
+
+    /// Returns (up to `limit`) queued jobs due at `now`,
+    /// the longest waiting ones first
+    pub fn list_due_webhooks(&self, now: SystemTime, limit: usize) -> Result<Vec<WebhookJob>> {
+        let due = self.webhooks_due()?;
+        let upto = now.duration_since(UNIX_EPOCH)?.as_secs().saturating_add(1);
+        let mut jobs = vec![];
+        for r in due.range(..upto.to_be_bytes().to_vec()) {
+            if jobs.len() >= limit {
+                break;
+            }
+            let (key, _) = r?;
+            let job: Option<WebhookJob> = self
+                .webhooks()?
+                .get(&key[8..])?
+                .map(|ivec| rmp_serde::from_slice(&ivec))
+                .transpose()?;
+            match job {
+                Some(job) if Self::due_key(&job)? == key.as_ref() => {
+                    if job.next_attempt <= now {
+                        jobs.push(job);
+                    }
+                }
+                // left behind by a job that was rescheduled or delivered
+                _ => {
+                    due.remove(key)?;
+                }
+            }
+        }
+        Ok(jobs)
+    }
+
+    /// Rebuilds the index of due webhooks from the whole queue,
+    /// returns the number of queued jobs
+    pub fn reindex_webhooks(&self) -> Result<usize> {
+        let jobs = self.list_webhooks()?;
+        let due = self.webhooks_due()?;
+        for job in &jobs {
+            due.insert(Self::due_key(job)?, &[])?;
+        }
+        Ok(jobs.len())
+    }
+
+    fn zap_receipts(&self) -> Result<sled::Tree> {
+        Ok(self.trees.open_tree(ZAP_RECEIPTS_TREE)?)
+    }
+
+    /// Adds the job to the zap receipt queue, assigning it a new id
+    pub fn push_zap_receipt(&self, job: &mut ZapReceiptJob) -> Result<()> {
+        job.id = self.trees.generate_id()?;
+        self.save_zap_receipt(job)
+    }
+
+    /// Stores the job (e.g. with the relays left) in the queue
+    pub fn save_zap_receipt(&self, job: &ZapReceiptJob) -> Result<()> {
+        let value = rmp_serde::to_vec_named(job)?;
+        self.zap_receipts()?.insert(job.id.to_be_bytes(), value)?;
+        Ok(())
+    }
+
+    pub fn delete_zap_receipt(&self, id: u64) -> Result<()> {
+        self.zap_receipts()?.remove(id.to_be_bytes())?;
+        Ok(())
+    }
+
+    /// Returns queued zap receipts, the oldest ones first
+    pub fn list_zap_receipts(&self) -> Result<Vec<ZapReceiptJob>> {
+        self.zap_receipts()?
+            .iter()
+            .map(|r| Ok(rmp_serde::from_slice(&r?.1)?))
+            .collect()
+    }
+
+    /// Forgets nonces used before `timestamp`
+    pub fn prune_nonces(&self, timestamp: u64) -> Result<()> {
+        let tree = self.trees.open_tree(NONCES_TREE)?;
+        for r in tree.iter() {
+            let (key, value) = r?;
+            let used_at = u64::from_be_bytes(value.as_ref().try_into()?);
+            if used_at < timestamp {
+                tree.remove(key)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 pub mod defaults {
     pub static MIN_SENDABLE: u64 = 1_000;
     pub static MAX_SENDABLE: u64 = 1_000_000_000;
+    /// less than 3k msats might mean routing problems
+    pub static KEYSEND_MIN_SENDABLE: u64 = 3_000;
+    pub static COMMENT_LEN: u8 = 128;
 }
 
 pub mod models {
     use std::{cmp::Ordering, time::SystemTime};
 
     use serde::{Deserialize, Serialize};
-    use strum_macros::{self, Display, EnumIter};
+    use sha2::{Digest, Sha256};
+    use strum_macros::{self, Display, EnumIter, EnumString};
 
-    use validator::Validate;
+    use validator::{Validate, ValidationError, ValidationErrors};
 
     #[derive(Serialize, Deserialize, Debug, Clone, EnumIter, Display, PartialEq, Eq)]
     pub enum InvoiceAPI {
@@ -110,18 +672,54 @@ pub mod models {
                 InvoiceAPI::Keysend(_) => false,
             }
         }
+        /// Whether the backend accepts preimages chosen by us,
+        /// which LUD-10 (AES) success actions need
+        pub fn supports_preimage(&self) -> bool {
+            matches!(self, InvoiceAPI::Lnd(_))
+        }
+        /// Whether the backend can issue invoices for fractions of a sat,
+        /// LNbits takes whole sats only
+        pub fn supports_msat(&self) -> bool {
+            matches!(self, InvoiceAPI::Lnd(_))
+        }
+        /// Whether the backend can issue an invoice for that amount
+        pub fn accepts_amount(&self, msat: u64) -> bool {
+            let fraction_msat = msat % 1000;
+            fraction_msat == 0 || self.supports_msat()
+        }
+        /// Lowest payment (in msat) that gets through, whatever the limits
+        pub fn min_sendable(&self) -> u64 {
+            match self {
+                InvoiceAPI::Keysend(_) => super::defaults::KEYSEND_MIN_SENDABLE,
+                _ => 1,
+            }
+        }
+        /// Fingerprint of the node or wallet issuing the invoices, tells
+        /// which backend a ledger entry came from without keeping its
+        /// credentials there
+        pub fn identity(&self) -> String {
+            let id = match self {
+                InvoiceAPI::Lnd(p) => format!("lnd:{}", p.host),
+                InvoiceAPI::LNBits(p) => format!("lnbits:{}:{}", p.host, p.key),
+                InvoiceAPI::Keysend(p) => {
+                    format!("keysend:{}", p.wallet_id.as_deref().unwrap_or_default())
+                }
+            };
+            hex::encode(Sha256::digest(id))
+        }
+        /// Length of payer comments accepted (LUD-12). Comments are kept
+        /// in the invoice ledger so every backend supports them.
         pub fn get_comment_len(&self) -> u8 {
-            // lnbits invoice api implementation does not allow having
-            // both memo and unhashed_description, it prefers unhashed_desc if both are provided
-            // https://github.com/lnbits/lnbits/blob/1660b9dcf1f3c17af1b7d7a894f6ce06359ca578/lnbits/core/views/api.py#L153
-            // which leads to problems:
-            // - if we use memo client wallet complains that there's no desc_hash
-            // - if we use unhashed_desc, there's no memo visible on lnbits when recieving
-            // sending from LND works though as it does not expect unhashed_desc, ugh
+            super::defaults::COMMENT_LEN
+        }
+    }
+
+    impl Validate for InvoiceAPI {
+        fn validate(&self) -> Result<(), ValidationErrors> {
             match self {
-                InvoiceAPI::Lnd(_) => 128,
-                InvoiceAPI::LNBits(_) => 0,
-                InvoiceAPI::Keysend(_) => 128,
+                InvoiceAPI::Lnd(p) => p.validate(),
+                InvoiceAPI::LNBits(p) => p.validate(),
+                InvoiceAPI::Keysend(p) => p.validate(),
             }
         }
     }
@@ -203,47 +801,623 @@ pub mod models {
 
     impl Eq for Stats {}
 
-    #[derive(Debug, Deserialize, Serialize, Default, Clone, PartialEq, Eq)]
+    #[derive(Debug, Deserialize, Serialize, Validate, Default, Clone, PartialEq, Eq)]
     #[serde(rename_all = "camelCase")]
+    #[validate(schema(function = "validate_sendable"))]
+    #[validate(schema(function = "validate_params_success_action"))]
+    #[validate(schema(function = "validate_links"))]
     pub struct Params {
+        #[validate(length(min = 1), custom = "validate_name")]
         pub name: String,
+        #[validate(length(min = 1))]
         pub domain: String,
+        #[validate]
         pub invoice_api: InvoiceAPI,
         pub min_sendable: Option<u64>,
         pub max_sendable: Option<u64>,
 
+        /// legacy, plaintext PIN derived from the server secret
+        /// (empty once migrated to `pin_hash`)
+        #[serde(default)]
         pub pin: String,
+        /// salted hash (PHC string) of the random per-address PIN
+        #[serde(default)]
+        pub pin_hash: Option<String>,
         #[serde(default)]
         pub stats: Stats,
+        /// disabled entries are kept but don't serve payment requests
+        #[serde(default)]
+        pub disabled: bool,
+        /// url notified about invoices issued for the address
+        #[serde(default)]
+        #[validate]
+        pub webhook: Option<Webhook>,
+        /// details asked from payers (LUD-18)
+        #[serde(default)]
+        pub payer_data: Option<PayerDataRequest>,
+        /// shown by the payer's wallet once paid (LUD-09),
+        /// a generic message if not set
+        #[serde(default)]
+        pub success_action: Option<SuccessActionConfig>,
+        /// description and avatar shown to payers,
+        /// generic ones if not set
+        #[serde(default)]
+        #[validate]
+        pub metadata: Option<AddressMetadata>,
+        /// fixed-price pay links, served as `name+link@domain`
+        #[serde(default)]
+        #[validate]
+        pub links: Vec<PayLink>,
+        /// codes of the fiat currencies payers can send amounts in
+        #[serde(default)]
+        #[validate(custom = "validate_currencies")]
+        pub currencies: Vec<String>,
+    }
+
+    impl Params {
+        /// Lowest and highest accepted payment (in msat), the server-wide
+        /// `bounds` unless set, never below what the backend can handle
+        pub fn sendable(&self, bounds: (u64, u64)) -> (u64, u64) {
+            let min = self.min_sendable.unwrap_or(bounds.0);
+            (
+                min.max(self.invoice_api.min_sendable()),
+                self.max_sendable.unwrap_or(bounds.1),
+            )
+        }
+
+        /// Entry as served for the pay link: the address with the price,
+        /// description and (if set) success action of the link
+        pub fn with_link(&self, name: &str) -> Option<Params> {
+            let link = self.links.iter().find(|l| l.name == name)?;
+            let mut params = self.clone();
+            params.min_sendable = Some(link.msat);
+            params.max_sendable = Some(link.msat);
+            params.metadata = Some(AddressMetadata {
+                text: Some(link.description.clone()),
+                ..self.metadata.clone().unwrap_or_default()
+            });
+            if link.success_action.is_some() {
+                params.success_action = link.success_action.clone();
+            }
+            // the price is fixed in msat
+            params.currencies.clear();
+            Some(params)
+        }
+    }
+
+    /// Fixed-price pay link of the address, e.g. `alice+coffee@domain`
+    /// for printing a QR code of a specific item
+    #[derive(Debug, Deserialize, Serialize, Validate, Default, Clone, PartialEq, Eq)]
+    #[serde(rename_all = "camelCase")]
+    pub struct PayLink {
+        #[validate(length(min = 1, max = 32), custom = "validate_link_name")]
+        pub name: String,
+        /// price in msat, the only amount accepted
+        pub msat: u64,
+        #[validate(length(min = 1, max = 144))]
+        pub description: String,
+        /// shown once paid instead of the one of the address
+        #[serde(default)]
+        pub success_action: Option<SuccessActionConfig>,
+    }
+
+    /// `+` separates the name of the address from the name of its pay link
+    pub fn validate_name(name: &str) -> Result<(), ValidationError> {
+        if name.contains('+') {
+            return Err(ValidationError::new("name can not contain +"));
+        }
+        Ok(())
+    }
+
+    fn validate_link_name(name: &str) -> Result<(), ValidationError> {
+        if !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(ValidationError::new("invalid link name"));
+        }
+        Ok(())
+    }
+
+    /// Only supported currencies, each listed once
+    fn validate_currencies(codes: &[String]) -> Result<(), ValidationError> {
+        for (i, code) in codes.iter().enumerate() {
+            if crate::fiat::currency(code).is_none() {
+                return Err(ValidationError::new("unsupported currency"));
+            }
+            if codes[..i].contains(code) {
+                return Err(ValidationError::new("duplicate currency"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes sure that link names are unique and that the backend
+    /// can handle the prices and success actions of the links
+    fn validate_links(params: &Params) -> Result<(), ValidationError> {
+        let api = &params.invoice_api;
+        for (i, link) in params.links.iter().enumerate() {
+            if params.links[..i].iter().any(|l| l.name == link.name) {
+                return Err(ValidationError::new("duplicate link name"));
+            }
+            if link.msat < api.min_sendable() || !api.accepts_amount(link.msat) {
+                return Err(ValidationError::new(
+                    "link price not accepted by the backend",
+                ));
+            }
+            if let Some(ref action) = link.success_action {
+                validate_success_action(action, Some(api))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Format of the avatar image
+    #[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Display)]
+    #[serde(rename_all = "lowercase")]
+    #[strum(serialize_all = "lowercase")]
+    pub enum ImageFormat {
+        Png,
+        Jpeg,
+    }
+
+    /// Avatar image shown to payers
+    #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+    pub struct Image {
+        pub format: ImageFormat,
+        /// base64 encoded image
+        pub data: String,
+    }
+
+    /// Custom parts of the LNURL metadata of the address
+    #[derive(Debug, Deserialize, Serialize, Validate, Default, Clone, PartialEq, Eq)]
+    #[serde(rename_all = "camelCase")]
+    pub struct AddressMetadata {
+        /// short description (`text/plain`)
+        #[validate(length(min = 1, max = 144))]
+        #[serde(default)]
+        pub text: Option<String>,
+        /// `text/long-desc`
+        #[validate(length(min = 1, max = 1000))]
+        #[serde(default)]
+        pub long_desc: Option<String>,
+        #[validate(custom = "validate_image")]
+        #[serde(default)]
+        pub image: Option<Image>,
+    }
+
+    impl AddressMetadata {
+        /// Treats empty fields (e.g. left blank in the web form) as not set,
+        /// returns `None` if nothing is set
+        pub fn normalized(self) -> Option<Self> {
+            let non_empty = |s: Option<String>| s.filter(|s| !s.trim().is_empty());
+            let metadata = Self {
+                text: non_empty(self.text),
+                long_desc: non_empty(self.long_desc),
+                image: self.image.filter(|i| !i.data.is_empty()),
+            };
+            (metadata != Self::default()).then_some(metadata)
+        }
+    }
+
+    fn validate_image(image: &Image) -> Result<(), ValidationError> {
+        crate::avatar::check(image.format, &image.data).map_err(|e| {
+            let mut err = ValidationError::new("invalid image");
+            err.message = Some(e.into());
+            err
+        })
+    }
+
+    /// Longest description (or message) of a success action
+    const SUCCESS_ACTION_TEXT_LEN: usize = 144;
+    /// Longest AES secret, its base64 encoded ciphertext has to fit in 4kb
+    const SUCCESS_ACTION_SECRET_LEN: usize = 3000;
+
+    /// Action the payer's wallet performs once the invoice is paid
+    #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+    #[serde(tag = "tag", rename_all = "lowercase")]
+    pub enum SuccessActionConfig {
+        /// message shown to the payer
+        Message { message: String },
+        /// link, e.g. to a thank-you page or a download
+        Url { description: String, url: String },
+        /// secret revealed to the payer only, encrypted with
+        /// the payment preimage (LUD-10)
+        Aes { description: String, secret: String },
+    }
+
+    /// Validates success action content and that the backend
+    /// (if known) can handle it
+    pub fn validate_success_action(
+        action: &SuccessActionConfig,
+        api: Option<&InvoiceAPI>,
+    ) -> Result<(), ValidationError> {
+        let text = match action {
+            SuccessActionConfig::Message { message } => message,
+            SuccessActionConfig::Url { description, url } => {
+                match url::Url::parse(url) {
+                    Ok(url) if url.scheme() == "https" || url.scheme() == "http" => {}
+                    _ => return Err(ValidationError::new("invalid success action url")),
+                }
+                description
+            }
+            SuccessActionConfig::Aes {
+                description,
+                secret,
+            } => {
+                if secret.is_empty() || secret.len() > SUCCESS_ACTION_SECRET_LEN {
+                    return Err(ValidationError::new("invalid success action secret length"));
+                }
+                if !api.map(|api| api.supports_preimage()).unwrap_or(true) {
+                    return Err(ValidationError::new(
+                        "backend does not support encrypted success actions",
+                    ));
+                }
+                description
+            }
+        };
+        if text.is_empty() || text.chars().count() > SUCCESS_ACTION_TEXT_LEN {
+            return Err(ValidationError::new("invalid success action text length"));
+        }
+        Ok(())
+    }
+
+    fn validate_params_success_action(params: &Params) -> Result<(), ValidationError> {
+        match params.success_action {
+            Some(ref action) => validate_success_action(action, Some(&params.invoice_api)),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the payer detail is required or can be skipped
+    #[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct PayerField {
+        pub mandatory: bool,
+    }
+
+    /// Payer details the address asks for, serialized
+    /// as `payerData` of the pay request (LUD-18)
+    #[derive(Debug, Deserialize, Serialize, Default, Clone, PartialEq, Eq)]
+    pub struct PayerDataRequest {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub name: Option<PayerField>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub pubkey: Option<PayerField>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub identifier: Option<PayerField>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub email: Option<PayerField>,
+    }
+
+    impl PayerDataRequest {
+        /// Checks that the payer sent all the mandatory details
+        /// and nothing that wasn't asked for
+        pub fn check(&self, data: &PayerData) -> Result<(), String> {
+            let fields = [
+                ("name", self.name, data.name.is_some()),
+                ("pubkey", self.pubkey, data.pubkey.is_some()),
+                ("identifier", self.identifier, data.identifier.is_some()),
+                ("email", self.email, data.email.is_some()),
+            ];
+            for (field, requested, given) in fields {
+                match (requested, given) {
+                    (Some(PayerField { mandatory: true }), false) => {
+                        return Err(format!("payer {} is required", field))
+                    }
+                    (None, true) => return Err(format!("payer {} was not requested", field)),
+                    _ => {}
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Details of the payer sent along with the payment (LUD-18)
+    #[derive(Debug, Deserialize, Serialize, Validate, Default, Clone, PartialEq, Eq)]
+    pub struct PayerData {
+        #[validate(length(min = 1, max = 100))]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub name: Option<String>,
+        /// hex encoded, compressed secp256k1 public key
+        #[validate(custom = "validate_pubkey")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub pubkey: Option<String>,
+        /// internet identifier (`name@domain`), e.g. a lightning address
+        #[validate(custom = "validate_identifier")]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub identifier: Option<String>,
+        #[validate(email)]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub email: Option<String>,
+    }
+
+    impl PayerData {
+        /// Short description of the payer, the first detail known
+        pub fn label(&self) -> Option<&str> {
+            self.name
+                .as_deref()
+                .or(self.identifier.as_deref())
+                .or(self.email.as_deref())
+                .or(self.pubkey.as_deref())
+        }
+    }
+
+    fn validate_pubkey(pubkey: &str) -> Result<(), ValidationError> {
+        match hex::decode(pubkey) {
+            Ok(bytes) if bytes.len() == 33 && (bytes[0] == 2 || bytes[0] == 3) => Ok(()),
+            _ => Err(ValidationError::new("invalid public key")),
+        }
+    }
+
+    fn validate_identifier(identifier: &str) -> Result<(), ValidationError> {
+        match identifier.split_once('@') {
+            Some((name, domain)) if !name.is_empty() && domain.contains('.') => Ok(()),
+            _ => Err(ValidationError::new("invalid identifier")),
+        }
+    }
+
+    /// Url receiving notifications about the address invoices
+    #[derive(Debug, Deserialize, Serialize, Validate, Default, Clone, PartialEq, Eq)]
+    pub struct Webhook {
+        #[validate(url)]
+        pub url: String,
+        /// key used to sign the payloads (HMAC-SHA256)
+        #[serde(default)]
+        pub secret: String,
+    }
+
+    /// Webhook notification waiting in the queue for the delivery
+    #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+    #[serde(rename_all = "camelCase")]
+    pub struct WebhookJob {
+        pub id: u64,
+        /// `name@domain` the notification is about
+        pub address: String,
+        pub url: String,
+        pub secret: String,
+        /// JSON body, signed and sent as is
+        pub payload: String,
+        pub attempts: u32,
+        pub next_attempt: SystemTime,
+    }
+
+    /// Zap receipt (NIP-57) waiting in the queue for the publication
+    #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ZapReceiptJob {
+        pub id: u64,
+        /// `name@domain` that was zapped
+        pub address: String,
+        /// signed JSON event, published as is
+        pub event: String,
+        /// relays that didn't accept the receipt yet
+        pub relays: Vec<String>,
+        pub attempts: u32,
+        pub next_attempt: SystemTime,
+    }
+
+    /// Permissions that can be granted to admin API tokens
+    #[derive(
+        Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, EnumIter,
+    )]
+    #[strum(serialize_all = "lowercase")]
+    pub enum Scope {
+        /// read-only access to usage statistics
+        Stats,
+        /// management of the address entries
+        Users,
+        /// read-only access to the ledger of issued invoices
+        Invoices,
+    }
+
+    /// Named admin API token, only the hash of its secret is stored
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct ApiToken {
+        pub name: String,
+        pub hash: String,
+        /// key signing the requests, encrypted with the server secret,
+        /// `None` for tokens which can't sign requests
+        #[serde(default)]
+        pub signing_key: Option<String>,
+        pub scopes: Vec<Scope>,
+        pub created_at: SystemTime,
+        pub expires_at: Option<SystemTime>,
+    }
+
+    impl ApiToken {
+        pub fn is_expired(&self) -> bool {
+            matches!(self.expires_at, Some(t) if t <= SystemTime::now())
+        }
+    }
+
+    /// Entry of the ledger of issued invoices, keyed by the payment hash
+    #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+    #[serde(rename_all = "camelCase")]
+    pub struct InvoiceRecord {
+        /// hex encoded payment hash
+        pub payment_hash: String,
+        pub payment_request: String,
+        /// `name@domain` the invoice was issued for
+        pub address: String,
+        pub msat: u64,
+        pub comment: Option<String>,
+        /// backend that issued the invoice (`InvoiceAPI` variant)
+        pub backend: String,
+        /// identity of that backend (`InvoiceAPI::identity`), unknown
+        /// for invoices issued before it was recorded
+        #[serde(default)]
+        pub backend_id: Option<String>,
+        /// last time the backend was asked about the invoice
+        #[serde(default)]
+        pub checked_at: Option<SystemTime>,
+        pub created_at: SystemTime,
+        pub expires_at: SystemTime,
+        #[serde(default)]
+        pub status: InvoiceStatus,
+        /// amount actually received, known once settled
+        #[serde(default)]
+        pub msat_received: Option<u64>,
+        #[serde(default)]
+        pub settled_at: Option<SystemTime>,
+        /// hex encoded preimage, proof of the payment
+        #[serde(default)]
+        pub preimage: Option<String>,
+        /// details the payer shared (LUD-18)
+        #[serde(default)]
+        pub payer_data: Option<PayerData>,
+        /// JSON zap request (NIP-57) the invoice was issued for
+        #[serde(default)]
+        pub zap_request: Option<String>,
+    }
+
+    /// Running totals of the invoice ledger
+    #[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct LedgerTotals {
+        /// invoices settled
+        pub settled: u64,
+        /// msat received by the settled invoices
+        pub msat_received: u64,
+    }
+
+    /// Payment state of an issued invoice
+    #[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Display)]
+    #[serde(rename_all = "lowercase")]
+    #[strum(serialize_all = "lowercase")]
+    pub enum InvoiceStatus {
+        #[default]
+        Pending,
+        Settled,
+        Expired,
+    }
+
+    /// Makes sure that sendable limits (if set) are not inverted
+    fn validate_sendable(params: &Params) -> Result<(), ValidationError> {
+        let defaults = (super::defaults::MIN_SENDABLE, super::defaults::MAX_SENDABLE);
+        let (min, max) = params.sendable(defaults);
+        if min > max {
+            return Err(ValidationError::new(
+                "min_sendable greater than max_sendable",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the limits set by the owner against the server-wide
+    /// `bounds` and against what the backend can handle
+    pub fn validate_sendable_bounds(
+        params: &Params,
+        bounds: (u64, u64),
+    ) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        let api = &params.invoice_api;
+        for (field, limit) in [
+            ("minSendable", params.min_sendable),
+            ("maxSendable", params.max_sendable),
+        ] {
+            let limit = match limit {
+                Some(limit) => limit,
+                None => continue,
+            };
+            let code = if limit < bounds.0 || limit > bounds.1 {
+                "outside of the server bounds"
+            } else if limit < api.min_sendable() {
+                "below the minimum of the backend"
+            } else if !api.accepts_amount(limit) {
+                "not a whole number of sats"
+            } else {
+                continue;
+            };
+            let mut error = ValidationError::new(code);
+            error.add_param("min".into(), &bounds.0.max(api.min_sendable()));
+            error.add_param("max".into(), &bounds.1);
+            errors.add(field, error);
+        }
+        if params
+            .links
+            .iter()
+            .any(|l| l.msat < bounds.0 || l.msat > bounds.1)
+        {
+            let mut error = ValidationError::new("link price outside of the server bounds");
+            error.add_param("min".into(), &bounds.0);
+            error.add_param("max".into(), &bounds.1);
+            errors.add("links", error);
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
     }
 }
 
 #[cfg(test)]
 pub mod helpers {
-    use std::env;
+    use std::{
+        env,
+        time::{Duration, SystemTime},
+    };
 
     use rand::{distributions::Alphanumeric, thread_rng, Rng};
 
-    use super::Db;
+    use super::{
+        models::{InvoiceRecord, InvoiceStatus},
+        Db,
+    };
 
-    pub fn tmp_db() -> Db {
-        let rnd_string: String = thread_rng()
+    /// Random name for temporary files
+    pub fn tmp_name() -> String {
+        thread_rng()
             .sample_iter(&Alphanumeric)
             .take(30)
             .map(char::from)
-            .collect();
-        let tmp_path = env::temp_dir().join(rnd_string);
+            .collect()
+    }
+
+    pub fn tmp_db() -> Db {
+        let tmp_path = env::temp_dir().join(tmp_name());
         Db::from_path(tmp_path.to_str().unwrap()).unwrap()
     }
+
+    /// Pending invoice of `user@domain.com` issued now, expiring in
+    /// an hour. Tests override the fields they care about.
+    pub fn invoice(payment_hash: &str) -> InvoiceRecord {
+        let now = SystemTime::now();
+        InvoiceRecord {
+            payment_hash: payment_hash.to_string(),
+            payment_request: "lnbc1".to_string(),
+            address: "user@domain.com".to_string(),
+            msat: 1000,
+            comment: None,
+            backend: "Lnd".to_string(),
+            backend_id: None,
+            checked_at: None,
+            created_at: now,
+            expires_at: now + Duration::from_secs(3600),
+            status: InvoiceStatus::Pending,
+            msat_received: None,
+            settled_at: None,
+            preimage: None,
+            payer_data: None,
+            zap_request: None,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::time::SystemTime;
 
-    use super::{helpers, models::LNDParams};
+    use envconfig::Envconfig;
+    use validator::Validate;
 
-    use super::models::{Counter, InvoiceAPI, LNBitsParams, Params};
+    use crate::DbConfig;
+
+    use super::{helpers, models::LNDParams, Db};
+
+    use super::models::{
+        validate_sendable_bounds, validate_success_action, Counter, InvoiceAPI, InvoiceRecord,
+        InvoiceStatus, KeysendParams, LNBitsParams, LedgerTotals, Params, PayLink,
+        SuccessActionConfig,
+    };
 
     #[test]
     fn counter_increments() {
@@ -274,9 +1448,151 @@ mod tests {
     }
 
     #[test]
-    fn invoice_api_lnbits_no_comments_support() {
+    fn invoice_api_lnbits_comments_support() {
         let iapi = InvoiceAPI::LNBits(LNBitsParams::default());
-        assert_eq!(iapi.get_comment_len(), 0)
+        assert_ne!(iapi.get_comment_len(), 0)
+    }
+
+    #[test]
+    fn success_actions_are_validated() {
+        let lnd = InvoiceAPI::Lnd(LNDParams::default());
+        let lnbits = InvoiceAPI::LNBits(LNBitsParams::default());
+        let message = SuccessActionConfig::Message {
+            message: "Thanks!".to_string(),
+        };
+        assert!(validate_success_action(&message, Some(&lnbits)).is_ok());
+        let too_long = SuccessActionConfig::Message {
+            message: "x".repeat(145),
+        };
+        assert!(validate_success_action(&too_long, None).is_err());
+
+        let url = SuccessActionConfig::Url {
+            description: "Download".to_string(),
+            url: "https://example.com/file".to_string(),
+        };
+        assert!(validate_success_action(&url, None).is_ok());
+        let bad_url = SuccessActionConfig::Url {
+            description: "Download".to_string(),
+            url: "ftp:/nope".to_string(),
+        };
+        assert!(validate_success_action(&bad_url, None).is_err());
+
+        // secrets are encrypted with the preimage, only LND accepts ours
+        let aes = SuccessActionConfig::Aes {
+            description: "Code".to_string(),
+            secret: "1234".to_string(),
+        };
+        assert!(validate_success_action(&aes, Some(&lnd)).is_ok());
+        assert!(validate_success_action(&aes, Some(&lnbits)).is_err());
+    }
+
+    #[test]
+    fn sendable_limits_are_bounded() {
+        let bounds = (1_000, 100_000);
+        let limits = |api: InvoiceAPI, min: Option<u64>, max: Option<u64>| Params {
+            invoice_api: api,
+            min_sendable: min,
+            max_sendable: max,
+            ..Default::default()
+        };
+        let lnd = || InvoiceAPI::Lnd(LNDParams::default());
+        let lnbits = || InvoiceAPI::LNBits(LNBitsParams::default());
+        let keysend = || InvoiceAPI::Keysend(KeysendParams::default());
+
+        let params = limits(lnd(), None, None);
+        assert!(validate_sendable_bounds(&params, bounds).is_ok());
+        assert_eq!(params.sendable(bounds), bounds);
+        let params = limits(lnd(), Some(1_500), Some(100_000));
+        assert!(validate_sendable_bounds(&params, bounds).is_ok());
+        assert_eq!(params.sendable(bounds), (1_500, 100_000));
+
+        let params = limits(lnd(), Some(500), None);
+        assert!(validate_sendable_bounds(&params, bounds).is_err());
+        let params = limits(lnd(), None, Some(200_000));
+        let errors = validate_sendable_bounds(&params, bounds).unwrap_err();
+        assert!(errors.field_errors().contains_key("maxSendable"));
+
+        // LNbits takes whole sats only
+        let params = limits(lnbits(), Some(1_500), None);
+        assert!(validate_sendable_bounds(&params, bounds).is_err());
+
+        // keysend payments below the fee floor would get stuck
+        let params = limits(keysend(), Some(2_000), None);
+        assert!(validate_sendable_bounds(&params, bounds).is_err());
+        let params = limits(keysend(), None, None);
+        assert_eq!(params.sendable(bounds), (3_000, 100_000));
+    }
+
+    #[test]
+    fn pay_links_are_validated() {
+        let link = |name: &str, msat: u64| PayLink {
+            name: name.to_string(),
+            msat,
+            description: "Coffee".to_string(),
+            success_action: None,
+        };
+        let mut params = Params {
+            name: "alice".to_string(),
+            domain: "domain.com".to_string(),
+            invoice_api: InvoiceAPI::LNBits(LNBitsParams {
+                host: "https://lnbits.example.com".to_string(),
+                key: "key".to_string(),
+            }),
+            links: vec![link("coffee", 5_000), link("cake-2", 12_000)],
+            ..Default::default()
+        };
+        assert!(params.validate().is_ok());
+
+        let served = params.with_link("cake-2").unwrap();
+        assert_eq!(served.sendable((1, u64::MAX)), (12_000, 12_000));
+        assert_eq!(served.metadata.unwrap().text.unwrap(), "Coffee");
+        assert!(params.with_link("tea").is_none());
+
+        params.links.push(link("coffee", 6_000));
+        assert!(params.validate().is_err());
+        params.links.pop();
+        params.links.push(link("tea time", 6_000));
+        assert!(params.validate().is_err());
+        params.links.pop();
+        // LNbits takes whole sats only
+        params.links.push(link("tea", 6_500));
+        assert!(params.validate().is_err());
+        params.links.pop();
+
+        let errors = validate_sendable_bounds(&params, (1_000, 10_000)).unwrap_err();
+        assert!(errors.field_errors().contains_key("links"));
+
+        params.name = "alice+bob".to_string();
+        assert!(params.validate().is_err());
+    }
+
+    #[test]
+    fn currencies_are_validated() {
+        let mut params = Params {
+            name: "alice".to_string(),
+            domain: "domain.com".to_string(),
+            invoice_api: InvoiceAPI::LNBits(LNBitsParams {
+                host: "https://lnbits.example.com".to_string(),
+                key: "key".to_string(),
+            }),
+            currencies: vec!["EUR".to_string(), "USD".to_string()],
+            links: vec![PayLink {
+                name: "coffee".to_string(),
+                msat: 5_000,
+                description: "Coffee".to_string(),
+                success_action: None,
+            }],
+            ..Default::default()
+        };
+        assert!(params.validate().is_ok());
+        // links keep their msat price
+        assert!(params.with_link("coffee").unwrap().currencies.is_empty());
+
+        params.currencies.push("EUR".to_string());
+        assert!(params.validate().is_err());
+        params.currencies.pop();
+        params.currencies.push("eur".to_string());
+        assert!(params.validate().is_err());
     }
 
     #[test]
@@ -304,5 +1620,115 @@ mod tests {
         db.update(&params).unwrap();
         let ret_params = db.get(&name, &domain).unwrap().unwrap();
         assert_eq!("321", ret_params.pin);
+
+        let deleted = db.delete(&name, &domain).unwrap().unwrap();
+        assert_eq!(deleted, ret_params);
+        assert!(db.get(&name, &domain).unwrap().is_none());
+        assert!(db.delete(&name, &domain).unwrap().is_none());
+    }
+
+    #[test]
+    fn ledger_totals_are_kept() {
+        let db = helpers::tmp_db();
+        db.insert_invoice(&helpers::invoice("aa")).unwrap();
+        let settled = InvoiceRecord {
+            status: InvoiceStatus::Settled,
+            msat_received: Some(9_000),
+            ..helpers::invoice("aa")
+        };
+        assert!(db.update_pending_invoice(&settled).unwrap());
+        assert!(!db.update_pending_invoice(&settled).unwrap());
+        let expected = LedgerTotals {
+            settled: 1,
+            msat_received: 9_000,
+        };
+        assert_eq!(db.ledger_totals().unwrap(), expected);
+
+        // recorded as settled right away, once
+        let other = InvoiceRecord {
+            status: InvoiceStatus::Settled,
+            msat_received: Some(1_000),
+            ..helpers::invoice("bb")
+        };
+        db.insert_invoice(&other).unwrap();
+        db.insert_invoice(&other).unwrap();
+        let expected = LedgerTotals {
+            settled: 2,
+            msat_received: 10_000,
+        };
+        assert_eq!(db.ledger_totals().unwrap(), expected);
+
+        // ledgers written before the totals were kept
+        db.ledger_totals_tree().unwrap().clear().unwrap();
+        db.invoices().unwrap().insert("cc", "garbage").unwrap();
+        db.init_ledger_totals().unwrap();
+        assert_eq!(db.ledger_totals().unwrap(), expected);
+    }
+
+    #[test]
+    fn records_are_replaced_only_if_unchanged() {
+        let db = helpers::tmp_db();
+        let read = Params {
+            name: "alice".to_string(),
+            domain: "one.com".to_string(),
+            ..Default::default()
+        };
+        db.create(&read).unwrap();
+        let edit = Params {
+            min_sendable: Some(5_000),
+            ..read.clone()
+        };
+
+        // bumped counters don't get in the way, nor get reverted
+        db.modify("alice", "one.com", |p| {
+            let mut p = p.clone();
+            p.stats.calls.inc();
+            Some(p)
+        })
+        .unwrap();
+        assert!(db.replace(&read, &edit).unwrap());
+        let stored = db.get("alice", "one.com").unwrap().unwrap();
+        assert_eq!(stored.min_sendable, Some(5_000));
+        assert_eq!(stored.stats.calls.num, 1);
+
+        // edited in the meantime
+        let concurrent = Params {
+            max_sendable: Some(9_000),
+            ..edit.clone()
+        };
+        assert!(!db.replace(&read, &concurrent).unwrap());
+        assert_eq!(db.get("alice", "one.com").unwrap().unwrap(), stored);
+
+        // the name can't be changed
+        assert!(db
+            .modify("alice", "one.com", |p| Some(Params {
+                name: "bob".to_string(),
+                ..p.clone()
+            }))
+            .unwrap()
+            .is_some());
+        assert!(db.get("bob", "one.com").unwrap().is_none());
+    }
+
+    #[test]
+    fn db_opens_with_tuned_config() {
+        let path = std::env::temp_dir().join(helpers::tmp_name());
+        let mut config = DbConfig::init_from_hashmap(&Default::default()).unwrap();
+        config.path = path.to_string_lossy().into_owned();
+        config.cache_capacity = 1024 * 1024;
+        config.flush_every_ms = 0;
+        config.compression = true;
+
+        let params = Params {
+            name: "user".to_string(),
+            domain: "domain.com".to_string(),
+            ..Default::default()
+        };
+        {
+            let db = Db::open(&config).unwrap();
+            db.insert(&params.name, &params.domain, &params).unwrap();
+        }
+        let db = Db::open(&config).unwrap();
+        assert_eq!(db.get("user", "domain.com").unwrap(), Some(params));
     }
 }
@@ -0,0 +1,208 @@
+//! Versioned envelope for `Params` records and migrations between versions.
+//!
+//! Records are stored as a marker byte, followed by a big endian `u16`
+//! version and the msgpack encoded payload. Records without the marker
+//! were written before versioning was introduced and are treated as
+//! version `0`.
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use super::models::{Params, Stats};
+
+/// Marker of versioned records. `0xc1` is never used by msgpack
+/// so it can't be confused with the first byte of a legacy record.
+const MARKER: u8 = 0xc1;
+
+/// Version of records written by this build
+pub const CURRENT_VERSION: u16 = 8;
+
+/// Version introducing the field, its name and producer of the default
+type Field = (u16, &'static str, fn() -> Value);
+
+/// Fields introduced by each version as `(version, field, default)`,
+/// upgrading a record to `version` adds `field` set to `default`
+/// unless it's already present. A new version only needs a new line.
+static FIELDS: &[Field] = &[
+    // v1 introduces the envelope, makes sure that fields which
+    // legacy records could lack are explicitly present.
+    (1, "pinHash", || Value::Null),
+    (1, "stats", || json!(Stats::default())),
+    (2, "disabled", || json!(false)),
+    (3, "webhook", || Value::Null),
+    (4, "payerData", || Value::Null),
+    (5, "successAction", || Value::Null),
+    (6, "metadata", || Value::Null),
+    (7, "links", || json!([])),
+    (8, "currencies", || json!([])),
+];
+
+/// Adds the field to the record unless it's already there
+fn add_field(record: &mut Value, name: &str, default: Value) -> Result<()> {
+    match record.as_object_mut() {
+        Some(obj) => {
+            obj.entry(name).or_insert(default);
+            Ok(())
+        }
+        None => bail!("record is not a map"),
+    }
+}
+
+/// Encodes params into the current version of the envelope
+pub fn encode(params: &Params) -> Result<Vec<u8>> {
+    let mut bytes = vec![MARKER];
+    bytes.extend_from_slice(&CURRENT_VERSION.to_be_bytes());
+    bytes.extend(rmp_serde::to_vec_named(params)?);
+    Ok(bytes)
+}
+
+/// Splits stored bytes into version and payload
+fn unwrap(bytes: &[u8]) -> Result<(u16, &[u8])> {
+    match bytes {
+        [MARKER, hi, lo, payload @ ..] => Ok((u16::from_be_bytes([*hi, *lo]), payload)),
+        [MARKER, ..] => bail!("truncated record envelope"),
+        payload => Ok((0, payload)),
+    }
+}
+
+/// Returns the schema version of the stored record
+pub fn version(bytes: &[u8]) -> Result<u16> {
+    Ok(unwrap(bytes)?.0)
+}
+
+/// Decodes a stored record of any known version,
+/// migrating it (in memory) if needed.
+pub fn decode(bytes: &[u8]) -> Result<Params> {
+    let (version, payload) = unwrap(bytes)?;
+    if version == CURRENT_VERSION {
+        return Ok(rmp_serde::from_slice(payload)?);
+    }
+    if version > CURRENT_VERSION {
+        bail!(
+            "record version {} is newer than supported {}",
+            version,
+            CURRENT_VERSION
+        );
+    }
+
+    let mut record: Value = rmp_serde::from_slice(payload)?;
+    for (_, field, default) in FIELDS.iter().filter(|(since, ..)| *since > version) {
+        add_field(&mut record, field, default())?;
+    }
+    Ok(serde_json::from_value(record)?)
+}
+
+/// Re-encodes the record with the current version, returns
+/// `None` if the record is already up to date.
+pub fn upgrade(bytes: &[u8]) -> Result<Option<Vec<u8>>> {
+    if version(bytes)? == CURRENT_VERSION {
+        return Ok(None);
+    }
+    Ok(Some(encode(&decode(bytes)?)?))
+}
+
+/// Summary of a migration run
+#[derive(Debug, Default, Serialize)]
+pub struct MigrationReport {
+    pub dry_run: bool,
+    pub total: usize,
+    pub up_to_date: usize,
+    /// number of migrated records per their original version
+    pub migrated: BTreeMap<u16, usize>,
+    /// records that could not be migrated along with the reason
+    pub failed: Vec<(String, String)>,
+}
+
+impl MigrationReport {
+    pub fn migrated_total(&self) -> usize {
+        self.migrated.values().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{
+        models::Params,
+        store::{SledStore, Store},
+    };
+
+    use super::{decode, encode, upgrade, version, CURRENT_VERSION, FIELDS, MARKER};
+
+    fn params() -> Params {
+        Params {
+            name: "user".to_string(),
+            domain: "domain.com".to_string(),
+            pin: "legacy-pin".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn envelope_round_trip() {
+        let bytes = encode(&params()).unwrap();
+        assert_eq!(bytes[0], MARKER);
+        assert_eq!(version(&bytes).unwrap(), CURRENT_VERSION);
+        assert_eq!(decode(&bytes).unwrap(), params());
+        assert!(upgrade(&bytes).unwrap().is_none());
+    }
+
+    #[test]
+    fn legacy_records_are_migrated() {
+        // legacy records were stored as plain msgpack, without `pinHash`
+        let mut legacy: serde_json::Value = serde_json::to_value(params()).unwrap();
+        for (_, field, _) in FIELDS {
+            legacy.as_object_mut().unwrap().remove(*field);
+        }
+        let bytes = rmp_serde::to_vec_named(&legacy).unwrap();
+
+        assert_eq!(version(&bytes).unwrap(), 0);
+        assert_eq!(decode(&bytes).unwrap(), params());
+
+        let upgraded = upgrade(&bytes).unwrap().unwrap();
+        assert_eq!(version(&upgraded).unwrap(), CURRENT_VERSION);
+        assert_eq!(decode(&upgraded).unwrap(), params());
+    }
+
+    #[test]
+    fn fields_cover_every_version() {
+        for version in 1..=CURRENT_VERSION {
+            assert!(FIELDS.iter().any(|(since, ..)| *since == version));
+        }
+        assert!(FIELDS.windows(2).all(|w| w[0].0 <= w[1].0));
+        assert!(FIELDS.iter().all(|(since, ..)| *since <= CURRENT_VERSION));
+    }
+
+    #[test]
+    fn newer_records_are_rejected() {
+        let mut bytes = encode(&params()).unwrap();
+        bytes[1..3].copy_from_slice(&(CURRENT_VERSION + 1).to_be_bytes());
+        assert!(decode(&bytes).is_err());
+        assert!(decode(&[MARKER]).is_err());
+    }
+
+    #[test]
+    fn db_migration_dry_run_does_not_write() {
+        let tree = sled::Config::new().temporary(true).open().unwrap();
+        let db = SledStore::new(tree.clone());
+        let legacy = rmp_serde::to_vec_named(&params()).unwrap();
+        tree.insert("user@domain.com", legacy).unwrap();
+        tree.insert("broken@domain.com", vec![MARKER, 0, 99, 1])
+            .unwrap();
+
+        let report = db.migrate(true).unwrap();
+        assert_eq!(report.total, 2);
+        assert_eq!(report.migrated.get(&0), Some(&1));
+        assert_eq!(report.failed.len(), 1);
+        let stored = tree.get("user@domain.com").unwrap().unwrap();
+        assert_eq!(version(&stored).unwrap(), 0);
+
+        let report = db.migrate(false).unwrap();
+        assert_eq!(report.migrated_total(), 1);
+        let stored = tree.get("user@domain.com").unwrap().unwrap();
+        assert_eq!(version(&stored).unwrap(), CURRENT_VERSION);
+        assert_eq!(db.migrate(true).unwrap().migrated_total(), 0);
+    }
+}
@@ -0,0 +1,531 @@
+//! Storage backends for the address records (`Params`).
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    sync::RwLock,
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use super::{
+    models::Params,
+    schema::{self, MigrationReport},
+};
+
+/// Operations every storage backend of address records needs to provide
+pub trait Store: Send + Sync {
+    /// Returns the record of `name@domain`
+    fn get(&self, name: &str, domain: &str) -> Result<Option<Params>>;
+    /// Inserts or replaces the record, returns `true` if it got replaced
+    fn insert(&self, params: &Params) -> Result<bool>;
+    /// Inserts the record unless it already exists (atomically),
+    /// returns `false` if it does
+    fn create(&self, params: &Params) -> Result<bool>;
+    /// Replaces existing record, fails if it does not exist
+    fn update(&self, params: &Params) -> Result<()>;
+    /// Replaces the record with what `f` returns for it (nothing is written
+    /// for `None`), atomically: no write happens between the read and the
+    /// replacement. Returns the record as stored after, `None` if it doesn't exist
+    fn modify(
+        &self,
+        name: &str,
+        domain: &str,
+        f: &mut dyn FnMut(&Params) -> Option<Params>,
+    ) -> Result<Option<Params>>;
+    /// Removes the record, returns it if it existed
+    fn delete(&self, name: &str, domain: &str) -> Result<Option<Params>>;
+    /// Returns all the records
+    fn list(&self) -> Result<Vec<Params>>;
+    /// Returns all the records of the given domain
+    fn scan_domain(&self, domain: &str) -> Result<Vec<Params>>;
+    /// Removes all the records
+    fn clear(&self) -> Result<()>;
+
+    /// Upgrades stored records to the current schema version. Backends
+    /// which don't persist encoded records have nothing to migrate.
+    fn migrate(&self, dry_run: bool) -> Result<MigrationReport> {
+        let total = self.list()?.len();
+        Ok(MigrationReport {
+            dry_run,
+            total,
+            up_to_date: total,
+            ..Default::default()
+        })
+    }
+}
+
+fn key(name: &str, domain: &str) -> String {
+    format!("{}@{}", name, domain)
+}
+
+/// Default backend, keeps records in the default tree of a `sled` database
+pub struct SledStore(sled::Db);
+
+impl SledStore {
+    pub fn new(db: sled::Db) -> Self {
+        Self(db)
+    }
+}
+
+impl Store for SledStore {
+    fn get(&self, name: &str, domain: &str) -> Result<Option<Params>> {
+        match self.0.get(key(name, domain))? {
+            Some(ivec) => Ok(Some(schema::decode(&ivec)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&self, params: &Params) -> Result<bool> {
+        let value = schema::encode(params)?;
+        let previous = self.0.insert(key(&params.name, &params.domain), value)?;
+        Ok(previous.is_some())
+    }
+
+    fn create(&self, params: &Params) -> Result<bool> {
+        let value = schema::encode(params)?;
+        let swap = self.0.compare_and_swap(
+            key(&params.name, &params.domain),
+            None as Option<&[u8]>,
+            Some(value),
+        )?;
+        Ok(swap.is_ok())
+    }
+
+    fn update(&self, params: &Params) -> Result<()> {
+        let key = key(&params.name, &params.domain);
+        if !self.0.contains_key(&key)? {
+            bail!("Key does not exist: {}", key);
+        }
+        self.0.insert(key, schema::encode(params)?)?;
+        Ok(())
+    }
+
+    fn modify(
+        &self,
+        name: &str,
+        domain: &str,
+        f: &mut dyn FnMut(&Params) -> Option<Params>,
+    ) -> Result<Option<Params>> {
+        let key = key(name, domain);
+        loop {
+            let value = match self.0.get(&key)? {
+                Some(value) => value,
+                None => return Ok(None),
+            };
+            let params = schema::decode(&value)?;
+            let modified = match f(&params) {
+                Some(modified) => modified,
+                None => return Ok(Some(params)),
+            };
+            // written in the meantime, try again with the new record
+            let swap =
+                self.0
+                    .compare_and_swap(&key, Some(value), Some(schema::encode(&modified)?))?;
+            if swap.is_ok() {
+                return Ok(Some(modified));
+            }
+        }
+    }
+
+    fn delete(&self, name: &str, domain: &str) -> Result<Option<Params>> {
+        match self.0.remove(key(name, domain))? {
+            Some(ivec) => Ok(Some(schema::decode(&ivec)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<Params>> {
+        self.0.iter().map(|r| schema::decode(&r?.1)).collect()
+    }
+
+    fn scan_domain(&self, domain: &str) -> Result<Vec<Params>> {
+        let suffix = format!("@{}", domain);
+        self.0
+            .iter()
+            .filter(|r| match r {
+                Ok((key, _)) => key.ends_with(suffix.as_bytes()),
+                Err(_) => true,
+            })
+            .map(|r| schema::decode(&r?.1))
+            .collect()
+    }
+
+    fn clear(&self) -> Result<()> {
+        Ok(self.0.clear()?)
+    }
+
+    fn migrate(&self, dry_run: bool) -> Result<MigrationReport> {
+        let mut report = MigrationReport {
+            dry_run,
+            ..Default::default()
+        };
+        for r in self.0.iter() {
+            let (key, value) = r?;
+            report.total += 1;
+
+            let version = schema::version(&value).unwrap_or_default();
+            match schema::upgrade(&value) {
+                Ok(None) => report.up_to_date += 1,
+                Ok(Some(upgraded)) => {
+                    if !dry_run {
+                        // don't overwrite records modified in the meantime
+                        self.0
+                            .compare_and_swap(&key, Some(value), Some(upgraded))?
+                            .ok();
+                    }
+                    *report.migrated.entry(version).or_default() += 1;
+                }
+                Err(e) => report
+                    .failed
+                    .push((String::from_utf8_lossy(&key).to_string(), e.to_string())),
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Keeps records in memory only, useful for tests
+#[derive(Default)]
+pub struct MemoryStore(RwLock<BTreeMap<String, Params>>);
+
+impl MemoryStore {
+    fn read(&self) -> Result<std::sync::RwLockReadGuard<'_, BTreeMap<String, Params>>> {
+        self.0.read().map_err(|e| anyhow!("poisoned store: {}", e))
+    }
+
+    fn write(&self) -> Result<std::sync::RwLockWriteGuard<'_, BTreeMap<String, Params>>> {
+        self.0.write().map_err(|e| anyhow!("poisoned store: {}", e))
+    }
+}
+
+impl Store for MemoryStore {
+    fn get(&self, name: &str, domain: &str) -> Result<Option<Params>> {
+        Ok(self.read()?.get(&key(name, domain)).cloned())
+    }
+
+    fn insert(&self, params: &Params) -> Result<bool> {
+        let key = key(&params.name, &params.domain);
+        Ok(self.write()?.insert(key, params.clone()).is_some())
+    }
+
+    fn create(&self, params: &Params) -> Result<bool> {
+        let key = key(&params.name, &params.domain);
+        match self.write()?.entry(key) {
+            Entry::Occupied(_) => Ok(false),
+            Entry::Vacant(entry) => {
+                entry.insert(params.clone());
+                Ok(true)
+            }
+        }
+    }
+
+    fn update(&self, params: &Params) -> Result<()> {
+        let key = key(&params.name, &params.domain);
+        match self.write()?.get_mut(&key) {
+            Some(entry) => {
+                *entry = params.clone();
+                Ok(())
+            }
+            None => bail!("Key does not exist: {}", key),
+        }
+    }
+
+    fn modify(
+        &self,
+        name: &str,
+        domain: &str,
+        f: &mut dyn FnMut(&Params) -> Option<Params>,
+    ) -> Result<Option<Params>> {
+        let mut records = self.write()?;
+        let entry = match records.get_mut(&key(name, domain)) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        if let Some(modified) = f(entry) {
+            *entry = modified;
+        }
+        Ok(Some(entry.clone()))
+    }
+
+    fn delete(&self, name: &str, domain: &str) -> Result<Option<Params>> {
+        Ok(self.write()?.remove(&key(name, domain)))
+    }
+
+    fn list(&self) -> Result<Vec<Params>> {
+        Ok(self.read()?.values().cloned().collect())
+    }
+
+    fn scan_domain(&self, domain: &str) -> Result<Vec<Params>> {
+        Ok(self
+            .read()?
+            .values()
+            .filter(|p| p.domain == domain)
+            .cloned()
+            .collect())
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.write()?.clear();
+        Ok(())
+    }
+}
+
+/// Keeps address records in a SQLite database, the rest of
+/// the data (see `Db`) stays in `sled`.
+#[cfg(feature = "sqlite")]
+pub struct SqliteStore(std::sync::Mutex<rusqlite::Connection>);
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<Self> {
+        let conn = rusqlite::Connection::open(path)?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS addresses (
+                name TEXT NOT NULL,
+                domain TEXT NOT NULL,
+                record BLOB NOT NULL,
+                PRIMARY KEY (name, domain)
+            );",
+        )?;
+        Ok(Self(std::sync::Mutex::new(conn)))
+    }
+
+    fn conn(&self) -> Result<std::sync::MutexGuard<'_, rusqlite::Connection>> {
+        self.0.lock().map_err(|e| anyhow!("poisoned store: {}", e))
+    }
+
+    fn query(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<Params>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(params, |row| row.get::<_, Vec<u8>>(0))?;
+        rows.map(|r| schema::decode(&r?)).collect()
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Store for SqliteStore {
+    fn get(&self, name: &str, domain: &str) -> Result<Option<Params>> {
+        Ok(self
+            .query(
+                "SELECT record FROM addresses WHERE name = ?1 AND domain = ?2",
+                [name, domain],
+            )?
+            .pop())
+    }
+
+    fn insert(&self, params: &Params) -> Result<bool> {
+        let conn = self.conn()?;
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM addresses WHERE name = ?1 AND domain = ?2)",
+            [&params.name, &params.domain],
+            |row| row.get(0),
+        )?;
+        conn.execute(
+            "INSERT OR REPLACE INTO addresses (name, domain, record) VALUES (?1, ?2, ?3)",
+            rusqlite::params![params.name, params.domain, schema::encode(params)?],
+        )?;
+        Ok(exists)
+    }
+
+    fn create(&self, params: &Params) -> Result<bool> {
+        let inserted = self.conn()?.execute(
+            "INSERT OR IGNORE INTO addresses (name, domain, record) VALUES (?1, ?2, ?3)",
+            rusqlite::params![params.name, params.domain, schema::encode(params)?],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    fn update(&self, params: &Params) -> Result<()> {
+        let changed = self.conn()?.execute(
+            "UPDATE addresses SET record = ?3 WHERE name = ?1 AND domain = ?2",
+            rusqlite::params![params.name, params.domain, schema::encode(params)?],
+        )?;
+        if changed == 0 {
+            bail!("Key does not exist: {}", key(&params.name, &params.domain));
+        }
+        Ok(())
+    }
+
+    fn modify(
+        &self,
+        name: &str,
+        domain: &str,
+        f: &mut dyn FnMut(&Params) -> Option<Params>,
+    ) -> Result<Option<Params>> {
+        // the connection lock keeps other writes out until it's replaced
+        let conn = self.conn()?;
+        let value: Option<Vec<u8>> = conn
+            .prepare("SELECT record FROM addresses WHERE name = ?1 AND domain = ?2")?
+            .query_map([name, domain], |row| row.get(0))?
+            .next()
+            .transpose()?;
+        let params = match value {
+            Some(value) => schema::decode(&value)?,
+            None => return Ok(None),
+        };
+        let modified = match f(&params) {
+            Some(modified) => modified,
+            None => return Ok(Some(params)),
+        };
+        conn.execute(
+            "UPDATE addresses SET record = ?3 WHERE name = ?1 AND domain = ?2",
+            rusqlite::params![name, domain, schema::encode(&modified)?],
+        )?;
+        Ok(Some(modified))
+    }
+
+    fn delete(&self, name: &str, domain: &str) -> Result<Option<Params>> {
+        let previous = self.get(name, domain)?;
+        self.conn()?.execute(
+            "DELETE FROM addresses WHERE name = ?1 AND domain = ?2",
+            [name, domain],
+        )?;
+        Ok(previous)
+    }
+
+    fn list(&self) -> Result<Vec<Params>> {
+        self.query("SELECT record FROM addresses ORDER BY name, domain", [])
+    }
+
+    fn scan_domain(&self, domain: &str) -> Result<Vec<Params>> {
+        self.query(
+            "SELECT record FROM addresses WHERE domain = ?1 ORDER BY name",
+            [domain],
+        )
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.conn()?.execute("DELETE FROM addresses", [])?;
+        Ok(())
+    }
+
+    fn migrate(&self, dry_run: bool) -> Result<MigrationReport> {
+        let mut report = MigrationReport {
+            dry_run,
+            ..Default::default()
+        };
+        let conn = self.conn()?;
+        let rows: Vec<(String, String, Vec<u8>)> = conn
+            .prepare("SELECT name, domain, record FROM addresses")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<_, _>>()?;
+
+        for (name, domain, value) in rows {
+            report.total += 1;
+            let version = schema::version(&value).unwrap_or_default();
+            match schema::upgrade(&value) {
+                Ok(None) => report.up_to_date += 1,
+                Ok(Some(upgraded)) => {
+                    if !dry_run {
+                        // don't overwrite records modified in the meantime
+                        conn.execute(
+                            "UPDATE addresses SET record = ?3
+                            WHERE name = ?1 AND domain = ?2 AND record = ?4",
+                            rusqlite::params![name, domain, upgraded, value],
+                        )?;
+                    }
+                    *report.migrated.entry(version).or_default() += 1;
+                }
+                Err(e) => report.failed.push((key(&name, &domain), e.to_string())),
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryStore, SledStore, Store};
+    use crate::db::models::Params;
+
+    fn params(name: &str, domain: &str) -> Params {
+        Params {
+            name: name.to_string(),
+            domain: domain.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Same expectations are checked for every backend
+    fn check_store(store: &dyn Store) {
+        let mut alice = params("alice", "one.com");
+        assert!(!store.insert(&alice).unwrap());
+        assert!(!store.insert(&params("bob", "one.com")).unwrap());
+        assert!(!store.insert(&params("alice", "two.com")).unwrap());
+        assert!(store.insert(&params("alice", "two.com")).unwrap());
+        assert_eq!(store.get("alice", "one.com").unwrap().unwrap(), alice);
+        // never replaces existing records
+        let mut other = alice.clone();
+        other.min_sendable = Some(5_000);
+        assert!(!store.create(&other).unwrap());
+        assert_eq!(store.get("alice", "one.com").unwrap().unwrap(), alice);
+        assert!(store.create(&params("dave", "one.com")).unwrap());
+        store.delete("dave", "one.com").unwrap();
+        assert!(store.get("carol", "one.com").unwrap().is_none());
+
+        alice.max_sendable = Some(42_000);
+        store.update(&alice).unwrap();
+        assert_eq!(
+            store.get("alice", "one.com").unwrap().unwrap().max_sendable,
+            Some(42_000)
+        );
+        assert!(store.update(&params("carol", "one.com")).is_err());
+
+        let modified = store
+            .modify("alice", "one.com", &mut |p| {
+                Some(Params {
+                    min_sendable: Some(7_000),
+                    ..p.clone()
+                })
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(modified.min_sendable, Some(7_000));
+        assert_eq!(modified.max_sendable, Some(42_000));
+        alice = store.get("alice", "one.com").unwrap().unwrap();
+        assert_eq!(alice, modified);
+        // nothing written when `None` is returned
+        let kept = store.modify("alice", "one.com", &mut |_| None).unwrap();
+        assert_eq!(kept.unwrap(), alice);
+        assert!(store
+            .modify("carol", "one.com", &mut |p| Some(p.clone()))
+            .unwrap()
+            .is_none());
+
+        assert_eq!(store.list().unwrap().len(), 3);
+        let mut names: Vec<String> = store
+            .scan_domain("one.com")
+            .unwrap()
+            .into_iter()
+            .map(|p| p.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["alice", "bob"]);
+
+        assert_eq!(store.delete("alice", "one.com").unwrap().unwrap(), alice);
+        assert!(store.delete("alice", "one.com").unwrap().is_none());
+        assert_eq!(store.migrate(true).unwrap().total, 2);
+
+        store.clear().unwrap();
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn memory_store_works() {
+        check_store(&MemoryStore::default());
+    }
+
+    #[test]
+    fn sled_store_works() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        check_store(&SledStore::new(db));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_works() {
+        check_store(&super::SqliteStore::open(":memory:").unwrap());
+    }
+}
@@ -0,0 +1,530 @@
+//! Fiat pricing of addresses. Pay requests of addresses accepting fiat
+//! currencies advertise them (`currencies`) along with the current rate,
+//! callbacks then take amounts like `1050.EUR` (in cents) and convert
+//! them to msat. Rates come from a configurable provider and are cached
+//! for a while.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use hyper_tls::HttpsConnector;
+use log::*;
+use serde_json::Value;
+use tokio::time::timeout;
+use warp::hyper::{self, Body, Client, Request};
+
+use crate::ln::PayCurrency;
+
+/// msat in a single bitcoin
+const MSAT_PER_BTC: f64 = 100_000_000_000.0;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Providers that failed aren't asked again for that long
+const RETRY_AFTER: Duration = Duration::from_secs(30);
+/// Public CoinGecko API, used unless another url is configured
+const COINGECKO_URL: &str = "https://api.coingecko.com";
+
+/// Fiat currency addresses can accept
+#[derive(Debug, PartialEq, Eq)]
+pub struct Currency {
+    pub code: &'static str,
+    pub name: &'static str,
+    pub symbol: &'static str,
+    /// digits of the smallest unit (e.g. cents), amounts are sent in those
+    pub decimals: u8,
+}
+
+pub static CURRENCIES: &[Currency] = &[
+    Currency {
+        code: "EUR",
+        name: "Euro",
+        symbol: "€",
+        decimals: 2,
+    },
+    Currency {
+        code: "USD",
+        name: "US Dollar",
+        symbol: "$",
+        decimals: 2,
+    },
+    Currency {
+        code: "GBP",
+        name: "Pound Sterling",
+        symbol: "£",
+        decimals: 2,
+    },
+    Currency {
+        code: "CHF",
+        name: "Swiss Franc",
+        symbol: "CHF",
+        decimals: 2,
+    },
+    Currency {
+        code: "CAD",
+        name: "Canadian Dollar",
+        symbol: "CA$",
+        decimals: 2,
+    },
+    Currency {
+        code: "JPY",
+        name: "Japanese Yen",
+        symbol: "¥",
+        decimals: 0,
+    },
+];
+
+/// Looks up a supported currency by its (ISO 4217) code
+pub fn currency(code: &str) -> Option<&'static Currency> {
+    CURRENCIES.iter().find(|c| c.code == code)
+}
+
+impl Currency {
+    /// msat worth a single smallest unit of the currency,
+    /// `rate` being the price of a bitcoin
+    pub fn multiplier(&self, rate: f64) -> f64 {
+        MSAT_PER_BTC / rate / 10f64.powi(self.decimals.into())
+    }
+
+    /// Converts the amount (in the smallest units) to msat
+    pub fn to_msat(&self, rate: f64, amount: u64) -> u64 {
+        (amount as f64 * self.multiplier(rate)).round() as u64
+    }
+}
+
+/// Prices of a bitcoin by currency code
+pub type Rates = HashMap<String, f64>;
+
+/// Provider of the exchange rates, configured as one of
+/// - `static:EUR=25000,USD=27000` fixed prices of a bitcoin
+/// - `file:<path>` JSON object of the prices (e.g. `{"EUR": 25000}`),
+///   read again once the cached rates expire
+/// - `coingecko` or `coingecko:<api url>` prices from the CoinGecko API
+#[derive(Debug, Clone, PartialEq)]
+pub enum RateSource {
+    Static(Rates),
+    File(PathBuf),
+    CoinGecko(String),
+}
+
+impl FromStr for RateSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, arg) = match s.trim().split_once(':') {
+            Some((kind, arg)) => (kind, Some(arg.trim())),
+            None => (s.trim(), None),
+        };
+        match (kind, arg) {
+            ("static", Some(prices)) => {
+                let mut rates = Rates::new();
+                for pair in prices
+                    .split(',')
+                    .map(|p| p.trim())
+                    .filter(|p| !p.is_empty())
+                {
+                    let (code, price) = pair
+                        .split_once('=')
+                        .ok_or_else(|| format!("expected CODE=price, got {}", pair))?;
+                    let price = price
+                        .trim()
+                        .parse::<f64>()
+                        .map_err(|_| format!("invalid price of {}", code))?;
+                    rates.insert(code.trim().to_uppercase(), price);
+                }
+                check_rates(rates).map(RateSource::Static)
+            }
+            ("file", Some(path)) if !path.is_empty() => Ok(RateSource::File(path.into())),
+            ("coingecko", None) => Ok(RateSource::CoinGecko(COINGECKO_URL.to_owned())),
+            ("coingecko", Some(url)) => {
+                Ok(RateSource::CoinGecko(url.trim_end_matches('/').to_owned()))
+            }
+            _ => Err(format!("unknown rate source {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for RateSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateSource::Static(_) => write!(f, "static"),
+            RateSource::File(path) => write!(f, "file:{}", path.display()),
+            RateSource::CoinGecko(url) => write!(f, "coingecko:{}", url),
+        }
+    }
+}
+
+/// Keeps the supported currencies, all of them need a positive price
+fn check_rates(rates: Rates) -> Result<Rates, String> {
+    let mut checked = Rates::new();
+    for (code, price) in rates {
+        if currency(&code).is_none() {
+            continue;
+        }
+        if !price.is_finite() || price <= 0.0 {
+            return Err(format!("invalid price of {}", code));
+        }
+        checked.insert(code, price);
+    }
+    Ok(checked)
+}
+
+impl RateSource {
+    async fn fetch(&self) -> Result<Rates> {
+        let rates = match self {
+            RateSource::Static(rates) => return Ok(rates.clone()),
+            RateSource::File(path) => {
+                let text = tokio::fs::read_to_string(path).await?;
+                serde_json::from_str::<Rates>(&text)?
+                    .into_iter()
+                    .map(|(code, price)| (code.to_uppercase(), price))
+                    .collect()
+            }
+            RateSource::CoinGecko(url) => coingecko(url).await?,
+        };
+        check_rates(rates).map_err(|e| anyhow!(e))
+    }
+}
+
+/// Asks the CoinGecko API about the prices in all supported currencies
+async fn coingecko(url: &str) -> Result<Rates> {
+    let codes: Vec<String> = CURRENCIES.iter().map(|c| c.code.to_lowercase()).collect();
+    let req = Request::get(format!(
+        "{}/api/v3/simple/price?ids=bitcoin&vs_currencies={}",
+        url,
+        codes.join(",")
+    ))
+    .body(Body::empty())?;
+    let client = Client::builder().build::<_, hyper::Body>(HttpsConnector::new());
+    let resp = match timeout(REQUEST_TIMEOUT, client.request(req)).await {
+        Ok(r) => r?,
+        Err(_) => bail!("request timed out"),
+    };
+    if !resp.status().is_success() {
+        bail!("unexpected response status {}", resp.status());
+    }
+    let body: Value = serde_json::from_slice(&hyper::body::to_bytes(resp.into_body()).await?)?;
+
+    let mut rates = Rates::new();
+    if let Some(prices) = body["bitcoin"].as_object() {
+        for (code, price) in prices {
+            if let Some(price) = price.as_f64() {
+                rates.insert(code.to_uppercase(), price);
+            }
+        }
+    }
+    Ok(rates)
+}
+
+/// Last rates fetched from a provider and the last failure, if any
+#[derive(Default)]
+struct Cached {
+    rates: Option<(Rates, Instant)>,
+    failed_at: Option<Instant>,
+    /// held while fetching, so that concurrent requests missing
+    /// the cache wait for a single fetch instead of starting their own
+    refresh: Arc<tokio::sync::Mutex<()>>,
+}
+
+type RateCache = HashMap<String, Cached>;
+
+/// Rates fetched from the providers (by their `Display` form)
+static RATES: Mutex<Option<RateCache>> = Mutex::new(None);
+
+/// Runs `f` on the cache entry of the source
+fn with_cached<T>(key: &str, f: impl FnOnce(&mut Cached) -> T) -> T {
+    let mut cache = RATES.lock().unwrap();
+    f(cache
+        .get_or_insert_with(HashMap::new)
+        .entry(key.to_owned())
+        .or_default())
+}
+
+/// Cached rates of the source younger than `max_age`
+fn cached(key: &str, max_age: Duration) -> Option<Rates> {
+    let cache = RATES.lock().unwrap();
+    match cache.as_ref()?.get(key)?.rates {
+        Some((ref rates, at)) if at.elapsed() < max_age => Some(rates.clone()),
+        _ => None,
+    }
+}
+
+/// Whether fetching the rates of the source failed less than `backoff` ago
+fn failed_recently(key: &str, backoff: Duration) -> bool {
+    let cache = RATES.lock().unwrap();
+    match cache.as_ref().and_then(|cache| cache.get(key)?.failed_at) {
+        Some(at) => at.elapsed() < backoff,
+        None => false,
+    }
+}
+
+/// Current rates of the source, fetched again once the cached ones
+/// are older than `ttl`. If the provider fails, the cached rates
+/// are still served until they're twice as old, and the provider isn't
+/// asked again for `RETRY_AFTER` (or `ttl` if shorter). Requests that can
+/// be served stale rates don't wait for a refresh in progress either.
+pub async fn rates(source: &RateSource, ttl: Duration) -> Result<Rates> {
+    if let RateSource::Static(rates) = source {
+        return Ok(rates.clone());
+    }
+    let key = source.to_string();
+    let backoff = RETRY_AFTER.min(ttl);
+    let stale_ttl = ttl.saturating_mul(2);
+    if let Some(rates) = cached(&key, ttl) {
+        return Ok(rates);
+    }
+    let stale = cached(&key, stale_ttl);
+    if failed_recently(&key, backoff) {
+        return stale.ok_or_else(|| anyhow!("{} rates are unavailable", key));
+    }
+
+    let refresh = with_cached(&key, |cached| cached.refresh.clone());
+    let _refresh = match (refresh.clone().try_lock_owned(), stale) {
+        (Ok(refresh), _) => refresh,
+        // being refreshed by another request
+        (Err(_), Some(stale)) => return Ok(stale),
+        (Err(_), None) => refresh.lock_owned().await,
+    };
+    // fetched (or failed) by another request while this one was waiting
+    if let Some(rates) = cached(&key, ttl) {
+        return Ok(rates);
+    }
+    if failed_recently(&key, backoff) {
+        return cached(&key, stale_ttl).ok_or_else(|| anyhow!("{} rates are unavailable", key));
+    }
+    match source.fetch().await {
+        Ok(rates) => {
+            with_cached(&key, |cached| {
+                cached.rates = Some((rates.clone(), Instant::now()));
+                cached.failed_at = None;
+            });
+            Ok(rates)
+        }
+        Err(e) => {
+            with_cached(&key, |cached| cached.failed_at = Some(Instant::now()));
+            match cached(&key, stale_ttl) {
+                Some(rates) => {
+                    warn!("Serving stale {} rates: {}", key, e);
+                    Ok(rates)
+                }
+                None => Err(e),
+            }
+        }
+    }
+}
+
+/// Currencies (out of `codes`) advertised in the pay request,
+/// those without a rate are left out
+pub fn pay_currencies(codes: &[String], rates: &Rates) -> Vec<PayCurrency> {
+    codes
+        .iter()
+        .filter_map(|code| {
+            let currency = currency(code)?;
+            let rate = rates.get(code)?;
+            Some(PayCurrency {
+                code: currency.code.to_owned(),
+                name: currency.name.to_owned(),
+                symbol: currency.symbol.to_owned(),
+                decimals: currency.decimals,
+                multiplier: currency.multiplier(*rate),
+                convertible: true,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        time::{Duration, Instant},
+    };
+
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::db::helpers::tmp_name;
+
+    use super::{currency, pay_currencies, rates, RateSource, Rates};
+
+    #[test]
+    fn rate_sources_are_parsed() {
+        let source: RateSource = "static:EUR=25000, usd=30000.5,XYZ=1".parse().unwrap();
+        assert_eq!(
+            source,
+            RateSource::Static(Rates::from([
+                ("EUR".to_owned(), 25_000.0),
+                ("USD".to_owned(), 30_000.5)
+            ]))
+        );
+        assert_eq!(
+            "file:/tmp/rates.json".parse::<RateSource>().unwrap(),
+            RateSource::File("/tmp/rates.json".into())
+        );
+        assert_eq!(
+            "coingecko".parse::<RateSource>().unwrap(),
+            RateSource::CoinGecko("https://api.coingecko.com".to_owned())
+        );
+        assert_eq!(
+            "coingecko:http://127.0.0.1:8000/"
+                .parse::<RateSource>()
+                .unwrap(),
+            RateSource::CoinGecko("http://127.0.0.1:8000".to_owned())
+        );
+
+        assert!("static:EUR=0".parse::<RateSource>().is_err());
+        assert!("static:EUR".parse::<RateSource>().is_err());
+        assert!("file:".parse::<RateSource>().is_err());
+        assert!("kraken".parse::<RateSource>().is_err());
+    }
+
+    #[test]
+    fn amounts_are_converted_to_msat() {
+        let eur = currency("EUR").unwrap();
+        assert_eq!(eur.multiplier(25_000.0), 40_000.0);
+        assert_eq!(eur.to_msat(25_000.0, 1050), 42_000_000);
+        // rounded to the closest msat
+        assert_eq!(eur.to_msat(30_000.0, 1), 33_333);
+
+        let jpy = currency("JPY").unwrap();
+        assert_eq!(jpy.to_msat(4_000_000.0, 1000), 25_000_000);
+        assert!(currency("XYZ").is_none());
+
+        let rates = Rates::from([("EUR".to_owned(), 25_000.0)]);
+        let currencies = pay_currencies(&["USD".to_owned(), "EUR".to_owned()], &rates);
+        assert_eq!(currencies.len(), 1);
+        assert_eq!(currencies[0].code, "EUR");
+        assert_eq!(currencies[0].decimals, 2);
+        assert_eq!(currencies[0].multiplier, 40_000.0);
+    }
+
+    #[tokio::test]
+    async fn rates_are_cached() {
+        let file = env::temp_dir().join(tmp_name());
+        std::fs::write(&file, r#"{"eur": 25000, "USD": 30000}"#).unwrap();
+        let source = RateSource::File(file.clone());
+        let ttl = Duration::from_secs(60);
+
+        let first = rates(&source, ttl).await.unwrap();
+        assert_eq!(first["EUR"], 25_000.0);
+        assert_eq!(first["USD"], 30_000.0);
+
+        std::fs::write(&file, r#"{"EUR": 20000}"#).unwrap();
+        assert_eq!(rates(&source, ttl).await.unwrap(), first);
+        let fresh = rates(&source, Duration::ZERO).await.unwrap();
+        assert_eq!(fresh, Rates::from([("EUR".to_owned(), 20_000.0)]));
+
+        std::fs::write(&file, r#"{"EUR": -1}"#).unwrap();
+        assert!(rates(&source, Duration::ZERO).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn coingecko_rates_are_fetched() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v3/simple/price"))
+            .and(query_param("ids", "bitcoin"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(
+                serde_json::json!({"bitcoin": {"eur": 25000, "usd": 30000.5, "jpy": 4000000}}),
+            ))
+            .mount(&mock_server)
+            .await;
+
+        let source = RateSource::CoinGecko(mock_server.uri());
+        let rates = rates(&source, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(rates.len(), 3);
+        assert_eq!(rates["USD"], 30_000.5);
+        assert_eq!(rates["JPY"], 4_000_000.0);
+    }
+
+    #[tokio::test]
+    async fn stale_rates_are_served_for_a_while() {
+        let file = env::temp_dir().join(tmp_name());
+        std::fs::write(&file, r#"{"EUR": 25000}"#).unwrap();
+        let source = RateSource::File(file.clone());
+        let ttl = Duration::from_millis(300);
+        let cached = rates(&source, ttl).await.unwrap();
+
+        // provider fails once the rates expired
+        std::fs::remove_file(&file).unwrap();
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        assert_eq!(rates(&source, ttl).await.unwrap(), cached);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(rates(&source, ttl).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_providers_are_not_retried_right_away() {
+        let mock_server = MockServer::start().await;
+        let prefix = tmp_name();
+        let price_path = format!("/{}/api/v3/simple/price", prefix);
+        Mock::given(method("GET"))
+            .and(path(price_path.as_str()))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({"bitcoin": {"eur": 25000}})),
+            )
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        // then it's down, answering slowly
+        Mock::given(method("GET"))
+            .and(path(price_path.as_str()))
+            .respond_with(ResponseTemplate::new(500).set_delay(Duration::from_millis(300)))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let source = RateSource::CoinGecko(format!("{}/{}", mock_server.uri(), prefix));
+        let ttl = Duration::from_secs(1);
+        let cached = rates(&source, ttl).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1050)).await;
+
+        // served stale rates without waiting for the refresh
+        let (refreshed, waited) = tokio::join!(rates(&source, ttl), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let started = Instant::now();
+            assert_eq!(rates(&source, ttl).await.unwrap(), cached);
+            started.elapsed()
+        });
+        assert_eq!(refreshed.unwrap(), cached);
+        assert!(waited < Duration::from_millis(100));
+        // the provider isn't asked again for a while
+        assert_eq!(rates(&source, ttl).await.unwrap(), cached);
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_fetch_once() {
+        let mock_server = MockServer::start().await;
+        // pooled mock servers get reused, a fresh prefix keeps
+        // the rates cached by other tests out of the way
+        let prefix = tmp_name();
+        Mock::given(method("GET"))
+            .and(path(format!("/{}/api/v3/simple/price", prefix)))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({"bitcoin": {"eur": 25000}}))
+                    .set_delay(Duration::from_millis(100)),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let source = RateSource::CoinGecko(format!("{}/{}", mock_server.uri(), prefix));
+        let ttl = Duration::from_secs(60);
+        let (a, b, c) = tokio::join!(
+            rates(&source, ttl),
+            rates(&source, ttl),
+            rates(&source, ttl)
+        );
+        assert_eq!(a.unwrap()["EUR"], 25_000.0);
+        assert_eq!(b.unwrap(), c.unwrap());
+    }
+}
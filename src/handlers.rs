@@ -1,20 +1,27 @@
 use crate::{
+    api::{release_keysend, remove_entry},
     db::{
-        defaults,
-        models::{InvoiceAPI, Params},
+        models::{
+            validate_name, validate_sendable_bounds, validate_success_action, AddressMetadata,
+            InvoiceAPI, InvoiceStatus, Params, PayLink, PayerData, PayerDataRequest, PayerField,
+            SuccessActionConfig, Webhook,
+        },
         Db,
     },
-    keysend,
+    fiat, keysend,
     ln::{
         invoice::{make_invoice, Metadata},
-        LNURLPayParams, LNURLPayValues, LNURLResponse, SuccessAction,
+        LNURLPayParams, LNURLPayValues, LNURLResponse, LNURLVerify, SuccessAction,
     },
+    lnurl, nostr,
+    pin::{reset_pin_async, verify_pin_async},
+    settlement,
+    webhook::{self, Event},
 };
 
 use log::*;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::json;
-use sha2::{Digest, Sha256};
 use thiserror::Error;
 
 use validator::{Validate, ValidateArgs, ValidationError, ValidationErrors, ValidationErrorsKind};
@@ -25,7 +32,12 @@ use warp::{
 };
 
 use super::Config;
-use std::{collections::HashMap, convert::Infallible, error::Error as StdError};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    error::Error as StdError,
+    time::{Duration, SystemTime},
+};
 use strum::IntoEnumIterator;
 
 use percent_encoding::percent_decode_str;
@@ -83,59 +95,138 @@ pub async fn lnurl(
 
     debug!("LN URL request data {}@{} {:?}", username, domain, query,);
 
-    let mut params = db
-        .get(&username, &domain)
+    let (name, link) = split_link(&username);
+    let owner = db
+        .get(name, &domain)
         .map_err(|_| warp::reject())?
         .ok_or_else(warp::reject)?;
 
+    if owner.disabled {
+        return Err(reject::custom(LnUrlError(format!(
+            "{}@{} is disabled",
+            name, domain
+        ))));
+    }
+    // pay links are served with their own price and description
+    let params = match link {
+        Some(link) => owner.with_link(link).ok_or_else(warp::reject)?,
+        None => owner,
+    };
+
+    // served to the payer and committed to by the invoice, has to be
+    // the very same string in both steps of the flow
+    let metadata = Metadata::from(params.clone())
+        .with_name(&username)
+        .with_email_identifier(config.is_email_identifier_domain(&domain))
+        .to_string();
+
     match query.get("amount") {
-        Some(msat) => {
-            let msat = msat.parse::<u64>().map_err(|_| warp::reject())?;
+        Some(amount) => {
+            let msat = parse_amount(&params, &config, amount)
+                .await
+                .map_err(|e| reject::custom(LnUrlError(e)))?;
 
             let memo = match query.get("comment") {
                 Some(s) if !s.is_empty() => Some(s.to_owned()),
                 _ => None,
             };
+            check_callback(&params, &config, msat, memo.as_ref())
+                .map_err(|e| reject::custom(LnUrlError(e)))?;
             if let (Some(memo), InvoiceAPI::Keysend(params)) = (&memo, &params.invoice_api) {
                 // payment for keysend
                 // update the scrub so that it matches the comment
                 // in the request
-                keysend::update_entry(
-                    &config.lnbits,
-                    params.admin_key.as_ref().unwrap(),
-                    None,
-                    Some(memo),
-                )
-                .await
-                .map_err(|e| Error::Val(format!("Problem updating keysend data: {}", e)))?;
+                let admin_key = params.admin_key.as_ref().ok_or_else(|| {
+                    reject::custom(LnUrlError("keysend backend is not provisioned".to_string()))
+                })?;
+                keysend::update_entry(&config.lnbits, admin_key, None, Some(memo))
+                    .await
+                    .map_err(|e| Error::Val(format!("Problem updating keysend data: {}", e)))?;
             }
-            let bolt11 = make_invoice(
+
+            // payer details (LUD-18) are ignored unless requested,
+            // the invoice description commits to them
+            let raw_payer_data = query.get("payerdata");
+            let payer_data = match params.payer_data {
+                Some(ref request) => parse_payer_data(request, raw_payer_data)
+                    .map_err(|e| reject::custom(LnUrlError(e)))?,
+                None => None,
+            };
+            // zap requests (NIP-57) take the place of the metadata,
+            // the receipt published once paid carries them
+            let zap_request = match query.get("nostr") {
+                Some(raw) if config.nostr_key(&domain).is_some() => {
+                    nostr::check_zap_request(raw, msat)
+                        .map_err(|e| reject::custom(LnUrlError(e)))?;
+                    Some(raw.to_owned())
+                }
+                Some(_) => {
+                    return Err(reject::custom(LnUrlError(format!(
+                        "{} does not accept zaps",
+                        domain
+                    ))))
+                }
+                None => None,
+            };
+            let description = match (&zap_request, &payer_data, raw_payer_data) {
+                (Some(zap_request), _, _) => zap_request.clone(),
+                (None, Some(_), Some(raw)) => format!("{}{}", metadata, raw),
+                _ => metadata,
+            };
+
+            // encrypted (AES) success actions use the preimage as the key,
+            // so it has to be chosen before the invoice is created
+            let preimage: Option<[u8; 32]> = match params.success_action {
+                Some(SuccessActionConfig::Aes { .. }) => Some(rand::random()),
+                _ => None,
+            };
+            let success_action = match params.success_action {
+                Some(ref action) => SuccessAction::from_config(action, preimage.as_ref())
+                    .map_err(|e| reject::custom(LnUrlError(e.to_string())))?,
+                None => SuccessAction::message("Payment received!"),
+            };
+
+            let invoice = make_invoice(
                 &params,
                 &config.lnbits.url,
                 msat,
                 config.tor_proxy_url,
-                memo,
+                memo.clone(),
+                Some(description),
+                preimage,
             )
             .await
             .map_err(|e| reject::custom(LnUrlError(e.to_string())))?;
 
-            let success_action = SuccessAction {
-                tag: "message".to_string(),
-                message: Some("Payment received!".to_string()),
-                ..Default::default()
-            };
+            let mut record = invoice.to_record(&params, msat, memo);
+            record.payer_data = payer_data;
+            record.zap_request = zap_request;
+            if let Err(e) = db.insert_invoice(&record) {
+                error!("Unable to record invoice {}: {}", record.payment_hash, e);
+            }
+            if let Some(ref webhook) = params.webhook {
+                if let Err(e) = webhook::enqueue(&db, webhook, Event::Issued, &record) {
+                    error!("Unable to queue webhook for {}: {}", record.address, e);
+                }
+            }
 
-            params.stats.invoices.inc();
-            db.update(&params).map_err(|_| warp::reject())?;
+            // the invoice is issued already, whatever happens to the entry
+            if let Err(e) = db.bump_stats(name, &domain, |s| s.invoices.inc()) {
+                error!("Unable to update stats of {}@{}: {}", name, domain, e);
+            }
 
             let resp = LNURLPayValues {
                 lnurl_response: LNURLResponse {
                     status: Some("OK".to_string()),
                     reason: None,
                 },
-                pr: bolt11,
+                pr: json!(invoice.payment_request),
                 disposable: Some(false),
                 success_action,
+                verify: Some(format!(
+                    "https://{}/.well-known/lnurlp/{}/verify/{}",
+                    domain, username, invoice.payment_hash
+                )),
             };
 
             Ok(warp::reply::json(&resp))
@@ -143,34 +234,268 @@ pub async fn lnurl(
         None => {
             // no amount provided, different payload
 
-            // TODO: support webhook comments
-            let min_sendable = params.min_sendable.unwrap_or(defaults::MIN_SENDABLE);
-            let max_sendable = params.max_sendable.unwrap_or(defaults::MAX_SENDABLE);
+            let (min_sendable, max_sendable) = params.sendable(config.sendable_bounds());
+            let nostr_pubkey = config.nostr_key(&domain).map(nostr::public_key);
+            let currencies = match (&config.fiat_rates, params.currencies.is_empty()) {
+                (Some(source), false) => {
+                    match fiat::rates(source, Duration::from_secs(config.fiat_rates_ttl)).await {
+                        Ok(rates) => Some(fiat::pay_currencies(&params.currencies, &rates))
+                            .filter(|c| !c.is_empty()),
+                        Err(e) => {
+                            warn!("Unable to get exchange rates: {}", e);
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            };
 
-            params.stats.calls.inc();
-            db.update(&params).map_err(|_| warp::reject())?;
+            if let Err(e) = db.bump_stats(name, &domain, |s| s.calls.inc()) {
+                error!("Unable to update stats of {}@{}: {}", name, domain, e);
+            }
 
             Ok(warp::reply::json(&LNURLPayParams {
                 lnurl_response: LNURLResponse {
                     status: Some("OK".to_string()),
                     reason: None,
                 },
-                callback: format!("https://{}/.well-known/lnurlp/{}", domain, username),
+                callback: lnurl::pay_url(&domain, &username),
                 min_sendable,
                 max_sendable,
-                metadata: Metadata::from(params.clone()).to_string(),
+                metadata,
                 comment_allowed: params.invoice_api.get_comment_len(),
                 tag: "payRequest".to_owned(),
+                payer_data: params.payer_data.clone(),
+                allows_nostr: nostr_pubkey.as_ref().map(|_| true),
+                nostr_pubkey,
+                currencies,
+            }))
+        }
+    }
+}
+
+/// Splits the requested name into the address name and the name
+/// of its pay link (`alice+coffee`), if any
+fn split_link(username: &str) -> (&str, Option<&str>) {
+    match username.split_once('+') {
+        Some((name, link)) => (name, Some(link)),
+        None => (username, None),
+    }
+}
+
+/// Parses the amount sent to the callback, either in msat or in the
+/// smallest units of one of the fiat currencies of the address (e.g.
+/// `1050.EUR`), which is converted to msat at the current rate
+async fn parse_amount(params: &Params, config: &Config, amount: &str) -> Result<u64, String> {
+    let (amount, code) = match amount.split_once('.') {
+        Some((amount, code)) => (amount, Some(code)),
+        None => (amount, None),
+    };
+    let amount = amount
+        .parse::<u64>()
+        .map_err(|_| "Invalid amount".to_string())?;
+    let code = match code {
+        Some(code) => code,
+        None => return Ok(amount),
+    };
+
+    let accepted = params.currencies.iter().any(|c| c == code);
+    let (currency, source) = match (fiat::currency(code), &config.fiat_rates) {
+        (Some(currency), Some(source)) if accepted => (currency, source),
+        _ => return Err(format!("Currency {} is not accepted", code)),
+    };
+    let rates = fiat::rates(source, Duration::from_secs(config.fiat_rates_ttl))
+        .await
+        .map_err(|e| {
+            warn!("Unable to get exchange rates: {}", e);
+            "Exchange rate not available".to_string()
+        })?;
+    let rate = rates
+        .get(code)
+        .ok_or_else(|| "Exchange rate not available".to_string())?;
+
+    let msat = currency.to_msat(*rate, amount);
+    // backends taking sats only accept whole sats
+    if params.invoice_api.supports_msat() {
+        Ok(msat)
+    } else {
+        // saturated conversions of huge amounts stay above the limits
+        Ok(msat.saturating_add(500) / 1000 * 1000)
+    }
+}
+
+/// Checks the amount and comment sent to the callback against
+/// the limits advertised in the pay request
+fn check_callback(
+    params: &Params,
+    config: &Config,
+    msat: u64,
+    comment: Option<&String>,
+) -> Result<(), String> {
+    let (min_sendable, max_sendable) = params.sendable(config.sendable_bounds());
+    if msat < min_sendable || msat > max_sendable {
+        return Err(format!(
+            "Amount has to be between {} and {} msat",
+            min_sendable, max_sendable
+        ));
+    }
+    // backends taking sats would silently round the amount down
+    if !params.invoice_api.accepts_amount(msat) {
+        return Err("Amount has to be a whole number of sats".to_string());
+    }
+    let comment_allowed = params.invoice_api.get_comment_len();
+    match comment {
+        Some(comment) if comment.chars().count() > comment_allowed.into() => Err(format!(
+            "Comment longer than {} characters",
+            comment_allowed
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Parses and validates payer details sent to the callback (LUD-18),
+/// returns `None` if the payer didn't send any (and none is mandatory)
+fn parse_payer_data(
+    request: &PayerDataRequest,
+    raw: Option<&String>,
+) -> Result<Option<PayerData>, String> {
+    let data: PayerData = match raw {
+        Some(raw) => serde_json::from_str(raw).map_err(|e| format!("invalid payer data: {}", e))?,
+        None => PayerData::default(),
+    };
+    request.check(&data)?;
+    data.validate()
+        .map_err(|e| format!("invalid payer data: {}", e))?;
+    Ok(raw.map(|_| data))
+}
+
+/// Handles LUD-21 verify requests, tells if the invoice issued
+/// for the address was paid. Pending invoices are checked
+/// with the backend right away, without waiting for the
+/// background settlement check.
+pub async fn verify(
+    db: Db,
+    config: Config,
+    username: String,
+    domain: String,
+    payment_hash: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let username = percent_decode_str(&username)
+        .decode_utf8()
+        .map_err(|_| warp::reject())?
+        .to_string();
+    // invoices of pay links are recorded for the address
+    let address = format!("{}@{}", split_link(&username).0, domain);
+
+    let invoice = db
+        .get_invoice(&payment_hash)
+        .map_err(|_| warp::reject())?
+        .filter(|i| i.address == address);
+    let mut invoice = match invoice {
+        Some(invoice) => invoice,
+        None => {
+            return Ok(warp::reply::json(&LNURLResponse {
+                status: Some("ERROR".to_string()),
+                reason: Some("Not found".to_string()),
             }))
         }
+    };
+
+    // anybody can ask, so the backend is asked at most once per
+    // interval and the other requests are served from the ledger
+    let since = SystemTime::now() - settlement::VERIFY_INTERVAL;
+    match db.claim_invoice_check(&payment_hash, since) {
+        Ok(Some(mut claimed)) => {
+            if let Err(e) = settlement::refresh(&db, &config, &mut claimed).await {
+                warn!("Unable to check invoice {}: {}", payment_hash, e);
+            }
+            invoice = claimed;
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Unable to claim check of invoice {}: {}", payment_hash, e),
     }
+
+    Ok(warp::reply::json(&LNURLVerify {
+        lnurl_response: LNURLResponse {
+            status: Some("OK".to_string()),
+            reason: None,
+        },
+        settled: invoice.status == InvoiceStatus::Settled,
+        preimage: invoice.preimage,
+        pr: invoice.payment_request,
+    }))
+}
+
+/// Looks up the (enabled) address or pay link and returns
+/// its bech32 encoded LNURL (LUD-01)
+fn address_lnurl(db: &Db, username: &str, domain: &str) -> Result<String, Rejection> {
+    let (name, link) = split_link(username);
+    let owner = db
+        .get(name, domain)
+        .map_err(|_| warp::reject())?
+        .filter(|p| !p.disabled)
+        .ok_or_else(warp::reject)?;
+    if let Some(link) = link {
+        owner.with_link(link).ok_or_else(warp::reject)?;
+    }
+    lnurl::encode(&lnurl::pay_url(domain, username)).map_err(|_| warp::reject())
+}
+
+/// Returns the bech32 encoded LNURL of the address, for wallets
+/// which don't support lightning addresses
+pub async fn lnurl_code(
+    db: Db,
+    _config: Config,
+    username: String,
+    domain: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let username = percent_decode_str(&username)
+        .decode_utf8()
+        .map_err(|_| warp::reject())?
+        .to_string();
+    let code = address_lnurl(&db, &username, &domain)?;
+
+    Ok(warp::reply::json(&json!({
+        "address": format!("{}@{}", username, domain),
+        "url": lnurl::pay_url(&domain, &username),
+        "lnurl": code,
+    })))
+}
+
+/// Renders the QR code of the LNURL of the address,
+/// `qr.png` and `qr.svg` images are served
+pub async fn lnurl_qr(
+    db: Db,
+    _config: Config,
+    username: String,
+    domain: String,
+    file: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let username = percent_decode_str(&username)
+        .decode_utf8()
+        .map_err(|_| warp::reject())?
+        .to_string();
+    let code = address_lnurl(&db, &username, &domain)?;
+
+    let (body, content_type) = match file.as_str() {
+        "qr.png" => (lnurl::qr_png(&code), "image/png"),
+        "qr.svg" => (
+            lnurl::qr_svg(&code).map(String::into_bytes),
+            "image/svg+xml",
+        ),
+        _ => return Err(warp::reject()),
+    };
+    let body = body.map_err(|e| reject::custom(Error::Val(e.to_string())))?;
+
+    Ok(warp::reply::with_header(body, "content-type", content_type))
 }
 
 /// Format of the POST request used to reserve/claim addresses
 /// in the system and to mofidy entries (PIN required)
 #[derive(Deserialize, Debug, Validate)]
+#[validate(schema(function = "validate_alias_success_action"))]
 struct AliasPostData {
-    #[validate(length(min = 1))]
+    #[validate(length(min = 1), custom = "validate_name")]
     pub name: String,
     #[validate(custom(function = "validate_domain", arg = "&'v_a Config"))]
     pub domain: String,
@@ -178,26 +503,95 @@ struct AliasPostData {
     pub backend: String,
     pub pin: Option<String>,
     pub backend_data: Option<InvoiceAPI>,
+    /// url notified about invoices, empty removes the webhook
+    /// (kept as is if not given)
+    pub webhook_url: Option<String>,
+    /// payer details to ask for, `field -> "optional" | "mandatory"`,
+    /// none selected stops asking
+    pub payer_data: Option<HashMap<String, String>>,
+    /// `null` removes the action
+    #[serde(default, deserialize_with = "explicit")]
+    pub success_action: Option<Option<SuccessActionConfig>>,
+    /// `null` or only blank fields remove the metadata,
+    /// validated along with the rest of the entry
+    #[serde(default, deserialize_with = "explicit")]
+    pub metadata: Option<Option<AddressMetadata>>,
+    /// payment limits in msat, server-wide bounds unless set,
+    /// `null` goes back to them
+    #[serde(default, deserialize_with = "explicit")]
+    pub min_sendable: Option<Option<u64>>,
+    #[serde(default, deserialize_with = "explicit")]
+    pub max_sendable: Option<Option<u64>>,
+    /// fixed-price pay links, existing ones are kept unless given
+    pub links: Option<Vec<PayLink>>,
+    /// codes of the fiat currencies payers can send amounts in,
+    /// empty stops accepting them
+    pub currencies: Option<Vec<String>>,
 }
 
-impl From<AliasPostData> for Params {
-    fn from(data: AliasPostData) -> Self {
-        let min_sendable = match data.backend_data.as_ref().unwrap() {
-            // less than 3k msats might mean routing problems
-            InvoiceAPI::Keysend(_) => Some(3000),
-            _ => None,
-        };
+/// Tells an explicit `null` (`Some(None)`) from a field
+/// that wasn't given at all (`None`)
+fn explicit<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+impl AliasPostData {
+    /// Params of the entry, optional fields that aren't given keep
+    /// their `stored` values. Note: PIN and webhook are not carried
+    /// over, they need to be set separately
+    fn into_params(self, stored: Option<&Params>) -> Params {
+        let stored = stored.cloned().unwrap_or_default();
         Params {
-            name: data.name,
-            domain: data.domain,
-            invoice_api: data.backend_data.unwrap(),
-            pin: data.pin.unwrap(),
-            min_sendable,
+            name: self.name,
+            domain: self.domain,
+            invoice_api: self.backend_data.unwrap(),
+            min_sendable: self.min_sendable.unwrap_or(stored.min_sendable),
+            max_sendable: self.max_sendable.unwrap_or(stored.max_sendable),
+            stats: stored.stats,
+            disabled: stored.disabled,
+            payer_data: match self.payer_data {
+                Some(ref form) => payer_data_from_form(form),
+                None => stored.payer_data,
+            },
+            success_action: self.success_action.unwrap_or(stored.success_action),
+            metadata: self.metadata.unwrap_or(stored.metadata),
+            links: self.links.unwrap_or(stored.links),
+            currencies: self.currencies.unwrap_or(stored.currencies),
             ..Default::default()
         }
     }
 }
 
+/// Validates the success action, AES ones need a backend
+/// that accepts preimages chosen by us
+fn validate_alias_success_action(data: &AliasPostData) -> Result<(), ValidationError> {
+    match data.success_action {
+        Some(Some(ref action)) => validate_success_action(action, data.backend_data.as_ref()),
+        _ => Ok(()),
+    }
+}
+
+/// Converts the payer details selection of the web form,
+/// fields not marked optional or mandatory are not requested
+fn payer_data_from_form(form: &HashMap<String, String>) -> Option<PayerDataRequest> {
+    let field = |name: &str| match form.get(name).map(|s| s.as_str()) {
+        Some("optional") => Some(PayerField { mandatory: false }),
+        Some("mandatory") => Some(PayerField { mandatory: true }),
+        _ => None,
+    };
+    let request = PayerDataRequest {
+        name: field("name"),
+        pubkey: field("pubkey"),
+        identifier: field("identifier"),
+        email: field("email"),
+    };
+    (request != PayerDataRequest::default()).then_some(request)
+}
+
 /// Validates that the domain is within domain list defined
 /// in the config.
 fn validate_domain(domain: &str, config: &Config) -> Result<(), ValidationError> {
@@ -220,13 +614,24 @@ fn validate_backend(backend: &str, _config: &Config) -> Result<(), ValidationErr
 }
 
 #[derive(Error, Debug)]
-enum Error {
+pub(crate) enum Error {
     #[error("JSON path error: {0}")]
     JSONPath(String),
     #[error("validation error: {0}")]
     Validation(ValidationErrors),
     #[error("value error: {0}")]
     Val(String),
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("conflict: {0}")]
+    Conflict(String),
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    /// storage (or other server side) failure, not the client's fault
+    #[error("internal error: {0}")]
+    Internal(String),
 }
 
 impl reject::Reject for Error {}
@@ -239,6 +644,12 @@ pub async fn grab(db: Db, config: Config, buf: impl Buf) -> Result<impl Reply, R
 
     debug!("processing the following body {:?}", body);
 
+    // fields left blank in the form are not set
+    body.metadata = body
+        .metadata
+        .take()
+        .map(|metadata| metadata.and_then(AddressMetadata::normalized));
+
     // perform basic validation
     body.validate_args((&config, &config))
         .map_err(|e| reject::custom(Error::Validation(e)))?;
@@ -298,41 +709,87 @@ pub async fn grab(db: Db, config: Config, buf: impl Buf) -> Result<impl Reply, R
         .map_err(|e| reject::custom(Error::Val(e.to_string())))?;
 
     // check pin match if object exists
-    let pin = compute_pin(&body.name, &body.domain, &config.pin_secret);
-    if entry.is_some() {
-        debug!("Generated pin to modify record = {:?}", pin);
-        match body.pin {
-            None => {
-                return Err(reject::custom(Error::Val(
-                    "PIN required to modify record (entry already exists)".to_string(),
-                )))
-            }
-            Some(in_pin) if in_pin != pin => {
+    let pin = match (&entry, body.pin.take()) {
+        (None, _) => None,
+        (Some(_), None) => {
+            return Err(reject::custom(Error::Val(
+                "PIN required to modify record (entry already exists)".to_string(),
+            )))
+        }
+        (Some(entry), Some(in_pin)) => {
+            if !verify_pin_async(entry, &in_pin, &config.pin_secrets()).await {
                 return Err(reject::custom(Error::Val(
                     "provided PIN incorrect".to_string(),
-                )))
+                )));
             }
-            Some(_) => (),
+            Some(in_pin)
         }
-    }
+    };
 
-    // set the pin entry
-    body.pin = Some(pin.clone());
-    let mut params: Params = body.into();
+    let webhook_url = body.webhook_url.take();
+    let mut params = body.into_params(entry.as_ref());
+    params
+        .validate()
+        .and_then(|_| validate_sendable_bounds(&params, config.sendable_bounds()))
+        .map_err(|e| reject::custom(Error::Validation(e)))?;
+    match webhook_url {
+        None => params.webhook = entry.as_ref().and_then(|e| e.webhook.clone()),
+        // explicitly emptied, the webhook is removed
+        Some(url) if url.is_empty() => {}
+        Some(url) => {
+            let mut hook = Webhook {
+                url,
+                ..Default::default()
+            };
+            hook.validate()
+                .map_err(|e| reject::custom(Error::Validation(e)))?;
+            webhook::check_url(&hook.url, config.webhook_allow_local)
+                .await
+                .map_err(|e| reject::custom(Error::Val(e.to_string())))?;
+            webhook::keep_or_generate_secret(
+                &mut hook,
+                entry.as_ref().and_then(|e| e.webhook.as_ref()),
+            );
+            params.webhook = Some(hook);
+        }
+    }
+    let pin = match (&entry, pin) {
+        // entry with hashed PIN, keep it as is
+        (
+            Some(Params {
+                pin_hash: Some(hash),
+                ..
+            }),
+            Some(pin),
+        ) => {
+            params.pin_hash = Some(hash.clone());
+            pin
+        }
+        // new entry or entry using legacy PIN, generate a new random one
+        _ => reset_pin_async(&mut params)
+            .await
+            .map_err(|e| reject::custom(Error::Val(e.to_string())))?,
+    };
 
     // we need to do some legwork when handling keysend
     if let InvoiceAPI::Keysend(ref mut k_params) = params.invoice_api {
         if let Some(Params {
             invoice_api: InvoiceAPI::Keysend(params),
             ..
-        }) = entry
+        }) = &entry
         {
             // update keysend pubkey if we only modify the entry
-            let api_key = params.admin_key.unwrap();
+            let api_key = params.admin_key.clone().ok_or_else(|| {
+                reject::custom(Error::Internal(
+                    "keysend backend is not provisioned".to_string(),
+                ))
+            })?;
             keysend::update_entry(&config.lnbits, &api_key, Some(&k_params.pub_key), None)
                 .await
                 .map_err(|e| Error::Val(format!("Problem updating pubkey: {}", e)))?;
 
+            k_params.user_id = params.user_id.clone();
+            k_params.wallet_id = params.wallet_id.clone();
             k_params.admin_key = Some(api_key);
         } else {
             // one-time: fully provision lnbits backend elements
@@ -355,7 +812,8 @@ pub async fn grab(db: Db, config: Config, buf: impl Buf) -> Result<impl Reply, R
         }
     }
 
-    // try to generate the invoice
+    // try to generate the invoice, it carries the PIN
+    // so (unlike payment invoices) it's not recorded in the ledger
     let memo = format!("{}@{} PIN: {}", params.name, params.domain, pin);
     if let Err(e) = make_invoice(
         &params,
@@ -363,6 +821,8 @@ pub async fn grab(db: Db, config: Config, buf: impl Buf) -> Result<impl Reply, R
         42000,
         config.tor_proxy_url,
         Some(memo),
+        None,
+        None,
     )
     .await
     {
@@ -370,18 +830,79 @@ pub async fn grab(db: Db, config: Config, buf: impl Buf) -> Result<impl Reply, R
         return Err(reject::custom(Error::Val(e.to_string())));
     }
 
-    // update entry in the database
-    db.insert(&params.name, &params.domain, &params)
+    // update entry in the database, unless it got claimed (or edited)
+    // by someone else since it was read
+    let saved = match entry {
+        Some(ref entry) => db.replace(entry, &params),
+        None => db.create(&params),
+    }
+    .map_err(|e| reject::custom(Error::Internal(e.to_string())))?;
+    if !saved {
+        if let (None, InvoiceAPI::Keysend(ref k_params)) = (&entry, &params.invoice_api) {
+            if let Err(e) = keysend::deprovision_backend(&config.lnbits, k_params).await {
+                error!("Unable to deprovision keysend backend: {}", e);
+            }
+        }
+        return Err(reject::custom(Error::Conflict(match entry {
+            Some(_) => format!("{}@{} changed in the meantime", params.name, params.domain),
+            None => format!("{}@{} already exists", params.name, params.domain),
+        })));
+    }
+    if let Some(ref entry) = entry {
+        release_keysend(&config.lnbits, entry, &params).await;
+    }
+
+    // shown by the wizard for wallets without lightning address support
+    let code = lnurl::encode(&lnurl::pay_url(&params.domain, &params.name))
         .map_err(|e| reject::custom(Error::Val(e.to_string())))?;
+    let qr_svg = lnurl::qr_svg(&code).map_err(|e| reject::custom(Error::Val(e.to_string())))?;
 
     let json = warp::reply::json(&json!({
         "message": "success",
-        "pin": params.pin,
+        "pin": pin,
+        "webhookSecret": params.webhook.map(|w| w.secret),
+        "lnurl": code,
+        "qrSvg": qr_svg,
         "errors": [],
     }));
     Ok(warp::reply::with_status(json, StatusCode::CREATED))
 }
 
+/// Format of the POST request used by owners to delete their addresses
+#[derive(Deserialize, Debug)]
+struct AliasDeleteData {
+    pub name: String,
+    pub domain: String,
+    pub pin: String,
+}
+
+/// Removes the address on the owner's request (PIN required).
+pub async fn delete(db: Db, config: Config, buf: impl Buf) -> Result<impl Reply, Rejection> {
+    let des = &mut serde_json::Deserializer::from_reader(buf.reader());
+    let body: AliasDeleteData = serde_path_to_error::deserialize(des)
+        .map_err(|e| reject::custom(Error::JSONPath(e.to_string())))?;
+
+    let entry = db
+        .get(&body.name, &body.domain)
+        .map_err(|e| reject::custom(Error::Val(e.to_string())))?
+        .ok_or_else(|| reject::custom(Error::NotFound(format!("{}@{}", body.name, body.domain))))?;
+    if !verify_pin_async(&entry, &body.pin, &config.pin_secrets()).await {
+        return Err(reject::custom(Error::Val(
+            "provided PIN incorrect".to_string(),
+        )));
+    }
+
+    remove_entry(&db, &config.lnbits, &body.name, &body.domain)
+        .await
+        .map_err(|e| reject::custom(Error::Val(format!("Problem removing alias: {}", e))))?;
+    info!("{}@{} deleted by the owner", body.name, body.domain);
+
+    Ok(warp::reply::json(&json!({
+        "message": "deleted",
+        "errors": [],
+    })))
+}
+
 #[derive(Serialize)]
 struct ErrorResponse {
     message: String,
@@ -403,6 +924,18 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
         match e {
             Error::Val(_) => (StatusCode::BAD_REQUEST, e.to_string(), None),
             Error::JSONPath(_) => (StatusCode::BAD_REQUEST, e.to_string(), None),
+            Error::NotFound(_) => (StatusCode::NOT_FOUND, e.to_string(), None),
+            Error::Conflict(_) => (StatusCode::CONFLICT, e.to_string(), None),
+            Error::Unauthorized(_) => (StatusCode::UNAUTHORIZED, e.to_string(), None),
+            Error::Forbidden(_) => (StatusCode::FORBIDDEN, e.to_string(), None),
+            Error::Internal(_) => {
+                error!("{}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal Server Error".to_string(),
+                    None,
+                )
+            }
             Error::Validation(val_errs) => {
                 let errors: Vec<FieldError> = val_errs
                     .errors()
@@ -449,19 +982,9 @@ pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible>
     } else if let Some(e) = err.find::<LnUrlError>() {
         // generate response with error
         // so that we display it in the LNUrl client
-        let success_action = SuccessAction {
-            tag: "message".to_string(),
-            ..Default::default()
-        };
-
-        let resp = LNURLPayValues {
-            lnurl_response: LNURLResponse {
-                status: Some("ERROR".to_string()),
-                reason: Some(e.0.to_string()),
-            },
-            pr: json!({}),
-            disposable: Some(false),
-            success_action,
+        let resp = LNURLResponse {
+            status: Some("ERROR".to_string()),
+            reason: Some(e.0.to_string()),
         };
         let json = warp::reply::json(&resp);
         return Ok(warp::reply::with_status(json, StatusCode::OK));
@@ -495,27 +1018,855 @@ fn validation_errs_to_str_vec(ve: &ValidationErrors) -> Vec<String> {
         .collect()
 }
 
-/// Computes the pin that is required in order to modify entries
-/// via the API calls.
-fn compute_pin(usnername: &str, domain: &str, secret: &str) -> String {
-    let sha = Sha256::new()
-        .chain_update(secret)
-        .chain_update(usnername)
-        .chain_update(domain)
-        .finalize();
-
-    hex::encode(sha)
-}
-
 #[cfg(test)]
 mod tests {
-    use super::compute_pin;
+    use std::{
+        collections::HashMap,
+        time::{Duration, SystemTime},
+    };
+
+    use envconfig::Envconfig;
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
+    use warp::{hyper::body::Bytes, Reply};
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{
+        db::{
+            helpers,
+            models::{
+                InvoiceAPI, InvoiceRecord, InvoiceStatus, KeysendParams, LNBitsParams, LNDParams,
+                Params, PayLink, PayerDataRequest, PayerField,
+            },
+            Db,
+        },
+        nostr,
+        pin::reset_pin,
+        Config,
+    };
+
+    use super::{
+        address_lnurl, check_callback, delete, grab, handle_rejection, lnurl, lnurl_code, lnurl_qr,
+        parse_amount, parse_payer_data, payer_data_from_form, verify,
+    };
+
+    fn init_config() -> Config {
+        let hm = HashMap::from([
+            ("DOMAINS".to_owned(), "mydomain.com,mail.com".to_owned()),
+            ("EMAIL_IDENTIFIER_DOMAINS".to_owned(), "mail.com".to_owned()),
+            (
+                "FIAT_RATES".to_owned(),
+                "static:EUR=25000,USD=30000".to_owned(),
+            ),
+            (
+                "NOSTR_KEYS".to_owned(),
+                format!("mydomain.com={}", "09".repeat(32)),
+            ),
+            ("PIN_SECRET".to_owned(), "my-secret".to_owned()),
+            ("SITE_NAME".to_owned(), "my-site".to_owned()),
+            ("SITE_SUB_NAME".to_owned(), "my-com".to_owned()),
+            ("LNBITS_URL".to_owned(), "http://127.0.0.1:5000/".to_owned()),
+            ("LNBITS_API_KEY".to_owned(), "lnbits-key".to_owned()),
+            ("LNBITS_ADMIN_ID".to_owned(), "lnbits-admin".to_owned()),
+        ]);
+        Config::init_from_hashmap(&hm).unwrap()
+    }
+
+    async fn reply_json(reply: impl Reply) -> Value {
+        let body = warp::hyper::body::to_bytes(reply.into_response().into_body())
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    async fn lnurl_json(db: &Db, domain: &str, query: &[(&str, &str)]) -> Value {
+        let query = query
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let reply = lnurl(
+            db.clone(),
+            init_config(),
+            "alice".to_owned(),
+            domain.to_owned(),
+            query,
+        )
+        .await
+        .unwrap();
+        reply_json(reply).await
+    }
+
+    /// LND node issuing the same invoice for every request
+    async fn lnd_mock() -> MockServer {
+        slow_lnd_mock(Duration::ZERO).await
+    }
+
+    /// Same as `lnd_mock`, taking `delay` to answer
+    async fn slow_lnd_mock(delay: Duration) -> MockServer {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/invoices"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({
+                        "payment_request": "lnbc-payment",
+                        "r_hash": base64::encode([0xab; 32]),
+                    }))
+                    .set_delay(delay),
+            )
+            .mount(&mock_server)
+            .await;
+        mock_server
+    }
+
+    /// Description hash of the last invoice requested from the node
+    async fn last_description_hash(mock_server: &MockServer) -> Value {
+        let requests = mock_server.received_requests().await.unwrap();
+        let body: Value = requests.last().unwrap().body_json().unwrap();
+        body["description_hash"].clone()
+    }
+
+    async fn verify_json(db: &Db, username: &str, hash: &str) -> Value {
+        let reply = verify(
+            db.clone(),
+            init_config(),
+            username.to_owned(),
+            "mydomain.com".to_owned(),
+            hash.to_owned(),
+        )
+        .await
+        .unwrap();
+        reply_json(reply).await
+    }
+
+    #[tokio::test]
+    async fn invoice_commits_to_the_served_metadata() {
+        let mock_server = lnd_mock().await;
+
+        let db = helpers::tmp_db();
+        for (domain, identifier) in [
+            ("mydomain.com", "text/identifier"),
+            ("mail.com", "text/email"),
+        ] {
+            let params = Params {
+                name: "alice".to_owned(),
+                domain: domain.to_owned(),
+                invoice_api: InvoiceAPI::Lnd(LNDParams {
+                    host: mock_server.uri(),
+                    macaroon: "00".to_owned(),
+                }),
+                payer_data: Some(PayerDataRequest {
+                    name: Some(PayerField { mandatory: false }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            db.insert("alice", domain, &params).unwrap();
+
+            let pay = lnurl_json(&db, domain, &[]).await;
+            let metadata = pay["metadata"].as_str().unwrap().to_owned();
+            let entries: Value = serde_json::from_str(&metadata).unwrap();
+            assert_eq!(entries[0], json!([identifier, format!("alice@{}", domain)]));
+
+            // the invoice commits to the served metadata, with payer data appended
+            let payer_data = r#"{"name":"Bob"}"#;
+            for (query, description) in [
+                (vec![("amount", "1000")], metadata.clone()),
+                (
+                    vec![("amount", "1000"), ("payerdata", payer_data)],
+                    format!("{}{}", metadata, payer_data),
+                ),
+            ] {
+                let resp = lnurl_json(&db, domain, &query).await;
+                assert_eq!(resp["pr"], "lnbc-payment");
+                assert_eq!(
+                    last_description_hash(&mock_server).await,
+                    base64::encode(Sha256::digest(description.as_bytes()))
+                );
+            }
+        }
+    }
 
     #[test]
-    fn computes_pin_for_user() {
+    fn callbacks_are_checked_against_the_limits() {
+        let config = init_config();
+        let lnbits = Params {
+            invoice_api: InvoiceAPI::LNBits(LNBitsParams::default()),
+            min_sendable: Some(2_000),
+            max_sendable: Some(10_000),
+            ..Default::default()
+        };
+        assert!(check_callback(&lnbits, &config, 2_000, None).is_ok());
+        assert!(check_callback(&lnbits, &config, 10_000, None).is_ok());
+        assert!(check_callback(&lnbits, &config, 1_000, None).is_err());
+        assert!(check_callback(&lnbits, &config, 11_000, None).is_err());
+        // LNbits takes whole sats only
+        assert!(check_callback(&lnbits, &config, 2_500, None).is_err());
+
+        let lnd = Params {
+            invoice_api: InvoiceAPI::Lnd(LNDParams::default()),
+            ..lnbits
+        };
+        assert!(check_callback(&lnd, &config, 2_500, None).is_ok());
+
+        let comment = "ä".repeat(128);
+        assert!(check_callback(&lnd, &config, 2_000, Some(&comment)).is_ok());
+        let comment = "a".repeat(129);
+        assert!(check_callback(&lnd, &config, 2_000, Some(&comment)).is_err());
+    }
+
+    #[tokio::test]
+    async fn callback_rejections_are_lnurl_errors() {
+        let db = helpers::tmp_db();
+        let params = Params {
+            name: "alice".to_owned(),
+            domain: "mydomain.com".to_owned(),
+            invoice_api: InvoiceAPI::LNBits(LNBitsParams::default()),
+            ..Default::default()
+        };
+        db.insert("alice", "mydomain.com", &params).unwrap();
+
+        for amount in ["1", "not-a-number"] {
+            let query = HashMap::from([("amount".to_owned(), amount.to_owned())]);
+            let rejection = lnurl(
+                db.clone(),
+                init_config(),
+                "alice".to_owned(),
+                "mydomain.com".to_owned(),
+                query,
+            )
+            .await
+            .err()
+            .unwrap();
+            let resp = reply_json(handle_rejection(rejection).await.unwrap()).await;
+            assert_eq!(resp["status"], "ERROR");
+            assert!(resp["reason"].is_string());
+            assert!(resp.get("pr").is_none());
+        }
+        // nothing was issued
+        assert!(db.list_invoices(None).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn pay_links_have_a_fixed_price() {
+        let mock_server = lnd_mock().await;
+        let db = helpers::tmp_db();
+        let params = Params {
+            name: "alice".to_owned(),
+            domain: "mydomain.com".to_owned(),
+            invoice_api: InvoiceAPI::Lnd(LNDParams {
+                host: mock_server.uri(),
+                macaroon: "00".to_owned(),
+            }),
+            links: vec![PayLink {
+                name: "coffee".to_owned(),
+                msat: 5_000,
+                description: "Flat white".to_owned(),
+                success_action: None,
+            }],
+            ..Default::default()
+        };
+        db.insert("alice", "mydomain.com", &params).unwrap();
+        let request = |username: &str, query: &[(&str, &str)]| {
+            let query = query
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            lnurl(
+                db.clone(),
+                init_config(),
+                username.to_owned(),
+                "mydomain.com".to_owned(),
+                query,
+            )
+        };
+
+        let pay = reply_json(request("alice+coffee", &[]).await.unwrap()).await;
+        assert_eq!(pay["minSendable"], 5_000);
+        assert_eq!(pay["maxSendable"], 5_000);
+        assert_eq!(
+            pay["callback"],
+            "https://mydomain.com/.well-known/lnurlp/alice+coffee"
+        );
+        let metadata = pay["metadata"].as_str().unwrap().to_owned();
+        let entries: Value = serde_json::from_str(&metadata).unwrap();
+        assert_eq!(
+            entries[0],
+            json!(["text/identifier", "alice+coffee@mydomain.com"])
+        );
+        assert_eq!(entries[1], json!(["text/plain", "Flat white"]));
+
+        assert!(request("alice+coffee", &[("amount", "4000")])
+            .await
+            .is_err());
+        assert!(request("alice+tea", &[]).await.is_err());
+        let resp = reply_json(
+            request("alice+coffee", &[("amount", "5000")])
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(resp["pr"], "lnbc-payment");
+        assert_eq!(
+            last_description_hash(&mock_server).await,
+            base64::encode(Sha256::digest(metadata.as_bytes()))
+        );
+
+        // recorded and counted for the address
+        let record = db.get_invoice(&"ab".repeat(32)).unwrap().unwrap();
+        assert_eq!(record.address, "alice@mydomain.com");
+        let owner = db.get("alice", "mydomain.com").unwrap().unwrap();
+        assert_eq!(owner.links, params.links);
+        assert_eq!(owner.stats.invoices.num, 1);
+    }
+
+    #[tokio::test]
+    async fn fiat_amounts_are_converted() {
+        let mock_server = lnd_mock().await;
+        let db = helpers::tmp_db();
+        let params = Params {
+            name: "alice".to_owned(),
+            domain: "mydomain.com".to_owned(),
+            invoice_api: InvoiceAPI::Lnd(LNDParams {
+                host: mock_server.uri(),
+                macaroon: "00".to_owned(),
+            }),
+            currencies: vec!["EUR".to_owned(), "JPY".to_owned()],
+            ..Default::default()
+        };
+        db.insert("alice", "mydomain.com", &params).unwrap();
+
+        // no rate of JPY, only EUR is advertised
+        let pay = lnurl_json(&db, "mydomain.com", &[]).await;
+        assert_eq!(
+            pay["currencies"],
+            json!([{
+                "code": "EUR",
+                "name": "Euro",
+                "symbol": "€",
+                "decimals": 2,
+                "multiplier": 40000.0,
+                "convertible": true,
+            }])
+        );
+
+        // 10.50 EUR at 25000 EUR per bitcoin
+        let resp = lnurl_json(&db, "mydomain.com", &[("amount", "1050.EUR")]).await;
+        assert_eq!(resp["pr"], "lnbc-payment");
+        let record = db.get_invoice(&"ab".repeat(32)).unwrap().unwrap();
+        assert_eq!(record.msat, 42_000_000);
+
+        let config = init_config();
+        for amount in ["1050.USD", "1000.JPY", "10.50.EUR", "1050.eur", ".EUR"] {
+            assert!(parse_amount(&params, &config, amount).await.is_err());
+        }
+
+        // backends taking sats get whole sats
+        let mut lnbits = params.clone();
+        lnbits.invoice_api = InvoiceAPI::LNBits(LNBitsParams::default());
+        assert_eq!(parse_amount(&lnbits, &config, "1.EUR").await, Ok(40_000));
+        assert_eq!(parse_amount(&params, &config, "1").await, Ok(1));
+        // way above the limits, but no overflow when rounding
+        let huge = parse_amount(&lnbits, &config, "18446744073709551615.EUR")
+            .await
+            .unwrap();
+        assert!(check_callback(&lnbits, &config, huge, None).is_err());
+        let usd = Params {
+            currencies: vec!["USD".to_owned()],
+            ..lnbits
+        };
+        assert_eq!(parse_amount(&usd, &config, "1.USD").await, Ok(33_000));
+        assert_eq!(parse_amount(&usd, &config, "2.USD").await, Ok(67_000));
+
+        // links and addresses without currencies don't advertise any
+        db.update(&Params {
+            currencies: vec![],
+            ..params
+        })
+        .unwrap();
+        let pay = lnurl_json(&db, "mydomain.com", &[]).await;
+        assert!(pay.get("currencies").is_none());
+    }
+
+    #[tokio::test]
+    async fn lnurls_are_served_for_addresses_and_links() {
+        let db = helpers::tmp_db();
+        let params = Params {
+            name: "alice".to_owned(),
+            domain: "mydomain.com".to_owned(),
+            links: vec![PayLink {
+                name: "coffee".to_owned(),
+                msat: 5_000,
+                description: "Flat white".to_owned(),
+                success_action: None,
+            }],
+            ..Default::default()
+        };
+        db.insert("alice", "mydomain.com", &params).unwrap();
+        let code = |username: &str| {
+            lnurl_code(
+                db.clone(),
+                init_config(),
+                username.to_owned(),
+                "mydomain.com".to_owned(),
+            )
+        };
+        let qr = |username: &str, file: &str| {
+            lnurl_qr(
+                db.clone(),
+                init_config(),
+                username.to_owned(),
+                "mydomain.com".to_owned(),
+                file.to_owned(),
+            )
+        };
+
+        let resp = reply_json(code("alice+coffee").await.unwrap()).await;
+        assert_eq!(resp["address"], "alice+coffee@mydomain.com");
+        assert_eq!(
+            crate::lnurl::decode(resp["lnurl"].as_str().unwrap()).unwrap(),
+            "https://mydomain.com/.well-known/lnurlp/alice+coffee"
+        );
+        assert!(code("bob").await.is_err());
+        assert!(code("alice+tea").await.is_err());
+
+        let png = qr("alice", "qr.png").await.unwrap().into_response();
+        assert_eq!(png.headers()["content-type"], "image/png");
+        let svg = qr("alice", "qr.svg").await.unwrap().into_response();
+        assert_eq!(svg.headers()["content-type"], "image/svg+xml");
+        assert!(qr("alice", "qr.gif").await.is_err());
+
+        // disabled addresses aren't served
+        db.update(&Params {
+            disabled: true,
+            ..params
+        })
+        .unwrap();
+        assert!(code("alice").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn disabled_addresses_are_lnurl_errors() {
+        let db = helpers::tmp_db();
+        let params = Params {
+            name: "alice".to_owned(),
+            domain: "mydomain.com".to_owned(),
+            invoice_api: InvoiceAPI::LNBits(LNBitsParams::default()),
+            disabled: true,
+            ..Default::default()
+        };
+        db.insert("alice", "mydomain.com", &params).unwrap();
+
+        for query in [vec![], vec![("amount", "1000")]] {
+            let query = query
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect();
+            let rejection = lnurl(
+                db.clone(),
+                init_config(),
+                "alice".to_owned(),
+                "mydomain.com".to_owned(),
+                query,
+            )
+            .await
+            .err()
+            .unwrap();
+            let resp = reply_json(handle_rejection(rejection).await.unwrap()).await;
+            assert_eq!(resp["status"], "ERROR");
+            assert_eq!(resp["reason"], "alice@mydomain.com is disabled");
+        }
+        assert!(address_lnurl(&db, "alice", "mydomain.com").is_err());
+        assert!(db.list_invoices(None).unwrap().is_empty());
+
+        db.update(&Params {
+            disabled: false,
+            ..params
+        })
+        .unwrap();
+        assert!(address_lnurl(&db, "alice", "mydomain.com").is_ok());
+    }
+
+    #[tokio::test]
+    async fn pay_requests_only_bump_the_stats() {
+        let mock_server = slow_lnd_mock(Duration::from_millis(200)).await;
+        let db = helpers::tmp_db();
+        let params = Params {
+            name: "alice".to_owned(),
+            domain: "mydomain.com".to_owned(),
+            invoice_api: InvoiceAPI::Lnd(LNDParams {
+                host: mock_server.uri(),
+                macaroon: "macaroon".to_owned(),
+            }),
+            ..Default::default()
+        };
+        db.insert("alice", "mydomain.com", &params).unwrap();
+
+        // disabled while the invoice is being issued
+        let disable = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let mut params = db.get("alice", "mydomain.com").unwrap().unwrap();
+            params.disabled = true;
+            db.update(&params).unwrap();
+        };
+        let (resp, _) = tokio::join!(
+            lnurl_json(&db, "mydomain.com", &[("amount", "10000")]),
+            disable
+        );
+        assert_eq!(resp["pr"], "lnbc-payment");
+        let stored = db.get("alice", "mydomain.com").unwrap().unwrap();
+        assert!(stored.disabled);
+        assert_eq!(stored.stats.invoices.num, 1);
+
+        // deleted meanwhile, the invoice is still served
+        db.update(&params).unwrap();
+        let delete = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            db.delete("alice", "mydomain.com").unwrap();
+        };
+        let (resp, _) = tokio::join!(
+            lnurl_json(&db, "mydomain.com", &[("amount", "10000")]),
+            delete
+        );
+        assert_eq!(resp["pr"], "lnbc-payment");
+        assert!(db.get("alice", "mydomain.com").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn owners_delete_addresses_with_their_pin() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("DELETE"))
+            .and(path("/usermanager/api/v1/users/user-1"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let mut config = init_config();
+        config.lnbits.url = format!("{}/", mock_server.uri()).parse().unwrap();
+
+        let db = helpers::tmp_db();
+        let mut params = Params {
+            name: "alice".to_owned(),
+            domain: "mydomain.com".to_owned(),
+            invoice_api: InvoiceAPI::Keysend(KeysendParams {
+                pub_key: "pubkey".to_owned(),
+                user_id: Some("user-1".to_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let pin = reset_pin(&mut params).unwrap();
+        db.insert("alice", "mydomain.com", &params).unwrap();
+        let body = |pin: &str| {
+            Bytes::from(json!({"name": "alice", "domain": "mydomain.com", "pin": pin}).to_string())
+        };
+
+        let rejection = delete(db.clone(), config.clone(), body("wrong-pin"))
+            .await
+            .err()
+            .unwrap();
+        let resp = handle_rejection(rejection).await.unwrap().into_response();
+        assert_eq!(resp.status(), 400);
+        assert!(db.get("alice", "mydomain.com").unwrap().is_some());
+        assert!(mock_server.received_requests().await.unwrap().is_empty());
+
+        let resp = reply_json(
+            delete(db.clone(), config.clone(), body(&pin))
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(resp["message"], "deleted");
+        assert!(db.get("alice", "mydomain.com").unwrap().is_none());
+        // the LNbits user provisioned for keysend is gone as well
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 1);
+
+        let rejection = delete(db.clone(), config, body(&pin)).await.err().unwrap();
+        let resp = handle_rejection(rejection).await.unwrap().into_response();
+        assert_eq!(resp.status(), 404);
+    }
+
+    /// Posts the address form, returns the response body
+    async fn grab_json(db: &Db, body: Value) -> Value {
+        let reply = grab(db.clone(), init_config(), Bytes::from(body.to_string()))
+            .await
+            .unwrap();
+        reply_json(reply).await
+    }
+
+    #[tokio::test]
+    async fn edits_keep_fields_not_given() {
+        let mock_server = lnd_mock().await;
+        let db = helpers::tmp_db();
+        let form = |fields: Value| {
+            let mut form = json!({
+                "name": "alice",
+                "domain": "mydomain.com",
+                "backend": "Lnd",
+                "backend_data": {"Lnd": {"host": mock_server.uri(), "macaroon": "bWFjYXJvb24="}},
+            });
+            form.as_object_mut()
+                .unwrap()
+                .extend(fields.as_object().unwrap().clone());
+            form
+        };
+
+        let created = grab_json(
+            &db,
+            form(json!({
+                "webhook_url": "https://1.1.1.1/hook",
+                "payer_data": {"name": "mandatory"},
+                "success_action": {"tag": "message", "message": "Thanks!"},
+                "metadata": {"text": "Coffee fund"},
+                "min_sendable": 10_000,
+                "currencies": ["EUR"],
+                "links": [{"name": "coffee", "msat": 5000, "description": "Flat white"}],
+            })),
+        )
+        .await;
+        let pin = created["pin"].as_str().unwrap().to_owned();
+        let before = db.get("alice", "mydomain.com").unwrap().unwrap();
+        assert!(before.webhook.is_some());
+        assert!(before.payer_data.is_some());
+        assert!(before.success_action.is_some());
+        assert!(before.metadata.is_some());
+        assert!(before.min_sendable.is_some());
+        assert!(!before.currencies.is_empty());
+
+        grab_json(&db, form(json!({ "pin": pin }))).await;
+        assert_eq!(db.get("alice", "mydomain.com").unwrap().unwrap(), before);
+
+        // explicitly emptied ones are cleared
+        grab_json(
+            &db,
+            form(json!({
+                "pin": pin,
+                "webhook_url": "",
+                "payer_data": {"name": ""},
+                "success_action": null,
+                "metadata": {"text": " "},
+                "min_sendable": null,
+                "currencies": [],
+                "links": [],
+            })),
+        )
+        .await;
+        let after = db.get("alice", "mydomain.com").unwrap().unwrap();
+        assert!(after.webhook.is_none());
+        assert!(after.payer_data.is_none());
+        assert!(after.success_action.is_none());
+        assert!(after.metadata.is_none());
+        assert!(after.min_sendable.is_none());
+        assert!(after.currencies.is_empty());
+        assert!(after.links.is_empty());
+    }
+
+    #[tokio::test]
+    async fn free_names_are_claimed_once() {
+        // slow node, both requests find the name free
+        let mock_server = slow_lnd_mock(Duration::from_millis(200)).await;
+        let db = helpers::tmp_db();
+        let body = Bytes::from(
+            json!({
+                "name": "alice",
+                "domain": "mydomain.com",
+                "backend": "Lnd",
+                "backend_data": {"Lnd": {"host": mock_server.uri(), "macaroon": "bWFjYXJvb24="}},
+            })
+            .to_string(),
+        );
+
+        let (first, second) = tokio::join!(
+            grab(db.clone(), init_config(), body.clone()),
+            grab(db.clone(), init_config(), body),
+        );
+        let (claimed, rejection) = match (first, second) {
+            (Ok(reply), Err(rejection)) | (Err(rejection), Ok(reply)) => (reply, rejection),
+            _ => panic!("expected a single claim"),
+        };
+        let pin = reply_json(claimed).await["pin"]
+            .as_str()
+            .unwrap()
+            .to_owned();
+        let resp = handle_rejection(rejection).await.unwrap().into_response();
+        assert_eq!(resp.status(), 409);
+        // the PIN given out matches the stored entry
+        let stored = db.get("alice", "mydomain.com").unwrap().unwrap();
+        assert!(crate::pin::verify_pin(&stored, &pin, &[]));
+    }
+
+    #[tokio::test]
+    async fn zap_requests_are_accepted_on_nostr_domains() {
+        let mock_server = lnd_mock().await;
+        let db = helpers::tmp_db();
+        for domain in ["mydomain.com", "mail.com"] {
+            let params = Params {
+                name: "alice".to_owned(),
+                domain: domain.to_owned(),
+                invoice_api: InvoiceAPI::Lnd(LNDParams {
+                    host: mock_server.uri(),
+                    macaroon: "00".to_owned(),
+                }),
+                ..Default::default()
+            };
+            db.insert("alice", domain, &params).unwrap();
+        }
+
+        let pay = lnurl_json(&db, "mydomain.com", &[]).await;
+        assert_eq!(pay["allowsNostr"], true);
         assert_eq!(
-            compute_pin("user", "domain", "secret1"),
-            "a8fe9f81a343e918a2aa9a6ee251b2e672c90b8f9b98d253db202ab910dc3668"
+            pay["nostrPubkey"],
+            nostr::public_key(&nostr::helpers::key(9))
         );
+        let pay = lnurl_json(&db, "mail.com", &[]).await;
+        assert!(pay.get("allowsNostr").is_none());
+
+        let request = nostr::helpers::zap_request(
+            &nostr::helpers::key(1),
+            &nostr::helpers::key(2),
+            &["wss://relay.example.com"],
+            vec![vec!["amount", "1000"]],
+        );
+        let request = serde_json::to_string(&request).unwrap();
+        let zap = |domain: &str, amount: &str| {
+            let query = HashMap::from([
+                ("amount".to_owned(), amount.to_owned()),
+                ("nostr".to_owned(), request.clone()),
+            ]);
+            lnurl(
+                db.clone(),
+                init_config(),
+                "alice".to_owned(),
+                domain.to_owned(),
+                query,
+            )
+        };
+
+        // the invoice commits to the zap request
+        let resp = reply_json(zap("mydomain.com", "1000").await.unwrap()).await;
+        assert_eq!(resp["pr"], "lnbc-payment");
+        assert_eq!(
+            last_description_hash(&mock_server).await,
+            base64::encode(Sha256::digest(request.as_bytes()))
+        );
+        let record = db.get_invoice(&"ab".repeat(32)).unwrap().unwrap();
+        assert_eq!(record.zap_request, Some(request.clone()));
+
+        assert!(zap("mydomain.com", "2000").await.is_err());
+        assert!(zap("mail.com", "1000").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn verify_reports_settled_invoices() {
+        let db = helpers::tmp_db();
+        let hash = "aa".repeat(32);
+        db.insert_invoice(&InvoiceRecord {
+            address: "alice@mydomain.com".to_owned(),
+            status: InvoiceStatus::Settled,
+            msat_received: Some(1000),
+            settled_at: Some(SystemTime::now()),
+            preimage: Some("bb".repeat(32)),
+            ..helpers::invoice(&hash)
+        })
+        .unwrap();
+
+        let resp = verify_json(&db, "alice", &hash).await;
+        assert_eq!(resp["status"], "OK");
+        assert_eq!(resp["settled"], true);
+        assert_eq!(resp["preimage"], "bb".repeat(32));
+        assert_eq!(resp["pr"], "lnbc1");
+
+        // invoices of other addresses are not revealed
+        let resp = verify_json(&db, "bob", &hash).await;
+        assert_eq!(resp["status"], "ERROR");
+        assert_eq!(resp["reason"], "Not found");
+        let resp = verify_json(&db, "alice", &"cc".repeat(32)).await;
+        assert_eq!(resp["status"], "ERROR");
+    }
+
+    #[tokio::test]
+    async fn verify_checks_the_backend_at_most_once_per_interval() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"paid": false})))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let db = helpers::tmp_db();
+        let params = Params {
+            name: "alice".to_owned(),
+            domain: "mydomain.com".to_owned(),
+            invoice_api: InvoiceAPI::LNBits(LNBitsParams {
+                host: mock_server.uri(),
+                key: "key".to_owned(),
+            }),
+            ..Default::default()
+        };
+        db.insert("alice", "mydomain.com", &params).unwrap();
+        let hash = "aa".repeat(32);
+        db.insert_invoice(&InvoiceRecord {
+            address: "alice@mydomain.com".to_owned(),
+            backend: "LNBits".to_owned(),
+            backend_id: Some(params.invoice_api.identity()),
+            ..helpers::invoice(&hash)
+        })
+        .unwrap();
+
+        for _ in 0..3 {
+            let resp = verify_json(&db, "alice", &hash).await;
+            assert_eq!(resp["status"], "OK");
+            assert_eq!(resp["settled"], false);
+        }
+        let stored = db.get_invoice(&hash).unwrap().unwrap();
+        assert!(stored.checked_at.is_some());
+    }
+
+    #[test]
+    fn payer_data_is_checked_against_the_request() {
+        let request = PayerDataRequest {
+            name: Some(PayerField { mandatory: true }),
+            email: Some(PayerField { mandatory: false }),
+            ..Default::default()
+        };
+        let raw = r#"{"name":"Bob","email":"bob@example.com"}"#.to_string();
+        let data = parse_payer_data(&request, Some(&raw)).unwrap().unwrap();
+        assert_eq!(data.name.as_deref(), Some("Bob"));
+        assert_eq!(data.email.as_deref(), Some("bob@example.com"));
+
+        // mandatory name missing
+        assert!(parse_payer_data(&request, None).is_err());
+        let raw = r#"{"email":"bob@example.com"}"#.to_string();
+        assert!(parse_payer_data(&request, Some(&raw)).is_err());
+        // not requested
+        let raw = r#"{"name":"Bob","pubkey":"02ab"}"#.to_string();
+        assert!(parse_payer_data(&request, Some(&raw)).is_err());
+        // invalid email
+        let raw = r#"{"name":"Bob","email":"nope"}"#.to_string();
+        assert!(parse_payer_data(&request, Some(&raw)).is_err());
+
+        let optional = PayerDataRequest {
+            identifier: Some(PayerField { mandatory: false }),
+            ..Default::default()
+        };
+        assert_eq!(parse_payer_data(&optional, None).unwrap(), None);
+    }
+
+    #[test]
+    fn payer_data_form_selection() {
+        let form = HashMap::from([
+            ("name".to_owned(), "mandatory".to_owned()),
+            ("email".to_owned(), "optional".to_owned()),
+            ("pubkey".to_owned(), "".to_owned()),
+        ]);
+        let request = payer_data_from_form(&form).unwrap();
+        assert_eq!(request.name, Some(PayerField { mandatory: true }));
+        assert_eq!(request.email, Some(PayerField { mandatory: false }));
+        assert_eq!(request.pubkey, None);
+        assert_eq!(request.identifier, None);
+
+        assert!(payer_data_from_form(&HashMap::new()).is_none());
     }
 }
@@ -5,7 +5,7 @@ use serde_json::{json, Value};
 use url::Url;
 use warp::hyper::{self, Body, Client, Method, Request, Uri};
 
-use crate::LNbitsConfig;
+use crate::{db::models::KeysendParams, LNbitsConfig};
 
 pub async fn provision_backend(
     conf: &LNbitsConfig,
@@ -87,6 +87,46 @@ pub async fn provision_backend(
     ))
 }
 
+/// Removes what `provision_backend` created for the entry:
+/// the scrub link and the LNbits user (along with its wallet).
+/// Elements that are already gone are skipped.
+pub async fn deprovision_backend(conf: &LNbitsConfig, params: &KeysendParams) -> Result<()> {
+    if let Some(ref admin_key) = params.admin_key {
+        let api = ScrubApi {
+            host: conf.url.clone(),
+            api_key: admin_key.to_string(),
+            wallet_id: params.wallet_id.clone(),
+        };
+        for scrub in api.list().await? {
+            api.delete(&scrub.id).await?;
+        }
+    }
+
+    if let Some(ref user_id) = params.user_id {
+        let mut url = Url::parse(&format!(
+            "{}usermanager/api/v1/users/{}",
+            &conf.url, user_id
+        ))?;
+        url.query_pairs_mut()
+            .append_pair("delete_core", "true")
+            .finish();
+
+        let req = Request::builder()
+            .method(Method::DELETE)
+            .uri(url.as_str())
+            .header("X-Api-Key", &conf.api_key)
+            .body(Body::default())?;
+        let resp = Client::new().request(req).await?;
+
+        let status = resp.status();
+        if !status.is_success() && status != hyper::StatusCode::NOT_FOUND {
+            bail!("unable to delete lnbits user {}: {}", user_id, status);
+        }
+        info!("Deleted lnbits user {}, status: {}", user_id, status);
+    }
+    Ok(())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct ScrubApiEntry {
     id: String,
@@ -194,4 +234,81 @@ impl ScrubApi {
         info!("scrub updated status: {}", resp.status());
         Ok(())
     }
+    async fn delete(&self, scrub_id: &str) -> Result<()> {
+        let client = Client::new();
+        let req = Request::builder()
+            .method(Method::DELETE)
+            .uri(format!("{}scrub/api/v1/links/{}", self.host, scrub_id))
+            .header("X-Api-Key", self.api_key.to_string())
+            .body(Body::default())?;
+        let resp = client.request(req).await?;
+
+        let status = resp.status();
+        if !status.is_success() && status != hyper::StatusCode::NOT_FOUND {
+            bail!("unable to delete scrub {}: {}", scrub_id, status);
+        }
+        info!("scrub {} deleted status: {}", scrub_id, status);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{
+        matchers::{header, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{db::models::KeysendParams, LNbitsConfig};
+
+    use super::deprovision_backend;
+
+    #[tokio::test]
+    async fn deprovision_removes_scrub_and_user() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/scrub/api/v1/links"))
+            .and(header("X-Api-Key", "user-admin-key"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!([{
+                    "id": "scrub-1",
+                    "description": "Payment via domain.com",
+                    "wallet": "wallet-1",
+                    "payoraddress": "pubkey",
+                    "deduct_fee": true,
+                }])),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/scrub/api/v1/links/scrub-1"))
+            .and(header("X-Api-Key", "user-admin-key"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        // user was already removed by hand
+        Mock::given(method("DELETE"))
+            .and(path("/usermanager/api/v1/users/user-1"))
+            .and(query_param("delete_core", "true"))
+            .and(header("X-Api-Key", "api-key"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let conf = LNbitsConfig {
+            url: format!("{}/", mock_server.uri()).parse().unwrap(),
+            api_key: "api-key".to_string(),
+            admin_id: "admin".to_string(),
+        };
+        let params = KeysendParams {
+            pub_key: "pubkey".to_string(),
+            user_id: Some("user-1".to_string()),
+            wallet_id: Some("wallet-1".to_string()),
+            admin_key: Some("user-admin-key".to_string()),
+        };
+        deprovision_backend(&conf, &params).await.unwrap();
+    }
 }
@@ -5,21 +5,42 @@
 //!
 //! Now with **keysend** support!
 
-use std::net::IpAddr;
+use std::{
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use envconfig::Envconfig;
 use warp::{hyper::Uri, Filter};
 
 /// REST API responsible for admin tasks
 pub mod api;
+/// Admin API authentication
+pub mod auth;
+/// Checks of avatar images of addresses
+pub mod avatar;
 /// Abstraction over an embedded database
 pub mod db;
+/// Fiat currencies of addresses and their exchange rates
+pub mod fiat;
 /// Main web and api application handlers
 pub mod handlers;
 /// Keysend backend helpers (based on LNbits)
 pub mod keysend;
 /// Lightning network helpers and structures
 pub mod ln;
+/// LNURL (LUD-01) encoding and QR codes of addresses
+pub mod lnurl;
+/// Local admin channel (Unix socket) used by the `cli`
+pub mod local;
+/// Nostr zap requests and receipts
+pub mod nostr;
+/// Per-address PIN generation and verification
+pub mod pin;
+/// Tracking of payments of issued invoices
+pub mod settlement;
+/// Outgoing webhooks about invoices of addresses
+pub mod webhook;
 
 /// Structure definining possible params and their structure
 /// used in order to configure the server
@@ -34,14 +55,110 @@ pub struct Config {
     #[envconfig(default = "admin,root,berni")]
     pub reserved_names: CsvVec,
     pub pin_secret: String,
+    /// Secrets used before `pin_secret` was rotated. Legacy PINs derived
+    /// from them are still accepted until `pin_secret_grace_until`.
+    #[envconfig(from = "PIN_SECRET_PREVIOUS")]
+    pub pin_secret_previous: Option<CsvVec>,
+    /// Unix timestamp after which previous secrets are no longer accepted
+    #[envconfig(from = "PIN_SECRET_GRACE_UNTIL")]
+    pub pin_secret_grace_until: Option<u64>,
 
     pub site_name: String,
     pub site_sub_name: String,
+    /// Domains whose addresses are emails too, their metadata
+    /// carries `text/email` instead of `text/identifier` (LUD-16)
+    #[envconfig(from = "EMAIL_IDENTIFIER_DOMAINS")]
+    pub email_identifier_domains: Option<CsvVec>,
     #[envconfig(default = "socks5://127.0.0.1:9050")]
     pub tor_proxy_url: Uri,
+    /// How often (in seconds) pending invoices are checked
+    /// against the backends, 0 disables the checks
+    #[envconfig(from = "SETTLEMENT_INTERVAL", default = "60")]
+    pub settlement_interval: u64,
+    /// How often (in seconds) queued webhooks are delivered,
+    /// 0 disables the delivery
+    #[envconfig(from = "WEBHOOK_INTERVAL", default = "10")]
+    pub webhook_interval: u64,
+    /// Accept webhook urls that aren't https or point at loopback,
+    /// link-local or private addresses, e.g. for receivers on the LAN.
+    /// Zap receipts are then published to relays at such addresses too.
+    #[envconfig(from = "WEBHOOK_ALLOW_LOCAL", default = "false")]
+    pub webhook_allow_local: bool,
+    /// Lowest payment (in msat) addresses can ask for
+    #[envconfig(from = "MIN_SENDABLE", default = "1000")]
+    pub min_sendable: u64,
+    /// Highest payment (in msat) addresses can ask for
+    #[envconfig(from = "MAX_SENDABLE", default = "1000000000")]
+    pub max_sendable: u64,
+    /// Nostr keys signing zap receipts, `domain=<hex secret key>` pairs,
+    /// zaps are accepted only on the listed domains
+    #[envconfig(from = "NOSTR_KEYS")]
+    pub nostr_keys: Option<nostr::Keys>,
+    /// How often (in seconds) queued zap receipts are published,
+    /// 0 disables the publication
+    #[envconfig(from = "NOSTR_INTERVAL", default = "10")]
+    pub nostr_interval: u64,
+    /// Provider of the exchange rates of fiat currencies (see `fiat::RateSource`),
+    /// fiat amounts aren't accepted unless set
+    #[envconfig(from = "FIAT_RATES")]
+    pub fiat_rates: Option<fiat::RateSource>,
+    /// How long (in seconds) fetched exchange rates are used
+    #[envconfig(from = "FIAT_RATES_TTL", default = "300")]
+    pub fiat_rates_ttl: u64,
+    /// Server secret encrypting the signing keys of admin API tokens,
+    /// tokens can't sign requests unless it's set (see `auth`)
+    #[envconfig(from = "TOKEN_SECRET")]
+    pub token_secret: Option<String>,
+    /// Unix socket the `cli` uses to talk to the running server
+    #[envconfig(from = "ADMIN_SOCKET", default = "sataddress.sock")]
+    pub admin_socket: String,
 
     #[envconfig(nested = true)]
     pub lnbits: LNbitsConfig,
+    #[envconfig(nested = true)]
+    pub db: DbConfig,
+}
+
+impl Config {
+    pub fn is_email_identifier_domain(&self, domain: &str) -> bool {
+        match self.email_identifier_domains {
+            Some(ref domains) => domains.contains(&domain.to_owned()),
+            None => false,
+        }
+    }
+
+    /// Server-wide bounds of the payment limits of addresses
+    pub fn sendable_bounds(&self) -> (u64, u64) {
+        (self.min_sendable, self.max_sendable)
+    }
+
+    /// Key signing zap receipts of the domain, if zaps are enabled there
+    pub fn nostr_key(&self, domain: &str) -> Option<&k256::schnorr::SigningKey> {
+        self.nostr_keys.as_ref().and_then(|keys| keys.get(domain))
+    }
+
+    /// Secrets that legacy PINs are checked against: the current one
+    /// followed by the previous ones (only during the grace window).
+    pub fn pin_secrets(&self) -> Vec<&str> {
+        let mut secrets = vec![self.pin_secret.as_str()];
+        let in_grace = match self.pin_secret_grace_until {
+            Some(until) => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_secs() <= until)
+                .unwrap_or(false),
+            None => true,
+        };
+        if let (Some(previous), true) = (&self.pin_secret_previous, in_grace) {
+            secrets.extend(
+                previous
+                    .0
+                    .iter()
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.as_str()),
+            );
+        }
+        secrets
+    }
 }
 
 #[derive(Envconfig, Debug, Clone)]
@@ -54,6 +171,30 @@ pub struct LNbitsConfig {
     pub admin_id: String,
 }
 
+/// Database location and `sled` tuning
+#[derive(Envconfig, Debug, Clone)]
+pub struct DbConfig {
+    #[envconfig(from = "DB_PATH", default = "sataddress.db")]
+    pub path: String,
+    /// size of the page cache in bytes
+    #[envconfig(from = "DB_CACHE_CAPACITY", default = "1073741824")]
+    pub cache_capacity: u64,
+    /// how often dirty data is flushed to disk, 0 disables periodic flushes
+    #[envconfig(from = "DB_FLUSH_EVERY_MS", default = "500")]
+    pub flush_every_ms: u64,
+    /// zstd compression of the stored data
+    #[envconfig(from = "DB_COMPRESSION", default = "false")]
+    pub compression: bool,
+    /// zstd compression level (1-22)
+    #[envconfig(from = "DB_COMPRESSION_FACTOR", default = "5")]
+    pub compression_factor: i32,
+    /// Keep address records in SQLite database at this path
+    /// (requires the `sqlite` feature), `sled` is used otherwise
+    /// and always for the rest of the data
+    #[envconfig(from = "SQLITE_PATH")]
+    pub sqlite_path: Option<String>,
+}
+
 /// Represents a comma delimited input for the CLI
 /// and converts it into a vector of strings.
 #[derive(Debug, Clone)]
@@ -96,9 +237,11 @@ pub fn with_clone<C: Clone + Send>(
 
 #[cfg(test)]
 mod tests {
-    use std::str::FromStr;
+    use std::{collections::HashMap, str::FromStr};
+
+    use envconfig::Envconfig;
 
-    use super::{with_clone, CsvVec};
+    use super::{with_clone, Config, CsvVec};
 
     #[tokio::test]
     async fn with_clone_returns_wrapped_clone() {
@@ -121,4 +264,33 @@ mod tests {
         let v: Vec<String> = cv.into();
         assert_eq!(v, vec!["elem"]);
     }
+
+    fn config_with_rotation(grace_until: Option<&str>) -> Config {
+        let mut hm = HashMap::from([
+            ("DOMAINS".to_owned(), "mydomain.com".to_owned()),
+            ("PIN_SECRET".to_owned(), "new-secret".to_owned()),
+            ("PIN_SECRET_PREVIOUS".to_owned(), "old-1,old-2".to_owned()),
+            ("SITE_NAME".to_owned(), "my-site".to_owned()),
+            ("SITE_SUB_NAME".to_owned(), "my-com".to_owned()),
+            ("LNBITS_URL".to_owned(), "http://127.0.0.1:5000/".to_owned()),
+            ("LNBITS_API_KEY".to_owned(), "lnbits-key".to_owned()),
+            ("LNBITS_ADMIN_ID".to_owned(), "lnbits-admin".to_owned()),
+        ]);
+        if let Some(until) = grace_until {
+            hm.insert("PIN_SECRET_GRACE_UNTIL".to_owned(), until.to_owned());
+        }
+        Config::init_from_hashmap(&hm).unwrap()
+    }
+
+    #[test]
+    fn pin_secrets_include_previous_during_grace_window() {
+        let config = config_with_rotation(None);
+        assert_eq!(config.pin_secrets(), vec!["new-secret", "old-1", "old-2"]);
+
+        let config = config_with_rotation(Some("99999999999"));
+        assert_eq!(config.pin_secrets(), vec!["new-secret", "old-1", "old-2"]);
+
+        let config = config_with_rotation(Some("1"));
+        assert_eq!(config.pin_secrets(), vec!["new-secret"]);
+    }
 }
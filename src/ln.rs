@@ -1,5 +1,10 @@
+use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
 use serde::{Deserialize, Serialize};
 
+use crate::db::models::{PayerDataRequest, SuccessActionConfig};
+
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+
 /// Defines a structure that we use to generate
 /// JSON response for LN URL
 #[derive(Deserialize, Serialize, Default)]
@@ -14,6 +19,61 @@ pub struct SuccessAction {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
     pub message: Option<String>,
+    /// base64 encoded AES-256-CBC ciphertext (LUD-10)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub ciphertext: Option<String>,
+    /// base64 encoded initialization vector of the ciphertext
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub iv: Option<String>,
+}
+
+impl SuccessAction {
+    /// Generic message shown when the address doesn't set any action
+    pub fn message(message: &str) -> Self {
+        Self {
+            tag: "message".to_string(),
+            message: Some(message.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Builds the action configured for the address, AES actions
+    /// need the preimage of the invoice which is their key
+    pub fn from_config(
+        config: &SuccessActionConfig,
+        preimage: Option<&[u8; 32]>,
+    ) -> anyhow::Result<Self> {
+        Ok(match config {
+            SuccessActionConfig::Message { message } => Self::message(message),
+            SuccessActionConfig::Url { description, url } => Self {
+                tag: "url".to_string(),
+                description: Some(description.clone()),
+                url: Some(url.clone()),
+                ..Default::default()
+            },
+            SuccessActionConfig::Aes {
+                description,
+                secret,
+            } => {
+                let key = match preimage {
+                    Some(key) => key,
+                    None => anyhow::bail!("preimage required to encrypt the secret"),
+                };
+                let iv: [u8; 16] = rand::random();
+                let ciphertext = Aes256CbcEnc::new(key.into(), &iv.into())
+                    .encrypt_padded_vec_mut::<Pkcs7>(secret.as_bytes());
+                Self {
+                    tag: "aes".to_string(),
+                    description: Some(description.clone()),
+                    ciphertext: Some(base64::encode(ciphertext)),
+                    iv: Some(base64::encode(iv)),
+                    ..Default::default()
+                }
+            }
+        })
+    }
 }
 
 /// Defines a structure that we use to generate
@@ -28,6 +88,20 @@ pub struct LNURLPayValues {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
     pub disposable: Option<bool>,
+    /// LUD-21 url that allows to check if the invoice was paid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub verify: Option<String>,
+}
+
+/// LUD-21 response describing the state of the invoice
+#[derive(Deserialize, Serialize)]
+pub struct LNURLVerify {
+    #[serde(flatten)]
+    pub lnurl_response: LNURLResponse,
+    pub settled: bool,
+    pub preimage: Option<String>,
+    pub pr: String,
 }
 
 /// Defines a structure that we use to generate
@@ -54,6 +128,36 @@ pub struct LNURLPayParams {
     pub min_sendable: u64,
     pub metadata: String,
     pub comment_allowed: u8,
+    /// payer details the address asks for (LUD-18)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub payer_data: Option<PayerDataRequest>,
+    /// zap requests (NIP-57) are accepted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub allows_nostr: Option<bool>,
+    /// hex encoded key signing the zap receipts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub nostr_pubkey: Option<String>,
+    /// fiat currencies the amount can be sent in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub currencies: Option<Vec<PayCurrency>>,
+}
+
+/// Currency accepted by the callback (`amount=<smallest units>.<code>`)
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PayCurrency {
+    pub code: String,
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+    /// msat worth a single smallest unit of the currency
+    pub multiplier: f64,
+    /// the amount is converted to msat by the server
+    pub convertible: bool,
 }
 
 /// BTC banner shown in wallets payment modals
@@ -61,7 +165,11 @@ const BTC_LN_IMG: &[u8] = include_bytes!("../assets/inv_banner.png");
 
 /// Invoice generation and interaction logic
 pub mod invoice {
-    use std::time::Duration;
+    use std::{
+        collections::HashMap,
+        sync::Mutex,
+        time::{Duration, Instant, SystemTime},
+    };
 
     use anyhow::bail;
     use log::debug;
@@ -73,7 +181,10 @@ pub mod invoice {
 
     use warp::hyper::{self, service::Service, Body, Client, Method, Request, Uri};
 
-    use crate::db::models::{self, InvoiceAPI};
+    use crate::db::{
+        defaults,
+        models::{self, AddressMetadata, InvoiceAPI, InvoiceRecord, InvoiceStatus},
+    };
     use base64;
     use hyper_tls::{HttpsConnecting, HttpsConnector, MaybeHttpsStream};
 
@@ -83,21 +194,83 @@ pub mod invoice {
 
     use super::BTC_LN_IMG;
 
+    /// Expiry of the generated invoices
+    pub const INVOICE_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+    /// Invoice generated by the backend
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Invoice {
+        /// bolt11 encoded invoice
+        pub payment_request: String,
+        /// hex encoded payment hash
+        pub payment_hash: String,
+        pub expiry: Duration,
+    }
+
+    impl Invoice {
+        /// Ledger entry of the invoice issued for the address
+        pub fn to_record(
+            &self,
+            params: &models::Params,
+            msat: u64,
+            comment: Option<String>,
+        ) -> InvoiceRecord {
+            let created_at = SystemTime::now();
+            InvoiceRecord {
+                payment_hash: self.payment_hash.clone(),
+                payment_request: self.payment_request.clone(),
+                address: format!("{}@{}", params.name, params.domain),
+                msat,
+                comment,
+                backend: params.invoice_api.to_string(),
+                backend_id: Some(params.invoice_api.identity()),
+                checked_at: None,
+                created_at,
+                expires_at: created_at + self.expiry,
+                status: InvoiceStatus::Pending,
+                msat_received: None,
+                settled_at: None,
+                preimage: None,
+                payer_data: None,
+                zap_request: None,
+            }
+        }
+    }
+
     /// Used to generate descriptions and information
     /// about the payments
     pub struct Metadata {
         name: String,
         domain: String,
+        /// custom parts set by the owner
+        custom: AddressMetadata,
+        /// address is also an email (`text/email`),
+        /// not just an identifier (LUD-16)
+        email: bool,
     }
 
     impl Metadata {
+        /// Marks the address as an email one
+        pub fn with_email_identifier(mut self, email: bool) -> Self {
+            self.email = email;
+            self
+        }
+        /// Identifies the recipient by the name used in the request
+        /// (e.g. the one of a pay link) instead of the address one
+        pub fn with_name(mut self, name: &str) -> Self {
+            self.name = name.to_owned();
+            self
+        }
         // Recipient of the payment
         fn for_whom(&self) -> String {
             format!("{}@{}", self.name, self.domain)
         }
         // Description of the payment - often used as memo
         fn get_text(&self) -> String {
-            format!("Satoshis for {}.", &self.for_whom())
+            match self.custom.text {
+                Some(ref text) => text.clone(),
+                None => format!("Satoshis for {}.", &self.for_whom()),
+            }
         }
     }
 
@@ -106,17 +279,30 @@ pub mod invoice {
             Self {
                 name: params.name,
                 domain: params.domain,
+                custom: params.metadata.unwrap_or_default(),
+                email: false,
             }
         }
     }
 
     impl From<&Metadata> for serde_json::Value {
         fn from(m: &Metadata) -> Self {
-            serde_json::json!([
-                ["text/identifier", m.for_whom()],
-                ["text/plain", m.get_text()],
-                ["image/png;base64", base64::encode(BTC_LN_IMG)],
-            ])
+            let identifier = match m.email {
+                true => "text/email",
+                false => "text/identifier",
+            };
+            let mut entries = vec![
+                json!([identifier, m.for_whom()]),
+                json!(["text/plain", m.get_text()]),
+            ];
+            if let Some(ref long_desc) = m.custom.long_desc {
+                entries.push(json!(["text/long-desc", long_desc]));
+            }
+            entries.push(match m.custom.image {
+                Some(ref image) => json!([format!("image/{};base64", image.format), image.data]),
+                None => json!(["image/png;base64", base64::encode(BTC_LN_IMG)]),
+            });
+            Value::Array(entries)
         }
     }
 
@@ -167,15 +353,11 @@ pub mod invoice {
         }
     }
 
-    /// Connects to defined IncoiceAPI defined in Params in
-    /// order to create an invoice based on the input data.
-    pub async fn make_invoice(
-        params: &models::Params,
-        ln_host: &Uri,
-        msat: u64,
-        tor_proxy: Uri,
-        memo: Option<String>,
-    ) -> Result<Value, anyhow::Error> {
+    type BackendClient = Client<MaybeProxiedConnector<hyper::client::HttpConnector>>;
+
+    /// Creates a client able to talk to the backend of the entry
+    /// (via the tor proxy for onion hosts)
+    fn backend_client(params: &models::Params, tor_proxy: Uri) -> anyhow::Result<BackendClient> {
         // enforce https
         let mut http = hyper::client::HttpConnector::new();
         http.enforce_http(false);
@@ -199,10 +381,157 @@ pub mod invoice {
             }
         };
 
-        let client = Client::builder().build::<_, hyper::Body>(https);
+        Ok(Client::builder().build::<_, hyper::Body>(https))
+    }
+
+    /// Converts the macaroon into a hex string, as required by LND.
+    /// Macaroons are usually delivered base64 encoded.
+    fn macaroon_hex(macaroon: &str) -> String {
+        match base64::decode(macaroon) {
+            Ok(decoded) => hex::encode(decoded),
+            Err(_) => macaroon.to_string(),
+        }
+    }
+
+    /// Sends the request to the backend and parses its JSON response
+    async fn call_backend(client: &BackendClient, req: Request<Body>) -> anyhow::Result<Value> {
+        let future = client.request(req);
+        let resp = match timeout(Duration::from_secs(180), future).await {
+            Ok(r) => r?,
+            Err(_e) => bail!("Connection timeout error"),
+        };
+
+        let status = resp.status().as_u16();
+
+        let bytes = hyper::body::to_bytes(resp).await?;
+        let mut data = String::from_utf8(bytes.to_vec())?;
+
+        if status >= 300 {
+            data.truncate(300);
+            bail!("Call to lnd failed ({}): {}", status, data)
+        }
+
+        match serde_json::from_str(&data) {
+            Ok(json) => Ok(json),
+            Err(e) => {
+                data.truncate(500);
+                debug!(
+                    "Unable to parse json response the LN Node err: {:?}, data: {:?}",
+                    e, data
+                );
+                bail!("Unable to parse json response from the LN Node");
+            }
+        }
+    }
+
+    /// LNbits version as `(major, minor, patch)`
+    type LNbitsVersion = (u32, u32, u32);
+
+    /// First LNbits version accepting `unhashed_description`
+    const LNBITS_UNHASHED_DESCRIPTION: LNbitsVersion = (0, 9, 0);
+
+    /// How long detected LNbits versions are remembered
+    const LNBITS_VERSION_TTL: Duration = Duration::from_secs(60 * 60);
+
+    type VersionCache = HashMap<String, (Option<LNbitsVersion>, Instant)>;
+
+    /// Detected versions of LNbits instances by their url,
+    /// `None` when the version couldn't be detected
+    static LNBITS_VERSIONS: Mutex<Option<VersionCache>> = Mutex::new(None);
+
+    /// Parses versions like `0.9.4` or `0.12.0rc1`
+    fn parse_version(version: &str) -> Option<LNbitsVersion> {
+        let mut parts = version.trim_start_matches('v').split('.').map(|part| {
+            part.chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect::<String>()
+                .parse::<u32>()
+                .ok()
+        });
+        let major = parts.next()??;
+        let minor = parts.next()??;
+        let patch = parts.next().flatten().unwrap_or_default();
+        Some((major, minor, patch))
+    }
+
+    /// Asks the LNbits instance about its version, which it publishes
+    /// in the OpenAPI schema. Results are cached for a while.
+    async fn lnbits_version(client: &BackendClient, host: &str) -> Option<LNbitsVersion> {
+        let host = host.trim_end_matches('/');
+        let now = Instant::now();
+        if let Some(versions) = LNBITS_VERSIONS.lock().unwrap().as_ref() {
+            match versions.get(host) {
+                Some((version, at)) if now.duration_since(*at) < LNBITS_VERSION_TTL => {
+                    return *version
+                }
+                _ => {}
+            }
+        }
+
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri(format!("{}/openapi.json", host))
+            .body(Body::empty())
+            .ok()?;
+        let version = match call_backend(client, req).await {
+            Ok(v) => v["info"]["version"].as_str().and_then(parse_version),
+            Err(e) => {
+                debug!("Unable to detect LNbits version of {}: {}", host, e);
+                None
+            }
+        };
+        LNBITS_VERSIONS
+            .lock()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .insert(host.to_owned(), (version, now));
+        version
+    }
+
+    /// Describes the invoice requested from LNbits. Versions before 0.9
+    /// only accept the description hash, newer ones take the description
+    /// itself (`unhashed_description`) and hash it. The memo (payer comment)
+    /// always goes along, versions that ignore it when the description is
+    /// set still have it recorded in the invoice ledger.
+    fn lnbits_description(
+        body: &mut Value,
+        version: Option<LNbitsVersion>,
+        description: &str,
+        memo: String,
+    ) {
+        match version {
+            Some(version) if version < LNBITS_UNHASHED_DESCRIPTION => {
+                body["description_hash"] = Value::String(hex::encode(Sha256::digest(description)));
+            }
+            // unknown version, assume a recent one
+            _ => {
+                body["unhashed_description"] = Value::String(hex::encode(description));
+            }
+        }
+        body["memo"] = Value::String(memo);
+    }
+
+    /// Connects to defined IncoiceAPI defined in Params in
+    /// order to create an invoice based on the input data.
+    pub async fn make_invoice(
+        params: &models::Params,
+        ln_host: &Uri,
+        msat: u64,
+        tor_proxy: Uri,
+        memo: Option<String>,
+        description: Option<String>,
+        preimage: Option<[u8; 32]>,
+    ) -> Result<Invoice, anyhow::Error> {
+        if preimage.is_some() && !params.invoice_api.supports_preimage() {
+            bail!("backend does not support custom preimages");
+        }
+        let client = backend_client(params, tor_proxy)?;
 
         let metadata = Metadata::from(params.clone());
-        let metadata_sha = Sha256::new().chain_update(metadata.to_string()).finalize();
+        // what the invoice commits to, the metadata unless extended by the caller
+        let description = description.unwrap_or_else(|| metadata.to_string());
+        let description_hash = Sha256::digest(&description);
+        let memo = memo.unwrap_or_else(|| metadata.get_text());
         let req: warp::http::request::Builder;
         let mut body: Value;
 
@@ -210,61 +539,34 @@ pub mod invoice {
             InvoiceAPI::Lnd(p) => {
                 body = json!({
                     "value_msat": msat,
+                    "expiry": INVOICE_EXPIRY.as_secs(),
                     // "out": false,
                     // again, either memo (to just put invoice in wallet) or desc_hash if actual transiation
                     // "memo": metadata.get_text(),
                     // "description_hash": base64::encode(metadata_sha),
                 });
 
-                body["memo"] = match memo {
-                    Some(memo) => serde_json::Value::String(memo),
-                    None => serde_json::Value::String(metadata.get_text()),
-                };
-                body["description_hash"] = serde_json::Value::String(base64::encode(metadata_sha));
-
-                let mut macaroon = p.macaroon.to_string();
-                // macaroon needs to be a hex string
-                // convert from base64 as that's how it's usually delivered
-                if let Ok(decoded) = base64::decode(&macaroon) {
-                    macaroon = hex::encode(decoded);
+                body["memo"] = serde_json::Value::String(memo);
+                body["description_hash"] =
+                    serde_json::Value::String(base64::encode(description_hash));
+                if let Some(preimage) = preimage {
+                    body["r_preimage"] = serde_json::Value::String(base64::encode(preimage));
                 }
 
                 req = Request::builder()
                     .method(Method::POST)
                     .uri(format!("{}/v1/invoices", p.host))
-                    .header("Grpc-Metadata-macaroon", macaroon)
+                    .header("Grpc-Metadata-macaroon", macaroon_hex(&p.macaroon))
                     .header("content-type", "application/json");
             }
             InvoiceAPI::LNBits(p) => {
                 body = json!({
                     "amount": msat / 1000,
                     "out": false,
-                    // "description": "satoshis incoming from SatAddress",
-                    // "comment": "this is a comment",
-                    // "memo": "this is a memo",
-                    // for lnbits 0.8.0 support (umbrel)
-                    // you need description_hash since unhashed_desc is not supported yet
-                    // but then it stops working with latest ;) huh
-                    // "description_hash": hex::encode(metadata_sha),
-                    // "unhashed_description": hex::encode(metadata.to_string()),
+                    "expiry": INVOICE_EXPIRY.as_secs(),
                 });
-
-                // memo is ignored, see code links above
-                match memo {
-                    Some(memo) => {
-                        body["memo"] = serde_json::Value::String(memo);
-                    }
-                    None => {
-                        // TODO PR for LNBits
-                        // https://github.com/lnbits/lnbits/blob/4ad3c841528de3efafefe48f667e6800eb7074e3/lnbits/core/services.py#L65
-                        //
-                        body["unhashed_description"] =
-                            serde_json::Value::String(hex::encode(metadata.to_string()));
-                    }
-                }
-
-                body["unhashed_description"] =
-                    serde_json::Value::String(hex::encode(metadata.to_string()));
+                let version = lnbits_version(&client, &p.host).await;
+                lnbits_description(&mut body, version, &description, memo);
 
                 debug!(
                     "Sending body {:?} to {:?} with key {:?}",
@@ -281,88 +583,151 @@ pub mod invoice {
                 // reject payments lower than 3 sats
                 // as those probably won't cover payment fees
                 // and transaction will get stuck :-(
-                if msat < 3000 {
+                if msat < defaults::KEYSEND_MIN_SENDABLE {
                     bail!("less than 3sats might not cover routing fees")
                 }
 
                 body = json!({
                     "amount": msat / 1000,
                     "out": false,
+                    "expiry": INVOICE_EXPIRY.as_secs(),
                 });
+                let version = lnbits_version(&client, &ln_host.to_string()).await;
+                lnbits_description(&mut body, version, &description, memo);
 
-                // memo is ignored, see code links above
-                match memo {
-                    Some(memo) => {
-                        body["memo"] = serde_json::Value::String(memo);
-                    }
-                    None => {
-                        body["unhashed_description"] =
-                            serde_json::Value::String(hex::encode(metadata.to_string()));
-                    }
-                }
-
-                body["unhashed_description"] =
-                    serde_json::Value::String(hex::encode(metadata.to_string()));
-
+                let admin_key = match p.admin_key {
+                    Some(key) => key,
+                    None => bail!("keysend backend is not provisioned"),
+                };
                 req = Request::builder()
                     .method(Method::POST)
                     .uri(format!("{}api/v1/payments", ln_host))
-                    .header("X-Api-Key", p.admin_key.unwrap())
+                    .header("X-Api-Key", admin_key)
                     .header("content-type", "application/json");
             }
         }
 
         let req = req.body(Body::from(body.to_string()))?;
-        let future = client.request(req);
-        let resp = match timeout(Duration::from_secs(180), future).await {
-            Ok(r) => r?,
-            Err(_e) => bail!("Connection timeout error"),
+        let v = call_backend(&client, req).await?;
+
+        let payment_request = match v["payment_request"].as_str() {
+            Some(pr) => pr.to_owned(),
+            None => bail!("No payment request in the response from the LN Node"),
+        };
+        let payment_hash = match (&v["payment_hash"], &v["r_hash"]) {
+            // lnbits returns hex encoded hash
+            (Value::String(hash), _) => hash.to_owned(),
+            // lnd returns base64 encoded bytes
+            (_, Value::String(hash)) => hex::encode(base64::decode(hash)?),
+            _ => bail!("No payment hash in the response from the LN Node"),
         };
 
-        let status = resp.status().as_u16();
+        debug!(
+            "Invoice generated [{:?}] for {} msat, inv: {}, hash: {}",
+            params.invoice_api, msat, payment_request, payment_hash
+        );
 
-        let bytes = hyper::body::to_bytes(resp).await?;
-        let mut data = String::from_utf8(bytes.to_vec())?;
+        Ok(Invoice {
+            payment_request,
+            payment_hash,
+            expiry: INVOICE_EXPIRY,
+        })
+    }
 
-        if status >= 300 {
-            data.truncate(300);
-            bail!("Call to lnd failed ({}): {}", status, data)
-        }
+    /// State of an invoice as reported by the backend
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum Settlement {
+        /// not paid (yet)
+        Open,
+        /// paid, along with the amount received and
+        /// the (hex encoded) preimage if the backend reveals it
+        Settled { msat: u64, preimage: Option<String> },
+        /// canceled, it can't be paid anymore
+        Canceled,
+    }
 
-        let v: Value = match serde_json::from_str(&data) {
-            Ok(json) => json,
-            Err(e) => {
-                data.truncate(500);
-                debug!(
-                    "Unable to parse json response the LN Node err: {:?}, data: {:?}",
-                    e, data
-                );
-                bail!("Unable to parse json response from the LN Node");
-            }
-        };
+    /// Reads an integer that LND encodes as a string
+    fn as_u64(value: &Value) -> u64 {
+        value
+            .as_u64()
+            .or_else(|| value.as_str().and_then(|s| s.parse().ok()))
+            .unwrap_or_default()
+    }
 
-        debug!(
-            "Invoice generated [{:?}] for {} msat, inv: {}",
-            params.invoice_api,
-            msat,
-            v["payment_request"].clone()
-        );
+    /// Asks the backend of the entry about the state of the invoice
+    /// with given (hex encoded) payment hash.
+    pub async fn check_invoice(
+        params: &models::Params,
+        ln_host: &Uri,
+        tor_proxy: Uri,
+        payment_hash: &str,
+    ) -> Result<Settlement, anyhow::Error> {
+        let client = backend_client(params, tor_proxy)?;
+        let req = match params.invoice_api {
+            InvoiceAPI::Lnd(ref p) => Request::builder()
+                .method(Method::GET)
+                .uri(format!("{}/v1/invoice/{}", p.host, payment_hash))
+                .header("Grpc-Metadata-macaroon", macaroon_hex(&p.macaroon)),
+            InvoiceAPI::LNBits(ref p) => Request::builder()
+                .method(Method::GET)
+                .uri(format!("{}/api/v1/payments/{}", p.host, payment_hash))
+                .header("X-Api-Key", &p.key),
+            InvoiceAPI::Keysend(ref p) => Request::builder()
+                .method(Method::GET)
+                .uri(format!("{}api/v1/payments/{}", ln_host, payment_hash))
+                .header("X-Api-Key", p.admin_key.clone().unwrap_or_default()),
+        };
+        let v = call_backend(&client, req.body(Body::empty())?).await?;
 
-        Ok(v["payment_request"].clone())
+        let settlement = match params.invoice_api {
+            InvoiceAPI::Lnd(_) => match v["state"].as_str() {
+                Some("SETTLED") => Settlement::Settled {
+                    msat: as_u64(&v["amt_paid_msat"]),
+                    preimage: v["r_preimage"]
+                        .as_str()
+                        .and_then(|p| base64::decode(p).ok())
+                        .map(hex::encode),
+                },
+                Some("CANCELED") => Settlement::Canceled,
+                _ => Settlement::Open,
+            },
+            // lnbits reports amounts in msat
+            InvoiceAPI::LNBits(_) | InvoiceAPI::Keysend(_) => match v["paid"].as_bool() {
+                Some(true) => Settlement::Settled {
+                    msat: v["details"]["amount"]
+                        .as_i64()
+                        .unwrap_or_default()
+                        .unsigned_abs(),
+                    preimage: v["preimage"].as_str().map(|p| p.to_owned()),
+                },
+                _ => Settlement::Open,
+            },
+        };
+        debug!("Invoice {} state: {:?}", payment_hash, settlement);
+        Ok(settlement)
     }
 
     #[cfg(test)]
     mod tests {
         use serde_json::{json, Value};
+        use sha2::{Digest, Sha256};
         use warp::hyper::Uri;
         use wiremock::{
             http::HeaderName,
-            matchers::{method, path},
+            matchers::{header, method, path},
             Mock, MockServer, ResponseTemplate,
         };
 
-        use super::{make_invoice, Metadata};
-        use crate::db::models::{InvoiceAPI, Params};
+        use super::{
+            check_invoice, make_invoice, parse_version, Metadata, Settlement, LNBITS_VERSIONS,
+        };
+        use crate::{
+            db::models::{
+                AddressMetadata, Image, ImageFormat, InvoiceAPI, LNBitsParams, Params,
+                SuccessActionConfig,
+            },
+            ln::SuccessAction,
+        };
 
         #[test]
         fn metadata_from_params() {
@@ -378,6 +743,36 @@ pub mod invoice {
             assert_eq!(metadata.domain, domain);
         }
 
+        #[test]
+        fn metadata_uses_custom_parts() {
+            let image = base64::encode(crate::avatar::helpers::jpeg(64, 64));
+            let params = Params {
+                name: "user".to_string(),
+                domain: "domain.com".to_string(),
+                metadata: Some(AddressMetadata {
+                    text: Some("Tips for my podcast".to_string()),
+                    long_desc: Some("Weekly episodes about bitcoin".to_string()),
+                    image: Some(Image {
+                        format: ImageFormat::Jpeg,
+                        data: image.clone(),
+                    }),
+                }),
+                ..Default::default()
+            };
+            let metadata: Metadata = params.into();
+            assert_eq!(metadata.get_text(), "Tips for my podcast");
+            let value: Value = serde_json::from_str(&metadata.to_string()).unwrap();
+            assert_eq!(
+                value,
+                json!([
+                    ["text/identifier", "user@domain.com"],
+                    ["text/plain", "Tips for my podcast"],
+                    ["text/long-desc", "Weekly episodes about bitcoin"],
+                    ["image/jpeg;base64", image],
+                ])
+            );
+        }
+
         #[test]
         fn metadata_forms() {
             let name = "aname".to_string();
@@ -385,6 +780,8 @@ pub mod invoice {
             let metadata = Metadata {
                 name: name.clone(),
                 domain: domain.clone(),
+                custom: Default::default(),
+                email: false,
             };
             assert!(metadata.for_whom().contains(&name));
             assert!(metadata.for_whom().contains(&domain));
@@ -400,6 +797,7 @@ pub mod invoice {
             let mock_server = MockServer::start().await;
             let resp = ResponseTemplate::new(200).set_body_json(json!({
                 "payment_request": "abc-payment",
+                "r_hash": base64::encode([0xab; 32]),
             }));
             Mock::given(method("POST"))
                 .and(path("/v1/invoices"))
@@ -424,6 +822,8 @@ pub mod invoice {
                 1000,
                 "http://127.0.0.0.1".parse::<Uri>().unwrap(),
                 Some("memo".to_string()),
+                None,
+                Some([0xef; 32]),
             )
             .await
             .unwrap();
@@ -436,11 +836,225 @@ pub mod invoice {
             assert_eq!(rcv_body["value_msat"].as_i64().unwrap(), 1000);
             assert!(rcv_body["memo"].is_string());
             assert!(rcv_body["description_hash"].is_string());
+            assert_eq!(rcv_body["expiry"].as_u64().unwrap(), 3600);
+            assert_eq!(rcv_body["r_preimage"], base64::encode([0xef; 32]));
             assert!(req
                 .headers
                 .contains_key(&HeaderName::from("grpc-metadata-macaroon")));
             // actual response check
-            assert_eq!(result, "abc-payment");
+            assert_eq!(result.payment_request, "abc-payment");
+            assert_eq!(result.payment_hash, "ab".repeat(32));
+        }
+
+        #[test]
+        fn aes_success_action_decrypts_with_the_preimage() {
+            use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+
+            let config = SuccessActionConfig::Aes {
+                description: "Your code".to_string(),
+                secret: "abc-123".to_string(),
+            };
+            assert!(SuccessAction::from_config(&config, None).is_err());
+
+            let preimage = [7u8; 32];
+            let action = SuccessAction::from_config(&config, Some(&preimage)).unwrap();
+            assert_eq!(action.tag, "aes");
+            let iv = base64::decode(action.iv.unwrap()).unwrap();
+            let ciphertext = base64::decode(action.ciphertext.unwrap()).unwrap();
+            let plaintext = cbc::Decryptor::<aes::Aes256>::new(&preimage.into(), iv[..].into())
+                .decrypt_padded_vec_mut::<Pkcs7>(&ciphertext)
+                .unwrap();
+            assert_eq!(plaintext, b"abc-123");
+        }
+
+        #[test]
+        fn lnbits_versions_are_parsed() {
+            assert_eq!(parse_version("0.9.4"), Some((0, 9, 4)));
+            assert_eq!(parse_version("0.12.0rc1"), Some((0, 12, 0)));
+            assert_eq!(parse_version("v1.0"), Some((1, 0, 0)));
+            assert_eq!(parse_version("unknown"), None);
+        }
+
+        /// Requests an invoice from LNbits reporting given version,
+        /// returns the body of the invoice request
+        async fn lnbits_invoice_body(
+            version: &str,
+            memo: Option<&str>,
+            description: Option<&str>,
+        ) -> Value {
+            // mock servers are pooled, forget versions of the previous ones
+            LNBITS_VERSIONS.lock().unwrap().take();
+            let mock_server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/openapi.json"))
+                .respond_with(
+                    ResponseTemplate::new(200).set_body_json(
+                        json!({"info": {"title": "LNbits API", "version": version}}),
+                    ),
+                )
+                .mount(&mock_server)
+                .await;
+            Mock::given(method("POST"))
+                .and(path("/api/v1/payments"))
+                .respond_with(ResponseTemplate::new(201).set_body_json(json!({
+                    "payment_request": "lnbc-payment",
+                    "payment_hash": "cd".repeat(32),
+                })))
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+
+            let params = Params {
+                name: "user".to_string(),
+                domain: "domain.com".to_string(),
+                invoice_api: InvoiceAPI::LNBits(LNBitsParams {
+                    host: mock_server.uri(),
+                    key: "invoice-key".to_string(),
+                }),
+                ..Default::default()
+            };
+            let uri = "http://127.0.0.0.1".parse::<Uri>().unwrap();
+            let invoice = make_invoice(
+                &params,
+                &uri,
+                5000,
+                uri.clone(),
+                memo.map(|m| m.to_owned()),
+                description.map(|d| d.to_owned()),
+                None,
+            )
+            .await
+            .unwrap();
+            assert_eq!(invoice.payment_hash, "cd".repeat(32));
+
+            let requests = mock_server.received_requests().await.unwrap();
+            requests
+                .iter()
+                .find(|r| r.method == wiremock::http::Method::Post)
+                .unwrap()
+                .body_json::<Value>()
+                .unwrap()
+        }
+
+        #[tokio::test]
+        async fn make_invoice_sends_comments_to_lnbits() {
+            let metadata = Metadata {
+                name: "user".to_string(),
+                domain: "domain.com".to_string(),
+                custom: Default::default(),
+                email: false,
+            };
+
+            let body = lnbits_invoice_body("0.10.3", Some("thanks!"), None).await;
+            assert_eq!(body["memo"], "thanks!");
+            assert_eq!(
+                body["unhashed_description"],
+                hex::encode(metadata.to_string())
+            );
+            assert!(body["description_hash"].is_null());
+
+            // old versions only know the description hash
+            let body = lnbits_invoice_body("0.8.0", Some("thanks!"), None).await;
+            assert_eq!(body["memo"], "thanks!");
+            assert_eq!(
+                body["description_hash"],
+                hex::encode(Sha256::digest(metadata.to_string()))
+            );
+            assert!(body["unhashed_description"].is_null());
+
+            let body = lnbits_invoice_body("0.10.3", None, None).await;
+            assert_eq!(body["memo"], metadata.get_text());
+
+            // extended description, e.g. with payer data
+            let description = format!("{}{}", metadata.to_string(), r#"{"name":"Bob"}"#);
+            let body = lnbits_invoice_body("0.10.3", None, Some(&description)).await;
+            assert_eq!(body["unhashed_description"], hex::encode(&description));
+        }
+
+        #[tokio::test]
+        async fn check_invoice_reads_lnd_state() {
+            let mock_server = MockServer::start().await;
+            let hash = "ab".repeat(32);
+            Mock::given(method("GET"))
+                .and(path(format!("/v1/invoice/{}", hash)))
+                .and(header("grpc-metadata-macaroon", "0102"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "state": "SETTLED",
+                    "settled": true,
+                    "amt_paid_msat": "21000",
+                    "r_preimage": base64::encode([0xcd; 32]),
+                })))
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+
+            let mut params = Params::default();
+            if let InvoiceAPI::Lnd(ref mut p) = params.invoice_api {
+                p.host = mock_server.uri();
+                p.macaroon = base64::encode([1, 2]);
+            }
+            let state = check_invoice(
+                &params,
+                &"http://127.0.0.0.1".parse::<Uri>().unwrap(),
+                "http://127.0.0.0.1".parse::<Uri>().unwrap(),
+                &hash,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                state,
+                Settlement::Settled {
+                    msat: 21000,
+                    preimage: Some("cd".repeat(32)),
+                }
+            );
+        }
+
+        #[tokio::test]
+        async fn check_invoice_reads_lnbits_state() {
+            let mock_server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/api/v1/payments/paid-hash"))
+                .and(header("X-Api-Key", "invoice-key"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "paid": true,
+                    "preimage": "ef".repeat(32),
+                    "details": {"amount": 5000, "pending": false},
+                })))
+                .mount(&mock_server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/api/v1/payments/open-hash"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "paid": false,
+                    "details": {"amount": 5000, "pending": true},
+                })))
+                .mount(&mock_server)
+                .await;
+
+            let params = Params {
+                invoice_api: InvoiceAPI::LNBits(LNBitsParams {
+                    host: mock_server.uri(),
+                    key: "invoice-key".to_string(),
+                }),
+                ..Default::default()
+            };
+            let ln_host = "http://127.0.0.0.1".parse::<Uri>().unwrap();
+            let tor = "http://127.0.0.0.1".parse::<Uri>().unwrap();
+            let state = check_invoice(&params, &ln_host, tor.clone(), "paid-hash")
+                .await
+                .unwrap();
+            assert_eq!(
+                state,
+                Settlement::Settled {
+                    msat: 5000,
+                    preimage: Some("ef".repeat(32)),
+                }
+            );
+            let state = check_invoice(&params, &ln_host, tor, "open-hash")
+                .await
+                .unwrap();
+            assert_eq!(state, Settlement::Open);
         }
     }
 }
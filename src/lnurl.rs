@@ -0,0 +1,117 @@
+//! Bech32 encoded LNURLs (LUD-01) of addresses and their QR codes,
+//! for wallets that can't resolve lightning addresses themselves.
+
+use anyhow::{bail, Result};
+use bech32::{FromBase32, ToBase32, Variant};
+use qrcode::{render::svg, Color, QrCode};
+
+/// Human readable part of the bech32 encoding
+const HRP: &str = "lnurl";
+/// Size (in pixels) of a single QR module of PNG codes
+const MODULE_SIZE: usize = 8;
+/// Light border around the code, in modules
+const QUIET_ZONE: usize = 4;
+
+/// Url serving the pay request of the address (LUD-16)
+pub fn pay_url(domain: &str, username: &str) -> String {
+    format!("https://{}/.well-known/lnurlp/{}", domain, username)
+}
+
+/// Encodes the url as a (lowercase) `lnurl1...` string
+pub fn encode(url: &str) -> Result<String> {
+    Ok(bech32::encode(
+        HRP,
+        url.as_bytes().to_base32(),
+        Variant::Bech32,
+    )?)
+}
+
+/// Decodes the `lnurl1...` string back to the url
+pub fn decode(lnurl: &str) -> Result<String> {
+    let (hrp, data, variant) = bech32::decode(lnurl)?;
+    if hrp != HRP || variant != Variant::Bech32 {
+        bail!("not an lnurl");
+    }
+    Ok(String::from_utf8(Vec::<u8>::from_base32(&data)?)?)
+}
+
+/// QR codes carry the uppercase form, which fits the
+/// denser alphanumeric mode
+fn qr_code(lnurl: &str) -> Result<QrCode> {
+    Ok(QrCode::new(lnurl.to_uppercase())?)
+}
+
+/// Renders the QR code of the lnurl as an SVG image
+pub fn qr_svg(lnurl: &str) -> Result<String> {
+    Ok(qr_code(lnurl)?
+        .render::<svg::Color>()
+        .quiet_zone(true)
+        .min_dimensions(256, 256)
+        .build())
+}
+
+/// Renders the QR code of the lnurl as a (grayscale) PNG image
+pub fn qr_png(lnurl: &str) -> Result<Vec<u8>> {
+    let code = qr_code(lnurl)?;
+    let modules = code.width();
+    let colors = code.to_colors();
+    let size = (modules + 2 * QUIET_ZONE) * MODULE_SIZE;
+
+    let mut pixels = vec![0xff; size * size];
+    for (i, color) in colors.iter().enumerate() {
+        if *color != Color::Dark {
+            continue;
+        }
+        let (x, y) = (i % modules + QUIET_ZONE, i / modules + QUIET_ZONE);
+        for row in y * MODULE_SIZE..(y + 1) * MODULE_SIZE {
+            let start = row * size + x * MODULE_SIZE;
+            pixels[start..start + MODULE_SIZE].fill(0);
+        }
+    }
+
+    let mut png = vec![];
+    let mut encoder = png::Encoder::new(&mut png, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::avatar;
+    use crate::db::models::ImageFormat;
+
+    use super::{decode, encode, pay_url, qr_png, qr_svg};
+
+    #[test]
+    fn lnurls_are_bech32_encoded() {
+        // LUD-01 example
+        let url = "https://service.com/api?q=3fc3645b439ce8e7f2553a69e5267081d96dcd340693afabe04be7b0ccd178df";
+        let lnurl = "lnurl1dp68gurn8ghj7um9wfmxjcm99e3k7mf0v9cxj0m385ekvcenxc6r2c35xvukxefcv5mkvv34x5ekzd3ev56nyd3hxqurzepexejxxepnxscrvwfnv9nxzcn9xq6xyefhvgcxxcmyxymnserxfq5fns";
+        assert_eq!(encode(url).unwrap(), lnurl);
+        assert_eq!(decode(lnurl).unwrap(), url);
+        assert_eq!(decode(&lnurl.to_uppercase()).unwrap(), url);
+
+        let url = pay_url("sataddress.rs", "alice");
+        assert_eq!(
+            decode(&encode(&url).unwrap()).unwrap(),
+            "https://sataddress.rs/.well-known/lnurlp/alice"
+        );
+        assert!(decode("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4").is_err());
+    }
+
+    #[test]
+    fn qr_codes_are_rendered() {
+        let lnurl = encode(&pay_url("sataddress.rs", "alice")).unwrap();
+
+        let svg = qr_svg(&lnurl).unwrap();
+        assert!(svg.contains("<svg"));
+
+        let png = qr_png(&lnurl).unwrap();
+        assert_eq!(avatar::detect_format(&png), Some(ImageFormat::Png));
+        let (width, height) = avatar::dimensions(ImageFormat::Png, &png).unwrap();
+        assert_eq!(width, height);
+        assert_eq!(width % 8, 0);
+    }
+}
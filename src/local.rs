@@ -0,0 +1,277 @@
+//! Local admin channel. The server listens on a Unix domain socket
+//! (readable and writable only by its owner) and serves the admin API
+//! routes there without token checks, so that the `cli` can work with
+//! the live database while the server is running.
+
+use std::{
+    collections::HashMap,
+    fs,
+    future::Future,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use log::*;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::net::{UnixListener, UnixStream};
+use tokio_stream::wrappers::UnixListenerStream;
+use url::form_urlencoded;
+use warp::{
+    http::{Method, Request},
+    hyper::{self, Body},
+    Filter,
+};
+
+use crate::{
+    api::{self, InvoiceQuery},
+    db::{
+        models::{InvoiceRecord, Params, Stats},
+        Db,
+    },
+    handlers, Config,
+};
+
+/// Binds the admin socket at `path` and returns the future serving it.
+/// Fails if another server is already listening on that socket.
+pub fn serve(db: Db, config: Config, path: &str) -> Result<impl Future<Output = ()>> {
+    if Path::new(path).exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            bail!("another server is already listening on {}", path);
+        }
+        // left behind by a server that wasn't shut down cleanly
+        fs::remove_file(path)?;
+    }
+    let listener = bind_private(Path::new(path))?;
+    info!("Admin socket listening on {}", path);
+
+    let routes = api::local_handlers(db, config).recover(handlers::handle_rejection);
+    Ok(warp::serve(routes).run_incoming(UnixListenerStream::new(listener)))
+}
+
+/// Binds the socket inside a fresh directory only accessible by the
+/// owner and moves it to `path` once its permissions are restricted,
+/// so it's never reachable by others, whatever the umask.
+fn bind_private(path: &Path) -> Result<UnixListener> {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => bail!("invalid socket path {}", path.display()),
+    };
+    let dir = path.with_file_name(format!(".{}.{}", name, std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let bound = dir.join(&*name);
+    let listener = UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&bound);
+    fs::remove_dir(&dir)?;
+    Ok(listener?)
+}
+
+/// Client of the admin socket
+pub struct Client {
+    path: PathBuf,
+}
+
+impl Client {
+    /// Connects to the server listening at `path`,
+    /// returns `None` if the server is not running
+    pub async fn connect(path: impl Into<PathBuf>) -> Option<Self> {
+        let path = path.into();
+        match UnixStream::connect(&path).await {
+            Ok(_) => Some(Self { path }),
+            Err(e) => {
+                debug!("Admin socket {} not available: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Sends a request to the server, returns the status code and parsed
+    /// JSON body of the response
+    async fn request(&self, method: Method, uri: &str) -> Result<(u16, Value)> {
+        let stream = UnixStream::connect(&self.path).await?;
+        let (mut sender, conn) = hyper::client::conn::handshake(stream).await?;
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                warn!("Admin socket connection failed: {}", e);
+            }
+        });
+
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("host", "localhost")
+            .body(Body::empty())?;
+        let res = sender.send_request(req).await?;
+        let status = res.status().as_u16();
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        Ok((status, serde_json::from_slice(&body)?))
+    }
+
+    /// Same as `request` but fails on error responses
+    async fn fetch<T: DeserializeOwned>(&self, method: Method, uri: &str) -> Result<T> {
+        match self.request(method, uri).await? {
+            (200..=299, value) => Ok(serde_json::from_value(value)?),
+            (_, value) => bail!("{}", value["message"].as_str().unwrap_or("request failed")),
+        }
+    }
+
+    /// Returns all the records
+    pub async fn list(&self) -> Result<Vec<Params>> {
+        self.fetch(Method::GET, "/users").await
+    }
+
+    /// Returns invoices from the ledger, the most recent ones first
+    pub async fn list_invoices(&self, query: &InvoiceQuery) -> Result<Vec<InvoiceRecord>> {
+        let mut params = form_urlencoded::Serializer::new(String::new());
+        if let Some(ref address) = query.address {
+            params.append_pair("address", address);
+        }
+        if let Some(limit) = query.limit {
+            params.append_pair("limit", &limit.to_string());
+        }
+        let uri = format!("/invoices?{}", params.finish());
+        self.fetch(Method::GET, &uri).await
+    }
+
+    /// Returns the invoice with given payment hash from the ledger
+    pub async fn get_invoice(&self, payment_hash: &str) -> Result<Option<InvoiceRecord>> {
+        let uri = format!("/invoice/{}", payment_hash);
+        match self.fetch_entry(Method::GET, &uri).await? {
+            Some(value) => Ok(Some(serde_json::from_value(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns usage stats, same as `api::generate_stats`
+    pub async fn stats(&self) -> Result<(HashMap<String, Stats>, Value)> {
+        let mut value: Value = self.fetch(Method::GET, "/stats").await?;
+        let data = serde_json::from_value(value["data"].take())?;
+        Ok((data, value["summary"].take()))
+    }
+
+    /// Same as `fetch` but returns `None` if the entry doesn't exist
+    async fn fetch_entry(&self, method: Method, uri: &str) -> Result<Option<Value>> {
+        match self.request(method, uri).await? {
+            (404, _) => Ok(None),
+            (200..=299, value) => Ok(Some(value)),
+            (_, value) => bail!("{}", value["message"].as_str().unwrap_or("request failed")),
+        }
+    }
+
+    /// Generates a new PIN for the entry, returns `None` if it doesn't exist
+    pub async fn reset_pin(&self, address: &str) -> Result<Option<String>> {
+        let uri = format!("/user/{}/pin", address);
+        let value = self.fetch_entry(Method::POST, &uri).await?;
+        Ok(value.and_then(|v| v["pin"].as_str().map(|pin| pin.to_owned())))
+    }
+
+    /// Removes the entry, returns `false` if it doesn't exist
+    pub async fn delete(&self, address: &str) -> Result<bool> {
+        let uri = format!("/user/{}", address);
+        Ok(self.fetch_entry(Method::DELETE, &uri).await?.is_some())
+    }
+
+    /// Disables or re-enables the entry, returns `false` if it doesn't exist
+    pub async fn set_disabled(&self, address: &str, disabled: bool) -> Result<bool> {
+        let action = if disabled { "disable" } else { "enable" };
+        let uri = format!("/user/{}/{}", address, action);
+        Ok(self.fetch_entry(Method::POST, &uri).await?.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, os::unix::fs::PermissionsExt};
+
+    use envconfig::Envconfig;
+
+    use crate::{
+        db::{helpers, models::Params},
+        pin::verify_pin,
+        Config,
+    };
+
+    use super::{serve, Client};
+
+    fn socket_path() -> String {
+        env::temp_dir()
+            .join(format!("{}.sock", helpers::tmp_name()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn init_config() -> Config {
+        let vars = [
+            ("DOMAINS", "domain.com"),
+            ("PIN_SECRET", "secret"),
+            ("SITE_NAME", "name"),
+            ("SITE_SUB_NAME", "sub_name"),
+            ("LNBITS_URL", "http://127.0.0.1:5001"),
+            ("LNBITS_API_KEY", "key"),
+            ("LNBITS_ADMIN_ID", "admin"),
+        ];
+        Config::init_from_hashmap(
+            &vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn cli_talks_to_the_live_db_over_the_socket() {
+        let path = socket_path();
+        assert!(Client::connect(&path).await.is_none());
+
+        let db = helpers::tmp_db();
+        let params = Params {
+            name: "user".to_string(),
+            domain: "domain.com".to_string(),
+            ..Default::default()
+        };
+        db.insert(&params.name, &params.domain, &params).unwrap();
+
+        tokio::spawn(serve(db.clone(), init_config(), &path).unwrap());
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // the private directory it was bound in is gone
+        let dir = std::path::Path::new(&path).parent().unwrap();
+        let name = std::path::Path::new(&path).file_name().unwrap();
+        assert!(!dir
+            .join(format!(
+                ".{}.{}",
+                name.to_string_lossy(),
+                std::process::id()
+            ))
+            .exists());
+        assert!(serve(db.clone(), init_config(), &path).is_err());
+
+        let client = Client::connect(&path).await.unwrap();
+        assert_eq!(client.list().await.unwrap(), vec![params]);
+        let (data, summary) = client.stats().await.unwrap();
+        assert!(data.contains_key("user@domain.com"));
+        assert_eq!(summary["invoices"], 0);
+
+        let pin = client.reset_pin("user@domain.com").await.unwrap().unwrap();
+        let stored = db.get("user", "domain.com").unwrap().unwrap();
+        assert!(verify_pin(&stored, &pin, &[]));
+        assert!(client
+            .reset_pin("nobody@domain.com")
+            .await
+            .unwrap()
+            .is_none());
+
+        assert!(client.set_disabled("user@domain.com", true).await.unwrap());
+        assert!(db.get("user", "domain.com").unwrap().unwrap().disabled);
+        assert!(client.delete("user@domain.com").await.unwrap());
+        assert!(!client.delete("user@domain.com").await.unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,669 @@
+//! Nostr zaps (NIP-57). Domains configured with a nostr key advertise
+//! `allowsNostr` in their pay requests and accept kind 9734 zap requests
+//! in the callback. Once the invoice gets paid a kind 9735 receipt, signed
+//! with the domain key, is queued in the database and published to the
+//! relays the zap request listed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Result};
+use futures::{future::join_all, stream, SinkExt, StreamExt};
+use k256::schnorr::{Signature, SigningKey, VerifyingKey};
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::{
+    net::{lookup_host, TcpStream},
+    time::timeout,
+};
+use tokio_tungstenite::{client_async_tls, tungstenite::Message};
+use url::{Host, Url};
+
+use crate::{
+    db::{
+        models::{InvoiceRecord, ZapReceiptJob},
+        Db,
+    },
+    webhook, Config,
+};
+
+pub const ZAP_REQUEST_KIND: u32 = 9734;
+pub const ZAP_RECEIPT_KIND: u32 = 9735;
+/// Relays of a zap request above that number are ignored
+const MAX_RELAYS: usize = 10;
+/// Publications that keep failing are dropped after that many attempts
+const MAX_ATTEMPTS: u32 = 10;
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Receipts published at the same time (each to all of its relays at once)
+const CONCURRENT_RECEIPTS: usize = 16;
+/// Most receipts published in a single run, the rest waits for the next one
+const PUBLISH_BATCH: usize = 256;
+
+/// Signing keys of the domains, parsed from `domain=<hex secret key>`
+/// pairs separated by commas
+#[derive(Clone)]
+pub struct Keys(HashMap<String, SigningKey>);
+
+impl Keys {
+    pub fn get(&self, domain: &str) -> Option<&SigningKey> {
+        self.0.get(domain)
+    }
+}
+
+impl FromStr for Keys {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keys = HashMap::new();
+        for pair in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let (domain, key) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected domain=key, got {}", pair))?;
+            let key = hex::decode(key.trim())
+                .ok()
+                .and_then(|bytes| SigningKey::from_bytes(&bytes).ok())
+                .ok_or_else(|| format!("invalid nostr key of {}", domain))?;
+            keys.insert(domain.trim().to_owned(), key);
+        }
+        Ok(Keys(keys))
+    }
+}
+
+/// Keeps the secret keys out of the logs
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.0.keys()).finish()
+    }
+}
+
+/// Hex encoded (x-only) public key of the signing key
+pub fn public_key(key: &SigningKey) -> String {
+    hex::encode(key.verifying_key().to_bytes())
+}
+
+/// Signed nostr event (NIP-01)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub id: String,
+    pub pubkey: String,
+    pub created_at: u64,
+    pub kind: u32,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+    pub sig: String,
+}
+
+impl Event {
+    /// Creates the event and signs it with the key
+    pub fn sign(
+        key: &SigningKey,
+        created_at: u64,
+        kind: u32,
+        tags: Vec<Vec<String>>,
+        content: String,
+    ) -> Result<Self> {
+        let mut event = Event {
+            id: String::new(),
+            pubkey: public_key(key),
+            created_at,
+            kind,
+            tags,
+            content,
+            sig: String::new(),
+        };
+        let id = event.hash();
+        let sig = key
+            .try_sign_prehashed(&id, &rand::random())
+            .map_err(|e| anyhow!("unable to sign the event: {}", e))?;
+        event.id = hex::encode(id);
+        event.sig = hex::encode(sig.as_bytes());
+        Ok(event)
+    }
+
+    /// Checks that the id matches the content and the signature the id
+    pub fn verify(&self) -> Result<()> {
+        let id = self.hash();
+        if hex::encode(id) != self.id {
+            bail!("event id does not match its content");
+        }
+        let pubkey = hex::decode(&self.pubkey)?;
+        if pubkey.len() != 32 {
+            bail!("invalid public key");
+        }
+        let pubkey =
+            VerifyingKey::from_bytes(&pubkey).map_err(|_| anyhow!("invalid public key"))?;
+        let sig = Signature::try_from(hex::decode(&self.sig)?.as_slice())
+            .map_err(|_| anyhow!("invalid signature"))?;
+        pubkey
+            .verify_prehashed(&id, &sig)
+            .map_err(|_| anyhow!("invalid signature"))
+    }
+
+    /// Values of the tags with the given name
+    pub fn tags(&self, name: &str) -> Vec<&[String]> {
+        self.tags
+            .iter()
+            .filter(|t| t.first().map(|n| n == name).unwrap_or(false))
+            .map(|t| &t[1..])
+            .collect()
+    }
+
+    /// First value of the only tag with the given name, fails
+    /// if there are more of them
+    fn single_tag(&self, name: &str) -> Result<Option<&String>, String> {
+        match self.tags(name).as_slice() {
+            [] => Ok(None),
+            [values] => Ok(values.first()),
+            _ => Err(format!("zap request has multiple {} tags", name)),
+        }
+    }
+
+    /// Sha256 of the canonical serialization, the id of the event
+    fn hash(&self) -> [u8; 32] {
+        let serialized = json!([
+            0,
+            self.pubkey,
+            self.created_at,
+            self.kind,
+            self.tags,
+            self.content
+        ])
+        .to_string();
+        Sha256::digest(serialized.as_bytes()).into()
+    }
+}
+
+/// Checks the zap request sent to the callback (NIP-57, appendix D),
+/// returns the reason it's not accepted
+pub fn check_zap_request(raw: &str, msat: u64) -> Result<Event, String> {
+    let event: Event =
+        serde_json::from_str(raw).map_err(|e| format!("invalid zap request: {}", e))?;
+    event
+        .verify()
+        .map_err(|e| format!("invalid zap request: {}", e))?;
+    if event.kind != ZAP_REQUEST_KIND {
+        return Err(format!(
+            "zap request has to be of kind {}",
+            ZAP_REQUEST_KIND
+        ));
+    }
+
+    let is_key = |k: &str| k.len() == 64 && hex::decode(k).is_ok();
+    match event.single_tag("p")? {
+        Some(p) if is_key(p) => {}
+        _ => return Err("zap request needs a single valid p tag".to_string()),
+    }
+    if let Some(e) = event.single_tag("e")? {
+        if !is_key(e) {
+            return Err("zap request has an invalid e tag".to_string());
+        }
+    }
+    if let Some(p) = event.single_tag("P")? {
+        if !is_key(p) {
+            return Err("zap request has an invalid P tag".to_string());
+        }
+    }
+    if let Some(a) = event.single_tag("a")? {
+        let valid = matches!(
+            a.splitn(3, ':').collect::<Vec<_>>().as_slice(),
+            [kind, pubkey, _] if kind.parse::<u32>().is_ok() && is_key(pubkey)
+        );
+        if !valid {
+            return Err("zap request has an invalid a tag".to_string());
+        }
+    }
+    if let Some(amount) = event.single_tag("amount")? {
+        if amount.parse::<u64>().ok() != Some(msat) {
+            return Err("zap request amount does not match".to_string());
+        }
+    }
+    if relays(&event).is_empty() {
+        return Err("zap request does not list any relays".to_string());
+    }
+    Ok(event)
+}
+
+/// Websocket relays the receipt of the zap request goes to, only
+/// secure ones and each of them once, in the order they were listed
+fn relays(request: &Event) -> Vec<String> {
+    let mut seen = HashSet::new();
+    request
+        .tags("relays")
+        .into_iter()
+        .flatten()
+        .filter(|r| r.starts_with("wss://"))
+        .filter(|r| seen.insert(r.as_str()))
+        .take(MAX_RELAYS)
+        .cloned()
+        .collect()
+}
+
+/// Creates the zap receipt of the paid invoice (NIP-57, appendix E)
+pub fn receipt(key: &SigningKey, invoice: &InvoiceRecord, request: &Event) -> Result<Event> {
+    let zap_request = match invoice.zap_request {
+        Some(ref zap_request) => zap_request.clone(),
+        None => bail!("invoice {} is not a zap", invoice.payment_hash),
+    };
+    let paid_at = invoice.settled_at.unwrap_or_else(SystemTime::now);
+
+    let mut tags = vec![];
+    for name in ["p", "e", "a"] {
+        if let Some(value) = request.tags(name).first().and_then(|v| v.first()) {
+            tags.push(vec![name.to_string(), value.clone()]);
+        }
+    }
+    tags.push(vec!["P".to_string(), request.pubkey.clone()]);
+    tags.push(vec!["bolt11".to_string(), invoice.payment_request.clone()]);
+    tags.push(vec!["description".to_string(), zap_request]);
+    if let Some(ref preimage) = invoice.preimage {
+        tags.push(vec!["preimage".to_string(), preimage.clone()]);
+    }
+
+    Event::sign(
+        key,
+        paid_at.duration_since(UNIX_EPOCH)?.as_secs(),
+        ZAP_RECEIPT_KIND,
+        tags,
+        String::new(),
+    )
+}
+
+/// Queues the receipt of the paid zap for publication, invoices
+/// that are not zaps are left alone
+pub fn enqueue_receipt(db: &Db, config: &Config, invoice: &InvoiceRecord) -> Result<()> {
+    let request = match invoice.zap_request {
+        Some(ref raw) => serde_json::from_str::<Event>(raw)?,
+        None => return Ok(()),
+    };
+    let domain = invoice.address.rsplit_once('@').map(|(_, d)| d);
+    let key = match domain.and_then(|d| config.nostr_key(d)) {
+        Some(key) => key,
+        None => bail!("no nostr key for {}", invoice.address),
+    };
+    let event = receipt(key, invoice, &request)?;
+    let mut job = ZapReceiptJob {
+        id: 0,
+        address: invoice.address.clone(),
+        event: serde_json::to_string(&event)?,
+        relays: relays(&request),
+        attempts: 0,
+        next_attempt: SystemTime::now(),
+    };
+    db.push_zap_receipt(&mut job)?;
+    debug!("Queued zap receipt #{} for {}", job.id, job.address);
+    Ok(())
+}
+
+/// Connects to one of the public addresses of the relay only (unless
+/// local ones are allowed), relays are picked by payers same as webhooks
+/// by owners (see `webhook::check_url`)
+async fn connect(relay: &str, allow_local: bool) -> Result<TcpStream> {
+    let url = Url::parse(relay)?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addrs: Vec<SocketAddr> = match url.host() {
+        Some(Host::Ipv4(ip)) => vec![(ip, port).into()],
+        Some(Host::Ipv6(ip)) => vec![(ip, port).into()],
+        Some(Host::Domain(domain)) => lookup_host((domain, port)).await?.collect(),
+        None => bail!("relay url has no host"),
+    };
+    let addrs: Vec<SocketAddr> = addrs
+        .into_iter()
+        .filter(|addr| allow_local || webhook::is_public(&addr.ip()))
+        .collect();
+    if addrs.is_empty() {
+        bail!("relay has no public address");
+    }
+    Ok(TcpStream::connect(&addrs[..]).await?)
+}
+
+/// Sends the event to the relay and waits for it to be accepted
+async fn publish(relay: &str, event: &str, allow_local: bool) -> Result<()> {
+    let stream = connect(relay, allow_local).await?;
+    let (mut ws, _) = client_async_tls(relay, stream).await?;
+    ws.send(Message::Text(format!(r#"["EVENT",{}]"#, event)))
+        .await?;
+    while let Some(msg) = ws.next().await {
+        let msg = match msg? {
+            Message::Text(msg) => msg,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        // ["OK", <event id>, <accepted>, <message>], notices are skipped
+        if let Ok(Value::Array(reply)) = serde_json::from_str(&msg) {
+            if reply.first() == Some(&json!("OK")) {
+                let _ = ws.close(None).await;
+                return match reply.get(2) {
+                    Some(Value::Bool(true)) => Ok(()),
+                    _ => bail!("rejected: {}", reply.get(3).unwrap_or(&Value::Null)),
+                };
+            }
+        }
+    }
+    bail!("connection closed before the event was accepted")
+}
+
+/// Summary of a single run over the queue
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PublishReport {
+    /// receipts accepted by all of their relays
+    pub published: usize,
+    /// receipts scheduled for another attempt at the failed relays
+    pub retried: usize,
+    /// receipts given up on
+    pub dropped: usize,
+}
+
+/// Publishes the receipt to all of its relays at once,
+/// returns the relays it didn't get to
+async fn publish_job(job: &ZapReceiptJob, allow_local: bool) -> Vec<String> {
+    let results = join_all(job.relays.iter().map(|relay| async move {
+        match timeout(RELAY_TIMEOUT, publish(relay, &job.event, allow_local)).await {
+            Ok(Ok(_)) => None,
+            Ok(Err(e)) => {
+                debug!("Zap receipt #{} not published to {}: {}", job.id, relay, e);
+                Some(relay.clone())
+            }
+            Err(_) => {
+                debug!("Zap receipt #{} timed out at {}", job.id, relay);
+                Some(relay.clone())
+            }
+        }
+    }))
+    .await;
+    results.into_iter().flatten().collect()
+}
+
+/// Publishes queued receipts that are due
+pub async fn publish_due(db: &Db, config: &Config) -> Result<PublishReport> {
+    let mut report = PublishReport::default();
+    let allow_local = config.webhook_allow_local;
+    let now = SystemTime::now();
+
+    let due: Vec<ZapReceiptJob> = db
+        .list_zap_receipts()?
+        .into_iter()
+        .filter(|job| job.next_attempt <= now)
+        .take(PUBLISH_BATCH)
+        .collect();
+    let mut publications = stream::iter(due)
+        .map(|job| async move {
+            let failed = publish_job(&job, allow_local).await;
+            (job, failed)
+        })
+        .buffer_unordered(CONCURRENT_RECEIPTS);
+
+    while let Some((mut job, failed)) = publications.next().await {
+        if failed.is_empty() {
+            db.delete_zap_receipt(job.id)?;
+            report.published += 1;
+            continue;
+        }
+        job.relays = failed;
+        job.attempts += 1;
+        if job.attempts >= MAX_ATTEMPTS {
+            warn!(
+                "Dropping zap receipt #{} for {} after {} attempts",
+                job.id, job.address, job.attempts
+            );
+            db.delete_zap_receipt(job.id)?;
+            report.dropped += 1;
+        } else {
+            job.next_attempt = now + webhook::backoff(job.attempts);
+            db.save_zap_receipt(&job)?;
+            report.retried += 1;
+        }
+    }
+    Ok(report)
+}
+
+/// Periodically publishes queued receipts, runs forever
+pub async fn run(db: Db, config: Config, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        match publish_due(&db, &config).await {
+            Ok(report) if report != PublishReport::default() => info!(
+                "Zap receipts: {} published, {} to retry, {} dropped",
+                report.published, report.retried, report.dropped
+            ),
+            Ok(_) => {}
+            Err(e) => error!("Zap receipt publication failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod helpers {
+    use k256::schnorr::SigningKey;
+
+    use super::{Event, ZAP_REQUEST_KIND};
+
+    /// Zap request of the key to `recipient`, with the given extra tags
+    pub fn zap_request(
+        key: &SigningKey,
+        recipient: &SigningKey,
+        relays: &[&str],
+        tags: Vec<Vec<&str>>,
+    ) -> Event {
+        let mut relays_tag = vec!["relays".to_string()];
+        relays_tag.extend(relays.iter().map(|r| r.to_string()));
+        let mut all_tags = vec![
+            vec!["p".to_string(), super::public_key(recipient)],
+            relays_tag,
+        ];
+        all_tags.extend(
+            tags.into_iter()
+                .map(|t| t.into_iter().map(String::from).collect()),
+        );
+        Event::sign(
+            key,
+            1_700_000_000,
+            ZAP_REQUEST_KIND,
+            all_tags,
+            "gm".to_string(),
+        )
+        .unwrap()
+    }
+
+    pub fn key(byte: u8) -> SigningKey {
+        SigningKey::from_bytes(&[byte; 32]).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::{net::TcpListener, sync::mpsc};
+    use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+    use envconfig::Envconfig;
+
+    use crate::{
+        db::{
+            self,
+            models::{InvoiceRecord, ZapReceiptJob},
+        },
+        Config,
+    };
+
+    use super::{
+        check_zap_request, helpers, publish_due, receipt, Event, Keys, PublishReport,
+        ZAP_RECEIPT_KIND,
+    };
+
+    fn init_config(allow_local: bool) -> Config {
+        let vars = [
+            ("DOMAINS", "domain.com"),
+            ("PIN_SECRET", "secret"),
+            ("SITE_NAME", "name"),
+            ("SITE_SUB_NAME", "sub_name"),
+            ("LNBITS_URL", "http://127.0.0.1:5001"),
+            ("LNBITS_API_KEY", "key"),
+            ("LNBITS_ADMIN_ID", "admin"),
+            (
+                "WEBHOOK_ALLOW_LOCAL",
+                if allow_local { "true" } else { "false" },
+            ),
+        ];
+        Config::init_from_hashmap(
+            &vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    /// Local relay stand-in, accepts events and passes them on
+    async fn relay() -> (String, mpsc::UnboundedReceiver<Event>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut ws = accept_async(stream).await.unwrap();
+                while let Some(Ok(Message::Text(msg))) = ws.next().await {
+                    let (_, event): (String, Event) = serde_json::from_str(&msg).unwrap();
+                    let ok = serde_json::json!(["OK", event.id, true, ""]);
+                    ws.send(Message::Text(ok.to_string())).await.unwrap();
+                    tx.send(event).unwrap();
+                }
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn events_are_signed_and_verified() {
+        let event = Event::sign(&helpers::key(1), 1, 1, vec![], "hello".to_string()).unwrap();
+        assert!(event.verify().is_ok());
+
+        let mut tampered = event.clone();
+        tampered.content = "bye".to_string();
+        assert!(tampered.verify().is_err());
+        let mut forged = event;
+        forged.pubkey = super::public_key(&helpers::key(2));
+        assert!(forged.verify().is_err());
+    }
+
+    #[test]
+    fn nostr_keys_are_parsed() {
+        let keys: Keys = format!("a.com={}, b.com={}", "01".repeat(32), "02".repeat(32))
+            .parse()
+            .unwrap();
+        assert!(keys.get("a.com").is_some());
+        assert!(keys.get("c.com").is_none());
+        assert!(!format!("{:?}", keys).contains(&"01".repeat(32)));
+        assert!("a.com=nothex".parse::<Keys>().is_err());
+    }
+
+    #[test]
+    fn zap_requests_are_checked() {
+        let (sender, recipient) = (helpers::key(1), helpers::key(2));
+        let relays = ["wss://relay.example.com"];
+        let check = |tags: Vec<Vec<&str>>| {
+            let request = helpers::zap_request(&sender, &recipient, &relays, tags);
+            check_zap_request(&serde_json::to_string(&request).unwrap(), 1000)
+        };
+
+        assert!(check(vec![]).is_ok());
+        assert!(check(vec![vec!["amount", "1000"]]).is_ok());
+        assert!(check(vec![vec!["amount", "2000"]]).is_err());
+        let other = super::public_key(&helpers::key(3));
+        assert!(check(vec![vec!["p", &other]]).is_err());
+        assert!(check(vec![vec!["e", "not-an-id"]]).is_err());
+
+        let mut request = helpers::zap_request(&sender, &recipient, &relays, vec![]);
+        request.content = "tampered".to_string();
+        assert!(check_zap_request(&serde_json::to_string(&request).unwrap(), 1000).is_err());
+
+        let no_relays = helpers::zap_request(&sender, &recipient, &[], vec![]);
+        assert!(check_zap_request(&serde_json::to_string(&no_relays).unwrap(), 1000).is_err());
+        let insecure = helpers::zap_request(&sender, &recipient, &["ws://127.0.0.1:8080"], vec![]);
+        assert!(check_zap_request(&serde_json::to_string(&insecure).unwrap(), 1000).is_err());
+
+        let listed = [
+            "wss://a.example.com",
+            "wss://b.example.com",
+            "ws://a.example.com",
+            "wss://a.example.com",
+            "http://c.example.com",
+        ];
+        let request = helpers::zap_request(&sender, &recipient, &listed, vec![]);
+        assert_eq!(
+            super::relays(&request),
+            vec!["wss://a.example.com", "wss://b.example.com"]
+        );
+
+        let note = super::Event::sign(&sender, 1, 1, vec![], String::new()).unwrap();
+        assert!(check_zap_request(&serde_json::to_string(&note).unwrap(), 1000).is_err());
+    }
+
+    #[tokio::test]
+    async fn receipts_are_published_to_the_relays() {
+        let (url, mut events) = relay().await;
+        let domain_key = helpers::key(9);
+        let request = helpers::zap_request(&helpers::key(1), &helpers::key(2), &[&url], vec![]);
+        let raw_request = serde_json::to_string(&request).unwrap();
+
+        let now = SystemTime::now();
+        let invoice = InvoiceRecord {
+            msat_received: Some(1000),
+            settled_at: Some(now),
+            preimage: Some("bb".repeat(32)),
+            zap_request: Some(raw_request.clone()),
+            ..db::helpers::invoice(&"aa".repeat(32))
+        };
+        let event = receipt(&domain_key, &invoice, &request).unwrap();
+
+        let db = db::helpers::tmp_db();
+        let mut job = ZapReceiptJob {
+            id: 0,
+            address: invoice.address.clone(),
+            event: serde_json::to_string(&event).unwrap(),
+            // the second relay is down
+            relays: vec![url.clone(), "ws://127.0.0.1:1".to_string()],
+            attempts: 0,
+            next_attempt: now,
+        };
+        db.push_zap_receipt(&mut job).unwrap();
+
+        // local relays are left alone unless allowed
+        let report = publish_due(&db, &init_config(false)).await.unwrap();
+        assert_eq!(report.retried, 1);
+        assert!(events.try_recv().is_err());
+        let mut job = db.list_zap_receipts().unwrap().pop().unwrap();
+        assert_eq!(job.relays.len(), 2);
+        job.attempts = 0;
+        job.next_attempt = now;
+        db.save_zap_receipt(&job).unwrap();
+
+        let config = init_config(true);
+        let report = publish_due(&db, &config).await.unwrap();
+        assert_eq!(report.retried, 1);
+        let job = db.list_zap_receipts().unwrap().pop().unwrap();
+        assert_eq!(job.relays, vec!["ws://127.0.0.1:1".to_string()]);
+        assert_eq!(
+            publish_due(&db, &config).await.unwrap(),
+            PublishReport::default()
+        );
+
+        let published = events.recv().await.unwrap();
+        assert_eq!(published, event);
+        assert!(published.verify().is_ok());
+        assert_eq!(published.kind, ZAP_RECEIPT_KIND);
+        assert_eq!(published.pubkey, super::public_key(&domain_key));
+        assert_eq!(published.tags("description"), vec![&[raw_request][..]]);
+        assert_eq!(published.tags("bolt11"), vec![&["lnbc1".to_string()][..]]);
+        assert_eq!(published.tags("P"), vec![&[request.pubkey][..]]);
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::{anyhow, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::db::models::Params;
+
+/// Length of randomly generated PINs
+const PIN_LEN: usize = 20;
+
+/// Generates a new random PIN
+pub fn generate_pin() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(PIN_LEN)
+        .map(char::from)
+        .collect()
+}
+
+/// Hashes the PIN with a random salt, returns the hash in PHC string format
+pub fn hash_pin(pin: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(pin.as_bytes(), &salt)
+        .map_err(|e| anyhow!("unable to hash the PIN: {}", e))?;
+    Ok(hash.to_string())
+}
+
+/// Checks if provided PIN allows to modify the entry. Entries that
+/// were not migrated yet use the legacy PIN, derived from one of
+/// the `secrets` (current one first, see `Config::pin_secrets`).
+pub fn verify_pin(params: &Params, pin: &str, secrets: &[&str]) -> bool {
+    match params.pin_hash {
+        Some(ref hash) => match PasswordHash::new(hash) {
+            Ok(hash) => Argon2::default()
+                .verify_password(pin.as_bytes(), &hash)
+                .is_ok(),
+            Err(_) => false,
+        },
+        None => secrets.iter().any(|secret| {
+            let legacy = compute_pin(&params.name, &params.domain, secret);
+            legacy.as_bytes().ct_eq(pin.as_bytes()).into()
+        }),
+    }
+}
+
+/// Same as `verify_pin`, but runs on the blocking thread pool, so
+/// that Argon2 doesn't stall the async workers.
+pub async fn verify_pin_async(params: &Params, pin: &str, secrets: &[&str]) -> bool {
+    let params = params.clone();
+    let pin = pin.to_owned();
+    let secrets: Vec<String> = secrets.iter().map(|s| s.to_string()).collect();
+    tokio::task::spawn_blocking(move || {
+        let secrets: Vec<&str> = secrets.iter().map(String::as_str).collect();
+        verify_pin(&params, &pin, &secrets)
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// Describes how the PIN of an entry is stored
+#[derive(Debug, PartialEq, Eq)]
+pub enum PinScheme {
+    /// salted hash of a random PIN
+    Hashed,
+    /// legacy PIN derived from the secret at given index
+    /// (0 is the current secret)
+    Legacy(usize),
+    /// legacy PIN not matching any of the known secrets
+    Unknown,
+}
+
+/// Determines how the PIN of the entry is stored
+pub fn pin_scheme(params: &Params, secrets: &[&str]) -> PinScheme {
+    if params.pin_hash.is_some() {
+        return PinScheme::Hashed;
+    }
+    secrets
+        .iter()
+        .position(|secret| compute_pin(&params.name, &params.domain, secret) == params.pin)
+        .map(PinScheme::Legacy)
+        .unwrap_or(PinScheme::Unknown)
+}
+
+/// Replaces the PIN of the entry with a new random one.
+/// Returns the new PIN, which is not stored anywhere in plaintext.
+pub fn reset_pin(params: &mut Params) -> Result<String> {
+    let pin = generate_pin();
+    params.pin_hash = Some(hash_pin(&pin)?);
+    params.pin.clear();
+    Ok(pin)
+}
+
+/// Same as `reset_pin`, but hashes the new PIN on the blocking thread pool.
+pub async fn reset_pin_async(params: &mut Params) -> Result<String> {
+    let pin = generate_pin();
+    let to_hash = pin.clone();
+    let hash = tokio::task::spawn_blocking(move || hash_pin(&to_hash)).await??;
+    params.pin_hash = Some(hash);
+    params.pin.clear();
+    Ok(pin)
+}
+
+/// Moves an entry still storing the legacy plaintext PIN to the
+/// hashed one. PIN value stays the same so owners can keep using it.
+/// Returns `false` if there was nothing to migrate.
+pub fn migrate_legacy_pin(params: &mut Params) -> Result<bool> {
+    if params.pin_hash.is_some() || params.pin.is_empty() {
+        return Ok(false);
+    }
+    params.pin_hash = Some(hash_pin(&params.pin)?);
+    params.pin.clear();
+    Ok(true)
+}
+
+/// Computes the legacy pin, derived from the server secret, that
+/// was required in order to modify entries before per-address
+/// PINs were introduced.
+pub(crate) fn compute_pin(usnername: &str, domain: &str, secret: &str) -> String {
+    let sha = Sha256::new()
+        .chain_update(secret)
+        .chain_update(usnername)
+        .chain_update(domain)
+        .finalize();
+
+    hex::encode(sha)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::models::Params;
+
+    use super::{
+        compute_pin, migrate_legacy_pin, pin_scheme, reset_pin, reset_pin_async, verify_pin,
+        verify_pin_async, PinScheme,
+    };
+
+    #[test]
+    fn computes_pin_for_user() {
+        assert_eq!(
+            compute_pin("user", "domain", "secret1"),
+            "a8fe9f81a343e918a2aa9a6ee251b2e672c90b8f9b98d253db202ab910dc3668"
+        );
+    }
+
+    #[test]
+    fn reset_pin_stores_only_the_hash() {
+        let mut params = Params {
+            pin: "legacy".to_string(),
+            ..Default::default()
+        };
+        let pin = reset_pin(&mut params).unwrap();
+        let hash = params.pin_hash.clone().unwrap();
+
+        assert!(params.pin.is_empty());
+        assert!(!hash.contains(&pin));
+        assert!(verify_pin(&params, &pin, &["secret"]));
+        assert!(!verify_pin(&params, "wrong-pin", &["secret"]));
+
+        let other_pin = reset_pin(&mut params).unwrap();
+        assert_ne!(pin, other_pin);
+        assert!(!verify_pin(&params, &pin, &["secret"]));
+    }
+
+    #[tokio::test]
+    async fn pins_are_hashed_and_verified_off_the_workers() {
+        let mut params = Params::default();
+        let pin = reset_pin_async(&mut params).await.unwrap();
+        assert!(params.pin.is_empty());
+        assert!(verify_pin_async(&params, &pin, &["secret"]).await);
+        assert!(!verify_pin_async(&params, "wrong-pin", &["secret"]).await);
+    }
+
+    #[test]
+    fn legacy_pins_are_verified_and_migrated() {
+        let legacy = compute_pin("user", "domain", "secret");
+        let mut params = Params {
+            name: "user".to_string(),
+            domain: "domain".to_string(),
+            pin: legacy.clone(),
+            ..Default::default()
+        };
+        assert!(verify_pin(&params, &legacy, &["secret"]));
+        assert!(!verify_pin(&params, &legacy, &["other-secret"]));
+
+        assert!(migrate_legacy_pin(&mut params).unwrap());
+        assert!(params.pin.is_empty());
+        assert!(verify_pin(&params, &legacy, &["other-secret"]));
+        assert!(!migrate_legacy_pin(&mut params).unwrap());
+    }
+
+    #[test]
+    fn legacy_pins_from_previous_secrets_are_accepted() {
+        let legacy = compute_pin("user", "domain", "old-secret");
+        let mut params = Params {
+            name: "user".to_string(),
+            domain: "domain".to_string(),
+            pin: legacy.clone(),
+            ..Default::default()
+        };
+        assert!(!verify_pin(&params, &legacy, &["new-secret"]));
+        assert!(verify_pin(&params, &legacy, &["new-secret", "old-secret"]));
+
+        assert_eq!(pin_scheme(&params, &["old-secret"]), PinScheme::Legacy(0));
+        assert_eq!(
+            pin_scheme(&params, &["new-secret", "old-secret"]),
+            PinScheme::Legacy(1)
+        );
+        assert_eq!(pin_scheme(&params, &["new-secret"]), PinScheme::Unknown);
+
+        reset_pin(&mut params).unwrap();
+        assert_eq!(pin_scheme(&params, &["new-secret"]), PinScheme::Hashed);
+    }
+}
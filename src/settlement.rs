@@ -0,0 +1,405 @@
+//! Background task that tracks whether invoices from the ledger
+//! were paid, by polling the backends that issued them.
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use log::*;
+
+use crate::{
+    db::{
+        models::{InvoiceRecord, InvoiceStatus, Params},
+        Db,
+    },
+    ln::invoice::{check_invoice, Settlement},
+    nostr,
+    webhook::{self, Event},
+    Config,
+};
+
+/// Pending invoices checked this recently are served from the ledger
+/// to LUD-21 verify requests, without asking the backend again
+pub const VERIFY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Summary of a single check of the pending invoices
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    pub checked: usize,
+    pub settled: usize,
+    pub expired: usize,
+    /// invoices the backend couldn't be asked about
+    pub failed: usize,
+}
+
+/// Checks all pending invoices against their backends,
+/// marks them settled (recording the amount received) or expired.
+pub async fn check_pending(db: &Db, config: &Config) -> Result<CheckReport> {
+    let mut report = CheckReport::default();
+    for mut invoice in db.list_pending_invoices()? {
+        report.checked += 1;
+        match refresh(db, config, &mut invoice).await {
+            Ok(_) => match invoice.status {
+                InvoiceStatus::Settled => report.settled += 1,
+                InvoiceStatus::Expired => report.expired += 1,
+                InvoiceStatus::Pending => {}
+            },
+            Err(e) => {
+                warn!(
+                    "Unable to check invoice {} of {}: {}",
+                    invoice.payment_hash, invoice.address, e
+                );
+                report.failed += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Asks the backend about a pending invoice and saves its new state
+/// to the ledger, invoices that are no longer pending are left as is.
+/// Only the check that moves the invoice on in the ledger queues its
+/// webhook and zap receipt, a concurrent one reloads the stored state.
+pub async fn refresh(db: &Db, config: &Config, invoice: &mut InvoiceRecord) -> Result<()> {
+    if invoice.status != InvoiceStatus::Pending {
+        return Ok(());
+    }
+    let now = SystemTime::now();
+    let params = lookup_params(db, invoice)?;
+    let state = match params {
+        Some(ref params) if issued_by(params, invoice) => {
+            check_invoice(
+                params,
+                &config.lnbits.url,
+                config.tor_proxy_url.clone(),
+                &invoice.payment_hash,
+            )
+            .await?
+        }
+        // entry was removed or moved to another backend,
+        // nobody to ask, wait for the expiry
+        _ => Settlement::Open,
+    };
+
+    match state {
+        Settlement::Settled { msat, preimage } => {
+            invoice.status = InvoiceStatus::Settled;
+            invoice.msat_received = Some(msat);
+            invoice.settled_at = Some(now);
+            invoice.preimage = preimage;
+        }
+        Settlement::Canceled => invoice.status = InvoiceStatus::Expired,
+        Settlement::Open if now >= invoice.expires_at => invoice.status = InvoiceStatus::Expired,
+        Settlement::Open => return Ok(()),
+    }
+    if !db.update_pending_invoice(invoice)? {
+        if let Some(stored) = db.get_invoice(&invoice.payment_hash)? {
+            *invoice = stored;
+        }
+        return Ok(());
+    }
+
+    if invoice.status == InvoiceStatus::Settled {
+        if let Err(e) = nostr::enqueue_receipt(db, config, invoice) {
+            error!("Unable to queue zap receipt for {}: {}", invoice.address, e);
+        }
+    }
+
+    if let Some(webhook) = params.and_then(|p| p.webhook) {
+        let event = match invoice.status {
+            InvoiceStatus::Settled => Event::Paid,
+            _ => Event::Expired,
+        };
+        if let Err(e) = webhook::enqueue(db, &webhook, event, invoice) {
+            error!("Unable to queue webhook for {}: {}", invoice.address, e);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the entry the invoice was issued for
+fn lookup_params(db: &Db, invoice: &InvoiceRecord) -> Result<Option<Params>> {
+    match invoice.address.rsplit_once('@') {
+        Some((name, domain)) => db.get(name, domain),
+        None => Ok(None),
+    }
+}
+
+/// Whether the entry still uses the backend that issued the invoice,
+/// another one knows nothing about it
+fn issued_by(params: &Params, invoice: &InvoiceRecord) -> bool {
+    match invoice.backend_id {
+        Some(ref id) => *id == params.invoice_api.identity(),
+        None => true,
+    }
+}
+
+/// Periodically checks pending invoices, runs forever
+pub async fn run(db: Db, config: Config, every: Duration) {
+    // ledgers written before the pending index was introduced
+    match db.reindex_pending_invoices() {
+        Ok(pending) => info!("{} pending invoices to check", pending),
+        Err(e) => error!("Unable to index pending invoices: {}", e),
+    }
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        match check_pending(&db, &config).await {
+            Ok(report) if report.checked > 0 => info!(
+                "Checked {} pending invoices: {} settled, {} expired, {} failed",
+                report.checked, report.settled, report.expired, report.failed
+            ),
+            Ok(_) => {}
+            Err(e) => error!("Settlement check failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use envconfig::Envconfig;
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{
+        db::{
+            helpers,
+            models::{InvoiceAPI, InvoiceRecord, InvoiceStatus, LNBitsParams, Params, Webhook},
+        },
+        nostr, Config,
+    };
+
+    use super::{check_pending, refresh, CheckReport};
+
+    fn init_config() -> Config {
+        let vars = [
+            ("DOMAINS", "domain.com"),
+            ("PIN_SECRET", "secret"),
+            ("SITE_NAME", "name"),
+            ("SITE_SUB_NAME", "sub_name"),
+            ("LNBITS_URL", "http://127.0.0.1:5001/"),
+            ("LNBITS_API_KEY", "key"),
+            ("LNBITS_ADMIN_ID", "admin"),
+            (
+                "NOSTR_KEYS",
+                "domain.com=0909090909090909090909090909090909090909090909090909090909090909",
+            ),
+        ];
+        Config::init_from_hashmap(
+            &vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    fn invoice(payment_hash: &str, expires_in: Duration, expired: bool) -> InvoiceRecord {
+        let now = SystemTime::now();
+        InvoiceRecord {
+            payment_request: format!("lnbc-{}", payment_hash),
+            msat: 10_000,
+            backend: "LNBits".to_string(),
+            expires_at: match expired {
+                true => now - expires_in,
+                false => now + expires_in,
+            },
+            ..helpers::invoice(payment_hash)
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_invoices_are_settled_or_expired() {
+        let mock_server = MockServer::start().await;
+        for (hash, paid) in [("paid", true), ("open", false), ("stale", false)] {
+            Mock::given(method("GET"))
+                .and(path(format!("/api/v1/payments/{}", hash)))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "paid": paid,
+                    "preimage": "ab".repeat(32),
+                    "details": {"amount": 9_000},
+                })))
+                .mount(&mock_server)
+                .await;
+        }
+
+        let db = helpers::tmp_db();
+        let params = Params {
+            name: "user".to_string(),
+            domain: "domain.com".to_string(),
+            invoice_api: InvoiceAPI::LNBits(LNBitsParams {
+                host: mock_server.uri(),
+                key: "key".to_string(),
+            }),
+            ..Default::default()
+        };
+        db.insert(&params.name, &params.domain, &params).unwrap();
+        let hour = Duration::from_secs(3600);
+        let mut paid = invoice("paid", hour, false);
+        let zap_request = nostr::helpers::zap_request(
+            &nostr::helpers::key(1),
+            &nostr::helpers::key(2),
+            &["wss://relay.example.com"],
+            vec![],
+        );
+        paid.zap_request = Some(serde_json::to_string(&zap_request).unwrap());
+        db.insert_invoice(&paid).unwrap();
+        db.insert_invoice(&invoice("open", hour, false)).unwrap();
+        db.insert_invoice(&invoice("stale", hour, true)).unwrap();
+
+        let report = check_pending(&db, &init_config()).await.unwrap();
+        assert_eq!(
+            report,
+            CheckReport {
+                checked: 3,
+                settled: 1,
+                expired: 1,
+                failed: 0,
+            }
+        );
+
+        let paid = db.get_invoice("paid").unwrap().unwrap();
+        assert_eq!(paid.status, InvoiceStatus::Settled);
+        assert_eq!(paid.msat_received, Some(9_000));
+        assert!(paid.settled_at.is_some());
+        assert_eq!(paid.preimage, Some("ab".repeat(32)));
+        // zap receipt of the paid invoice is queued
+        let receipts = db.list_zap_receipts().unwrap();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].relays, vec!["wss://relay.example.com"]);
+        let open = db.get_invoice("open").unwrap().unwrap();
+        assert_eq!(open.status, InvoiceStatus::Pending);
+        let stale = db.get_invoice("stale").unwrap().unwrap();
+        assert_eq!(stale.status, InvoiceStatus::Expired);
+
+        // only the open one is checked again
+        let report = check_pending(&db, &init_config()).await.unwrap();
+        assert_eq!(report.checked, 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_checks_settle_once() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/payments/paid"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "paid": true,
+                "preimage": "ab".repeat(32),
+                "details": {"amount": 10_000},
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let db = helpers::tmp_db();
+        let params = Params {
+            name: "user".to_string(),
+            domain: "domain.com".to_string(),
+            invoice_api: InvoiceAPI::LNBits(LNBitsParams {
+                host: mock_server.uri(),
+                key: "key".to_string(),
+            }),
+            webhook: Some(Webhook {
+                url: "https://example.com/hook".to_string(),
+                secret: "secret".to_string(),
+            }),
+            ..Default::default()
+        };
+        db.insert(&params.name, &params.domain, &params).unwrap();
+        let mut paid = invoice("paid", Duration::from_secs(3600), false);
+        let zap_request = nostr::helpers::zap_request(
+            &nostr::helpers::key(1),
+            &nostr::helpers::key(2),
+            &["wss://relay.example.com"],
+            vec![],
+        );
+        paid.zap_request = Some(serde_json::to_string(&zap_request).unwrap());
+        db.insert_invoice(&paid).unwrap();
+
+        // both checks started off the pending record
+        let (mut first, mut second) = (paid.clone(), paid);
+        let config = init_config();
+        let (a, b) = tokio::join!(
+            refresh(&db, &config, &mut first),
+            refresh(&db, &config, &mut second)
+        );
+        a.unwrap();
+        b.unwrap();
+
+        assert_eq!(first.status, InvoiceStatus::Settled);
+        assert_eq!(first, second);
+        assert_eq!(db.list_webhooks().unwrap().len(), 1);
+        assert_eq!(db.list_zap_receipts().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn pending_invoices_are_indexed() {
+        let db = helpers::tmp_db();
+        let hour = Duration::from_secs(3600);
+        db.insert_invoice(&invoice("later", 2 * hour, false))
+            .unwrap();
+        db.insert_invoice(&invoice("sooner", hour, false)).unwrap();
+        let mut settled = invoice("settled", hour, false);
+        settled.status = InvoiceStatus::Settled;
+        db.insert_invoice(&settled).unwrap();
+
+        let hashes = |db: &crate::db::Db| -> Vec<String> {
+            db.list_pending_invoices()
+                .unwrap()
+                .into_iter()
+                .map(|i| i.payment_hash)
+                .collect()
+        };
+        assert_eq!(hashes(&db), vec!["sooner", "later"]);
+
+        let mut sooner = db.get_invoice("sooner").unwrap().unwrap();
+        sooner.status = InvoiceStatus::Expired;
+        assert!(db.update_pending_invoice(&sooner).unwrap());
+        assert!(!db.update_pending_invoice(&sooner).unwrap());
+        assert_eq!(hashes(&db), vec!["later"]);
+        assert_eq!(db.reindex_pending_invoices().unwrap(), 1);
+        assert_eq!(hashes(&db), vec!["later"]);
+    }
+
+    #[tokio::test]
+    async fn only_the_issuing_backend_is_asked() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"paid": false})))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let db = helpers::tmp_db();
+        let wallet = |key: &str| {
+            InvoiceAPI::LNBits(LNBitsParams {
+                host: mock_server.uri(),
+                key: key.to_string(),
+            })
+        };
+        let params = Params {
+            name: "user".to_string(),
+            domain: "domain.com".to_string(),
+            invoice_api: wallet("new-wallet"),
+            ..Default::default()
+        };
+        db.insert(&params.name, &params.domain, &params).unwrap();
+        let hour = Duration::from_secs(3600);
+        let mut old = invoice("old", hour, false);
+        old.backend_id = Some(wallet("old-wallet").identity());
+        db.insert_invoice(&old).unwrap();
+        let mut new = invoice("new", hour, false);
+        new.backend_id = Some(params.invoice_api.identity());
+        db.insert_invoice(&new).unwrap();
+
+        let report = check_pending(&db, &init_config()).await.unwrap();
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.failed, 0);
+        let requests = mock_server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].url.path(), "/api/v1/payments/new");
+    }
+}
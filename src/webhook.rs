@@ -0,0 +1,525 @@
+//! Outgoing webhooks. Address owners can register a url which gets
+//! POSTed JSON notifications about invoices issued for the address,
+//! their payment and expiry. Notifications are queued in the database
+//! and retried with an exponential backoff until delivered.
+
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use futures::{stream, StreamExt};
+use hmac::{Hmac, Mac};
+use hyper_tls::HttpsConnector;
+use log::*;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::Serialize;
+use sha2::Sha256;
+use strum_macros::Display;
+use tokio::{net::lookup_host, time::timeout};
+use url::{Host, Url};
+use warp::hyper::{
+    self,
+    client::{connect::dns::Name, HttpConnector},
+    service::Service,
+    Body, Client, Request,
+};
+
+use crate::{
+    db::{
+        models::{InvoiceRecord, Webhook, WebhookJob},
+        Db,
+    },
+    Config,
+};
+
+/// Header carrying `sha256=<hex encoded HMAC-SHA256 of the body>`
+pub static SIGNATURE_HEADER: &str = "x-sataddress-webhook-signature";
+/// Length of generated signing secrets
+const SECRET_LEN: usize = 32;
+/// Deliveries that keep failing are dropped after that many attempts
+const MAX_ATTEMPTS: u32 = 10;
+/// Delay before the first retry, doubled with each failed attempt
+const BACKOFF_BASE: Duration = Duration::from_secs(30);
+const BACKOFF_MAX: Duration = Duration::from_secs(3600);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Notifications sent at the same time
+const CONCURRENT_DELIVERIES: usize = 16;
+/// Most notifications sent in a single run, the rest waits for the next one
+const DELIVERY_BATCH: usize = 256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What happened to the invoice
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum Event {
+    #[serde(rename = "invoice.issued")]
+    #[strum(serialize = "invoice.issued")]
+    Issued,
+    #[serde(rename = "invoice.paid")]
+    #[strum(serialize = "invoice.paid")]
+    Paid,
+    #[serde(rename = "invoice.expired")]
+    #[strum(serialize = "invoice.expired")]
+    Expired,
+}
+
+/// Body of the notification
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: Event,
+    /// unix time the event was queued at
+    timestamp: u64,
+    invoice: &'a InvoiceRecord,
+}
+
+/// Generates a new random signing secret
+pub fn generate_secret() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SECRET_LEN)
+        .map(char::from)
+        .collect()
+}
+
+/// Keeps the secret when it's given or the url didn't change,
+/// generates a new one otherwise
+pub fn keep_or_generate_secret(webhook: &mut Webhook, previous: Option<&Webhook>) {
+    if !webhook.secret.is_empty() {
+        return;
+    }
+    webhook.secret = match previous {
+        Some(previous) if previous.url == webhook.url => previous.secret.clone(),
+        _ => generate_secret(),
+    };
+}
+
+/// Returns the hex encoded HMAC-SHA256 of the payload
+pub fn sign(secret: &str, payload: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key size");
+    mac.update(payload);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Queues the notification about the invoice for the delivery
+pub fn enqueue(db: &Db, webhook: &Webhook, event: Event, invoice: &InvoiceRecord) -> Result<()> {
+    let now = SystemTime::now();
+    let payload = serde_json::to_string(&Payload {
+        event,
+        timestamp: now.duration_since(UNIX_EPOCH)?.as_secs(),
+        invoice,
+    })?;
+    let mut job = WebhookJob {
+        id: 0,
+        address: invoice.address.clone(),
+        url: webhook.url.clone(),
+        secret: webhook.secret.clone(),
+        payload,
+        attempts: 0,
+        next_attempt: now,
+    };
+    db.push_webhook(&mut job)?;
+    debug!("Queued {} webhook #{} for {}", event, job.id, job.address);
+    Ok(())
+}
+
+/// Delay before the next attempt, after `attempts` failed ones
+pub(crate) fn backoff(attempts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+    BACKOFF_BASE.saturating_mul(factor).min(BACKOFF_MAX)
+}
+
+/// Whether the address is reachable from the internet, loopback,
+/// link-local, private and similar ranges are not
+pub(crate) fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || a == 0
+                // shared address space (carrier-grade NAT)
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(&IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local and link-local
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Webhook urls have to use https and point at public addresses only,
+/// unless local receivers are allowed. Checked when the url is saved
+/// and before each delivery.
+pub async fn check_url(url: &str, allow_local: bool) -> Result<()> {
+    if allow_local {
+        return Ok(());
+    }
+    let url = Url::parse(url)?;
+    if url.scheme() != "https" {
+        bail!("webhook url has to use https");
+    }
+    let ips: Vec<IpAddr> = match url.host() {
+        Some(Host::Ipv4(ip)) => vec![ip.into()],
+        Some(Host::Ipv6(ip)) => vec![ip.into()],
+        Some(Host::Domain(domain)) => lookup_host((domain, url.port().unwrap_or(443)))
+            .await?
+            .map(|addr| addr.ip())
+            .collect(),
+        None => bail!("webhook url has no host"),
+    };
+    if ips.is_empty() || !ips.iter().all(is_public) {
+        bail!("webhook url has to point at a public address");
+    }
+    Ok(())
+}
+
+/// Resolves hosts of webhook urls to their public addresses only, so
+/// that a checked name can't be pointed at internal services later on
+#[derive(Clone)]
+struct PublicResolver {
+    allow_local: bool,
+}
+
+impl Service<Name> for PublicResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let allow_local = self.allow_local;
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| allow_local || is_public(&addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} has no public address", name),
+                ));
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+type WebhookClient = Client<HttpsConnector<HttpConnector<PublicResolver>>>;
+
+/// POSTs the signed payload, any non 2xx response is a failure
+async fn post(client: &WebhookClient, job: &WebhookJob, allow_local: bool) -> Result<()> {
+    check_url(&job.url, allow_local).await?;
+    let req = Request::post(&job.url)
+        .header("content-type", "application/json")
+        .header(
+            SIGNATURE_HEADER,
+            format!("sha256={}", sign(&job.secret, job.payload.as_bytes())),
+        )
+        .body(Body::from(job.payload.clone()))?;
+    let resp = match timeout(REQUEST_TIMEOUT, client.request(req)).await {
+        Ok(r) => r?,
+        Err(_) => bail!("request timed out"),
+    };
+    if !resp.status().is_success() {
+        bail!("unexpected response status {}", resp.status());
+    }
+    Ok(())
+}
+
+/// Summary of a single run over the queue
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DeliveryReport {
+    pub delivered: usize,
+    /// failed deliveries scheduled for another attempt
+    pub retried: usize,
+    /// failed deliveries given up on
+    pub dropped: usize,
+}
+
+/// Delivers queued notifications that are due
+pub async fn deliver_due(db: &Db, config: &Config) -> Result<DeliveryReport> {
+    let mut report = DeliveryReport::default();
+    let allow_local = config.webhook_allow_local;
+    let mut http = HttpConnector::new_with_resolver(PublicResolver { allow_local });
+    http.enforce_http(false);
+    let client =
+        Client::builder().build::<_, hyper::Body>(HttpsConnector::new_with_connector(http));
+    let now = SystemTime::now();
+
+    let mut deliveries = stream::iter(db.list_due_webhooks(now, DELIVERY_BATCH)?)
+        .map(|job| {
+            let client = &client;
+            async move {
+                let result = post(client, &job, allow_local).await;
+                (job, result)
+            }
+        })
+        .buffer_unordered(CONCURRENT_DELIVERIES);
+
+    while let Some((mut job, result)) = deliveries.next().await {
+        match result {
+            Ok(_) => {
+                db.delete_webhook(job.id)?;
+                report.delivered += 1;
+            }
+            Err(e) => {
+                job.attempts += 1;
+                if job.attempts >= MAX_ATTEMPTS {
+                    warn!(
+                        "Dropping webhook #{} for {} after {} attempts: {}",
+                        job.id, job.address, job.attempts, e
+                    );
+                    db.delete_webhook(job.id)?;
+                    report.dropped += 1;
+                } else {
+                    debug!("Webhook #{} for {} failed: {}", job.id, job.address, e);
+                    job.next_attempt = now + backoff(job.attempts);
+                    db.save_webhook(&job)?;
+                    report.retried += 1;
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Periodically delivers queued notifications, runs forever
+pub async fn run(db: Db, config: Config, every: Duration) {
+    // queues written before the index of due jobs was introduced
+    match db.reindex_webhooks() {
+        Ok(queued) => info!("{} webhooks queued", queued),
+        Err(e) => error!("Unable to index queued webhooks: {}", e),
+    }
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        match deliver_due(&db, &config).await {
+            Ok(report) if report != DeliveryReport::default() => info!(
+                "Webhooks: {} delivered, {} to retry, {} dropped",
+                report.delivered, report.retried, report.dropped
+            ),
+            Ok(_) => {}
+            Err(e) => error!("Webhook delivery failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use wiremock::{
+        matchers::{header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use envconfig::Envconfig;
+
+    use crate::{
+        db::{
+            helpers,
+            models::{InvoiceRecord, Webhook},
+        },
+        Config,
+    };
+
+    use super::{
+        backoff, check_url, deliver_due, enqueue, keep_or_generate_secret, sign, DeliveryReport,
+        Event,
+    };
+
+    fn init_config(allow_local: bool) -> Config {
+        let vars = [
+            ("DOMAINS", "domain.com"),
+            ("PIN_SECRET", "secret"),
+            ("SITE_NAME", "name"),
+            ("SITE_SUB_NAME", "sub_name"),
+            ("LNBITS_URL", "http://127.0.0.1:5001"),
+            ("LNBITS_API_KEY", "key"),
+            ("LNBITS_ADMIN_ID", "admin"),
+            (
+                "WEBHOOK_ALLOW_LOCAL",
+                if allow_local { "true" } else { "false" },
+            ),
+        ];
+        Config::init_from_hashmap(
+            &vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    fn invoice() -> InvoiceRecord {
+        InvoiceRecord {
+            comment: Some("thanks!".to_string()),
+            ..helpers::invoice(&"aa".repeat(32))
+        }
+    }
+
+    #[test]
+    fn secrets_are_kept_for_unchanged_urls() {
+        let previous = Webhook {
+            url: "https://example.com/hook".to_string(),
+            secret: "old".to_string(),
+        };
+        let mut webhook = Webhook {
+            url: previous.url.clone(),
+            ..Default::default()
+        };
+        keep_or_generate_secret(&mut webhook, Some(&previous));
+        assert_eq!(webhook.secret, "old");
+
+        let mut webhook = Webhook {
+            url: "https://example.com/other".to_string(),
+            ..Default::default()
+        };
+        keep_or_generate_secret(&mut webhook, Some(&previous));
+        assert_eq!(webhook.secret.len(), 32);
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_limit() {
+        assert_eq!(backoff(1), Duration::from_secs(30));
+        assert_eq!(backoff(2), Duration::from_secs(60));
+        assert_eq!(backoff(20), Duration::from_secs(3600));
+    }
+
+    #[tokio::test]
+    async fn urls_have_to_be_public() {
+        for url in [
+            "http://1.1.1.1/hook",
+            "https://127.0.0.1/hook",
+            "https://localhost/hook",
+            "https://10.0.0.1/hook",
+            "https://192.168.1.1/hook",
+            "https://169.254.169.254/latest/meta-data",
+            "https://100.64.0.1/hook",
+            "https://[::1]/hook",
+            "https://[fd00::1]/hook",
+            "https://[fe80::1]/hook",
+            "https://[::ffff:127.0.0.1]/hook",
+            "file:///etc/passwd",
+        ] {
+            assert!(check_url(url, false).await.is_err(), "{}", url);
+        }
+        assert!(check_url("https://1.1.1.1/hook", false).await.is_ok());
+        assert!(check_url("https://[2606:4700::1111]/hook", false)
+            .await
+            .is_ok());
+        assert!(check_url("http://127.0.0.1:8080/hook", true).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn local_receivers_are_not_notified_unless_allowed() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+        let db = helpers::tmp_db();
+        let webhook = Webhook {
+            url: format!("{}/hook", mock_server.uri()),
+            secret: "secret".to_string(),
+        };
+        enqueue(&db, &webhook, Event::Issued, &invoice()).unwrap();
+
+        let report = deliver_due(&db, &init_config(false)).await.unwrap();
+        assert_eq!(report.retried, 1);
+    }
+
+    #[tokio::test]
+    async fn notifications_are_signed_and_retried() {
+        let mock_server = MockServer::start().await;
+        let db = helpers::tmp_db();
+        let webhook = Webhook {
+            url: format!("{}/hook", mock_server.uri()),
+            secret: "secret".to_string(),
+        };
+        enqueue(&db, &webhook, Event::Issued, &invoice()).unwrap();
+        let job = db.list_webhooks().unwrap().pop().unwrap();
+        let payload: serde_json::Value = serde_json::from_str(&job.payload).unwrap();
+        assert_eq!(payload["event"], "invoice.issued");
+        assert_eq!(payload["invoice"]["comment"], "thanks!");
+
+        // receiver is down, the job stays in the queue
+        let report = deliver_due(&db, &init_config(true)).await.unwrap();
+        assert_eq!(report.retried, 1);
+        let job = db.list_webhooks().unwrap().pop().unwrap();
+        assert_eq!(job.attempts, 1);
+        assert!(job.next_attempt > SystemTime::now());
+
+        // not due yet
+        assert_eq!(
+            deliver_due(&db, &init_config(true)).await.unwrap(),
+            DeliveryReport::default()
+        );
+
+        let signature = format!("sha256={}", sign("secret", job.payload.as_bytes()));
+        Mock::given(method("POST"))
+            .and(path("/hook"))
+            .and(header(super::SIGNATURE_HEADER, signature.as_str()))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let mut job = job;
+        job.next_attempt = SystemTime::now();
+        db.save_webhook(&job).unwrap();
+
+        let report = deliver_due(&db, &init_config(true)).await.unwrap();
+        assert_eq!(report.delivered, 1);
+        assert!(db.list_webhooks().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_due_notifications_are_read() {
+        let db = helpers::tmp_db();
+        let webhook = Webhook {
+            url: "https://1.1.1.1/hook".to_string(),
+            secret: "secret".to_string(),
+        };
+        for _ in 0..3 {
+            enqueue(&db, &webhook, Event::Issued, &invoice()).unwrap();
+        }
+        let mut later = db.list_webhooks().unwrap().pop().unwrap();
+        later.next_attempt = SystemTime::now() + Duration::from_secs(60);
+        db.save_webhook(&later).unwrap();
+
+        let now = SystemTime::now();
+        let due = db.list_due_webhooks(now, 10).unwrap();
+        assert_eq!(due.len(), 2);
+        assert!(due.iter().all(|job| job.id != later.id));
+        assert_eq!(db.list_due_webhooks(now, 1).unwrap().len(), 1);
+
+        db.delete_webhook(due[0].id).unwrap();
+        assert_eq!(db.list_due_webhooks(now, 10).unwrap().len(), 1);
+        let soon = now + Duration::from_secs(120);
+        assert_eq!(db.list_due_webhooks(soon, 10).unwrap().len(), 2);
+        assert_eq!(db.reindex_webhooks().unwrap(), 2);
+        assert_eq!(db.list_due_webhooks(soon, 10).unwrap().len(), 2);
+    }
+}